@@ -0,0 +1,33 @@
+//! Links the test kernel built by `cargo test`, see src/testing.rs.
+//! The normal kernel is a static library linked by the Makefile, which link arguments do not apply to.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const BOOT_FILES: [&str; 3] = ["multiboot_header", "multiboot1_header", "boot"];
+
+fn main() {
+    println!("cargo:rerun-if-changed=link.ld");
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    for name in BOOT_FILES {
+        let source = manifest_dir.join(format!("src/boot/{}.asm", name));
+        let object = out_dir.join(format!("{}.o", name));
+        println!("cargo:rerun-if-changed={}", source.display());
+        let status = Command::new("nasm")
+            .arg("-felf64")
+            .arg(&source)
+            .arg("-o")
+            .arg(&object)
+            .status()
+            .expect("nasm is needed to build the test kernel");
+        assert!(status.success(), "nasm failed on {}", source.display());
+        println!("cargo:rustc-link-arg={}", object.display());
+    }
+    println!("cargo:rustc-link-arg=-n");
+    println!("cargo:rustc-link-arg=-T{}", manifest_dir.join("link.ld").display());
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Boots a test kernel built by `cargo test`, see src/testing.rs.
+# The kernel reports on COM1 and exits through isa-debug-exit with (code << 1) | 1.
+qemu-system-x86_64 -kernel "$1" \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -serial stdio -display none -no-reboot
+# 0x10 is success
+[ $? -eq 33 ]
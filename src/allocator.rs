@@ -1,11 +1,86 @@
-use core::ptr::addr_of;
+//! Physical frame allocator
 
-use x86_64::VirtAddr;
+use core::ops::Range;
+
+use spin::{Mutex, Once};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use crate::bootinfo::{self, BootInfo, MemoryKind};
+use crate::paging;
+
+pub static FRAME_ALLOCATOR: Once<Mutex<BumpFrameAllocator>> = Once::new();
+
+/// Maximum amount of reserved ranges
+const MAX_RESERVED: usize = 32;
+
+/// Hands out the available frames from the memory map one by one, never freeing them.
+/// Only frames that are identity mapped are handed out, so they can be accessed directly.
+pub struct BumpFrameAllocator {
+    boot_info: &'static BootInfo,
+    /// Physical ranges that are in use (the kernel, boot information, modules)
+    reserved: [Range<u64>; MAX_RESERVED],
+    reserved_len: usize,
+    /// The next frame to consider
+    next: u64,
+}
+
+impl BumpFrameAllocator {
+    pub fn new(boot_info: &'static BootInfo) -> Self {
+        const EMPTY: Range<u64> = 0..0;
+        Self { boot_info, reserved: [EMPTY; MAX_RESERVED], reserved_len: 0, next: 0 }
+    }
+
+    /// Never hand out frames in this range
+    pub fn reserve(&mut self, range: Range<u64>) {
+        if self.reserved_len >= MAX_RESERVED {
+            panic!("Too many reserved ranges");
+        }
+        self.reserved[self.reserved_len] = range;
+        self.reserved_len += 1;
+    }
+
+    pub fn reserved(&self) -> &[Range<u64>] {
+        &self.reserved[..self.reserved_len]
+    }
+
+    fn is_available(&self, frame: u64) -> bool {
+        let end = frame + 0x1000;
+        end <= paging::IDENTITY_MAPPED_END
+        && self.boot_info.memory_regions().any(|r| r.kind == MemoryKind::Usable && r.start <= frame && end <= r.end)
+        && !self.reserved().iter().any(|r| r.start < end && frame < r.end)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BumpFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        while self.next < paging::IDENTITY_MAPPED_END {
+            let frame = self.next;
+            self.next += 0x1000;
+            if self.is_available(frame) {
+                return Some(PhysFrame::containing_address(PhysAddr::new(frame)));
+            }
+        }
+        None
+    }
+}
 
 pub fn init() {
-    let mbi = crate::MBI.get().expect("Allocator could net get MBI");
-    let mbi_end = unsafe { (addr_of!(**mbi) as *const u8).offset(mbi.total_size as isize) };
-    let first_page = unsafe { mbi_end.offset(mbi_end.align_offset(0x1000) as isize) };
-    //let first_page = VirtAddr::new(first_page as u64);
-    println!("First page: {first_page:#x?}");
+    let boot_info = bootinfo::get();
+    if !boot_info.memory_regions().any(|r| r.kind == MemoryKind::Usable) {
+        panic!("No memory map");
+    }
+    let mut allocator = BumpFrameAllocator::new(boot_info);
+
+    // BIOS data, VGA memory and the like
+    allocator.reserve(0..0x100000);
+
+    allocator.reserve(boot_info.kernel_sections.image());
+    allocator.reserve(boot_info.reserved.clone());
+
+    for module in boot_info.modules() {
+        allocator.reserve(module.start..module.end);
+    }
+
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(allocator));
 }
@@ -0,0 +1,169 @@
+//! Stack backtraces by following the frame pointers (the kernel is built with them, see .cargo/config.toml)
+//! https://wiki.osdev.org/Stack_Trace
+
+// Every function starts with `push rbp; mov rbp, rsp`,
+// so RBP points to the saved RBP of the caller with the return address above it.
+// The chain ends at the null RBP boot.asm sets before calling runix.
+
+use core::arch::asm;
+use core::fmt;
+use core::ops::Range;
+use core::ptr::addr_of;
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{gdt, symbols};
+use crate::interrupts::trap::TrapFrame;
+use crate::symbols::Demangle;
+
+/// Stop after this many frames, in case the chain loops
+const MAX_FRAMES: usize = 64;
+
+extern "C" {
+    static stack_bottom: u8;
+    static stack_top: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// The stacks frames are allowed to be on
+fn known_stacks() -> [Range<u64>; 3] {
+    let [kernel_stack, double_fault_stack] = gdt::stacks();
+    [
+        addr_of!(stack_bottom) as u64..addr_of!(stack_top) as u64,
+        kernel_stack,
+        double_fault_stack,
+    ]
+}
+
+fn is_text(addr: u64) -> bool {
+    (addr_of!(__text_start) as u64..addr_of!(__text_end) as u64).contains(&addr)
+}
+
+/// If a frame at `rbp` lies entirely on a known stack
+fn is_valid_frame(rbp: u64) -> bool {
+    rbp % 8 == 0 && known_stacks().iter().any(|s| s.start <= rbp && rbp + 16 <= s.end)
+}
+
+/// Read the value at `addr`, it has to be on a known stack
+fn read(addr: u64) -> Option<u64> {
+    known_stacks().iter()
+        .any(|s| s.start <= addr && addr + 8 <= s.end)
+        .then(|| unsafe { *(addr as *const u64) })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub rbp: u64,
+    /// Where the function returns to
+    pub return_address: u64,
+}
+
+/// Walks the frame pointer chain starting at `rbp`
+pub struct Frames {
+    rbp: u64,
+    count: usize,
+}
+
+impl Iterator for Frames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.count >= MAX_FRAMES || !is_valid_frame(self.rbp) {
+            return None;
+        }
+        let rbp = self.rbp;
+        let next = read(rbp)?;
+        let mut return_address = read(rbp + 8)?;
+        // x86-interrupt handlers with an error code have it where the return address would be,
+        // the interrupted RIP comes after it
+        if !is_text(return_address) {
+            return_address = read(rbp + 16).filter(|&a| is_text(a))?;
+        }
+        self.rbp = next;
+        self.count += 1;
+        Some(Frame { rbp, return_address })
+    }
+}
+
+/// A backtrace that can be displayed, one symbolized frame per line
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    /// The faulting instruction, for exceptions
+    rip: Option<u64>,
+    rbp: u64,
+}
+
+impl Backtrace {
+    /// The backtrace of the caller
+    #[inline(always)]
+    pub fn here() -> Self {
+        Self { rip: None, rbp: rbp() }
+    }
+
+    /// The backtrace of the code an exception interrupted,
+    /// has to be called from (a function called by) the exception handler.
+    #[inline(always)]
+    pub fn interrupted(stack_frame: &InterruptStackFrame) -> Self {
+        let rip = stack_frame.instruction_pointer.as_u64();
+        let cs = stack_frame.code_segment;
+        // Find the frame of the handler by the RIP and CS the CPU pushed above it,
+        // its saved RBP belongs to the interrupted code
+        let handler = Frames { rbp: rbp(), count: 0 }.find(|f| {
+            [8, 16].iter().any(|&offset| read(f.rbp + offset) == Some(rip) && read(f.rbp + offset + 8) == Some(cs))
+        });
+        match handler.and_then(|f| read(f.rbp)) {
+            Some(rbp) => Self { rip: Some(rip), rbp },
+            None => Self { rip: Some(rip), rbp: rbp() },
+        }
+    }
+
+    /// The backtrace of the code a debug trap interrupted
+    pub fn trapped(frame: &TrapFrame) -> Self {
+        Self { rip: Some(frame.rip), rbp: frame.rbp }
+    }
+
+    /// The frame pointer of the innermost frame
+    pub fn rbp(&self) -> u64 {
+        self.rbp
+    }
+
+    pub fn frames(&self) -> Frames {
+        Frames { rbp: self.rbp, count: 0 }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rip) = self.rip {
+            match symbols::symbolize(rip) {
+                Some((name, offset)) => writeln!(f, "  {:#018x} {}+{:#x}", rip, Demangle(name), offset)?,
+                None => writeln!(f, "  {:#018x} ?", rip)?,
+            }
+        }
+        for frame in self.frames() {
+            writeln!(f, "  {:#018x} {}", frame.return_address, Caller(frame.return_address))?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays the function a return address is in as `name+0x1c`, or `?`
+pub struct Caller(pub u64);
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The return address points after the call, which may be the start of the next function
+        match symbols::symbolize(self.0 - 1) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", Demangle(name), offset + 1),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+#[inline(always)]
+fn rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
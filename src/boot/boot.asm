@@ -1,4 +1,7 @@
 global start
+; The boot stack, see backtrace.rs
+global stack_bottom
+global stack_top
 
 section .rodata
 gdt:
@@ -8,22 +11,45 @@ gdt:
     dw $ - gdt - 1                          ; SIZE of GDT
     dd gdt                                  ; Address of GDT
 
+; Xen PVH entry point, found by Xen and QEMU -kernel through this ELF note
+; https://xenbits.xen.org/docs/unstable/misc/pvh.html
+section .note.Xen note alloc noexec nowrite align=4
+    dd 4                ; namesz
+    dd 4                ; descsz
+    dd 18               ; type, XEN_ELFNOTE_PHYS32_ENTRY
+    db "Xen", 0         ; name
+    dd pvh_start        ; desc, the 32-bit entry point
+
 section .text
 bits 32
 OK equ 0xF04BF04F     ; black on white OK
 VGA equ 0xb8000 + 160 ; start on next line
+
+; Like multiboot we start in 32 bit protected mode without paging,
+; but EBX points to a hvm_start_info structure that starts with its magic.
+pvh_start:
+    mov esp, stack_top
+    mov edi, ebx
+    mov eax, [ebx]
+    mov esi, eax
+    jmp start.boot
+
 start:
 
-    ; At this point multiboot should've set EAX to ‘0x36d76289’;
+    ; At this point multiboot should've set EAX to ‘0x36d76289’
+    ; (or ‘0x2BADB002’ for multiboot 1, pvh_start passes ‘0x336ec578’);
     ; And EBX points to a multiboot information structure
     ; See 3.3 I386 machine state
 
     ; Set the stack pointer
     mov esp, stack_top
 
-    ; Save multiboot information
+    ; Save multiboot information and magic
+    ; these are the first two arguments to runix
     mov edi, ebx
+    mov esi, eax
 
+.boot:
     call .disable_cursor
     call .confirm_multiboot
     call .check_cpuid
@@ -57,7 +83,12 @@ start:
 ; Confirm if booted via multiboot
 .confirm_multiboot:
     cmp eax, 0x36d76289
+    je .is_multiboot
+    cmp eax, 0x2BADB002
+    je .is_multiboot
+    cmp eax, 0x336ec578 ; PVH
     jne .not_multiboot
+.is_multiboot:
     ret
 .not_multiboot:
     mov ax, "nm"
@@ -142,8 +173,8 @@ start:
 ; https://wiki.osdev.org/Setting_Up_Paging
 
 
-; We have one of each table. The PT table is full (to get more control of the first 2MiB of pages)
-; The PDT is filled with an aditional 7 huge pages
+; We have one of each table. The PT table is full (to get more control of the 2MiB containing the guard page)
+; The PDT is filled with huge pages for the other 7 entries
 ; In total we map 16 MiB
 .setup_tables:
     mov eax, PDPT
@@ -154,12 +185,9 @@ start:
     or eax, 0b11        ; Set present and writable
     mov [PDPT], eax     ; Save only PDP in PDPT
 
-    mov eax, PT
-    or eax, 0b11        ; Set present and writable
-    mov [PDT], eax      ; Save only PDP in PDPT
-
     mov ecx, 512
-    mov ebx, 0
+    mov ebx, guard
+    and ebx, ~(0x200000 - 1) ; Start of the 2MiB containing the guard page
     mov edx, 0
 
     ; EBX is location of page
@@ -177,9 +205,9 @@ start:
     add ebx, 0x1000
     loop .setup_table_pt
 
-    mov ecx, 7              ; Huge pages to create
-    mov ebx, 0x200000
-    mov edx, 8
+    mov ecx, 8              ; Huge pages to create
+    mov ebx, 0
+    mov edx, 0
 
 .setup_table_pdt:
     mov eax, ebx            ; Copy page address to entry
@@ -189,6 +217,13 @@ start:
     add ebx, 0x200000
     loop .setup_table_pdt
 
+    ; Replace the huge page containing the guard page with the PT
+    mov edx, guard
+    shr edx, 21
+    mov eax, PT
+    or eax, 0b11        ; Set present and writable
+    mov [PDT + edx * 8], eax
+
     ret
 
 .enable_paging:
@@ -228,6 +263,13 @@ long_mode:
     mov fs, ax
     mov gs, ax
 
+    ; Zero extend the arguments, the upper halves are undefined
+    mov edi, edi
+    mov esi, esi
+
+    ; The end of the frame pointer chain for backtraces
+    xor ebp, ebp
+
     ; Call rust
     extern runix
     call runix
@@ -258,7 +300,7 @@ PT:
 ; The first megabyte of the ELF
 ; is configured to have 1MiB of null bytes.
 ; GRUB maps this ELF at the top of memory.
-; This .bss section is created somewhere with a page table
+; This .bss section is created at the end with a page table
 ; mapping the first 16MiB of memory.
 ; Also in the .bss section is 16 pages (64KiB)
 ; reserved for the stack.
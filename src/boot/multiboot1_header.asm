@@ -0,0 +1,39 @@
+; Multiboot 1 header, for loaders that do not speak multiboot2
+; https://www.gnu.org/software/grub/manual/multiboot/multiboot.html#Header-layout
+; It has to be aligned to 4 bytes and within the first 8192 bytes of the file.
+; QEMU -kernel prefers this header over the PVH note in boot.asm,
+; so it is left out of target/runix-pvh.elf (see the Makefile).
+
+; Offset	Type	Field Name
+; 0			u32		magic
+; 4			u32		flags
+; 8			u32		checksum
+; 12		u32		header_addr		(if flags[16] is set)
+; 16		u32		load_addr		(if flags[16] is set)
+; 20		u32		load_end_addr	(if flags[16] is set)
+; 24		u32		bss_end_addr	(if flags[16] is set)
+; 28		u32		entry_addr		(if flags[16] is set)
+
+extern start
+extern __kernel_start
+extern __load_end
+extern __bss_end
+
+MB1_MAGIC equ 0x1BADB002
+; bit 0: align modules on page boundaries
+; bit 1: provide a memory map
+; bit 16: use the address fields (the "a.out kludge"),
+;         QEMU -kernel can't load 64 bit ELF files without it
+MB1_FLAGS equ (1 << 0) | (1 << 1) | (1 << 16)
+
+section .multiboot_header
+align 4
+mb1_header_start:
+	dd MB1_MAGIC
+	dd MB1_FLAGS
+	dd -(MB1_MAGIC + MB1_FLAGS)	; magic + flags + checksum has to be 0
+	dd mb1_header_start			; header_addr
+	dd __kernel_start			; load_addr, see link.ld
+	dd __load_end				; load_end_addr, see link.ld
+	dd __bss_end				; bss_end_addr
+	dd start					; entry_addr
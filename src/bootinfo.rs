@@ -0,0 +1,245 @@
+//! Information from the bootloader, independent of the boot protocol.
+//! Multiboot2 fills this in, multiboot 1 and PVH are translated to multiboot2 first.
+
+use core::ffi::CStr;
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::multiboot::ElfSection;
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Maximum amount of memory regions, adjacent regions of the same kind are merged
+const MAX_REGIONS: usize = 128;
+/// Maximum amount of boot modules
+const MAX_MODULES: usize = 16;
+
+/// Set the boot information, can only be done once
+pub fn init(info: BootInfo) -> &'static BootInfo {
+    BOOT_INFO.call_once(|| info)
+}
+
+pub fn get() -> &'static BootInfo {
+    BOOT_INFO.get().expect("Boot information is not initialized")
+}
+
+/// For code that can run before the boot information is set, like exception handlers
+pub fn try_get() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free to use once the boot information has been read
+    Usable,
+    Reserved,
+    /// Holds ACPI tables, usable after they have been read
+    AcpiReclaimable,
+    AcpiNvs,
+    /// Defective memory
+    Bad,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+/// A file loaded by the bootloader, like an application
+#[derive(Debug, Clone)]
+pub struct BootModule {
+    /// Physical address of the first byte
+    pub start: u64,
+    /// Physical address of the first byte after the module
+    pub end: u64,
+    pub cmdline: &'static str,
+}
+
+impl BootModule {
+    /// The contents of the module
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, (self.end - self.start) as usize) }
+    }
+
+    /// If the first word of the command line is `name`, or a path ending in `name`
+    pub fn is_named(&self, name: &str) -> bool {
+        let path = self.cmdline.split_ascii_whitespace().next().unwrap_or_default();
+        path == name || path.rsplit('/').next() == Some(name)
+    }
+}
+
+/// Position and size of a color in a pixel, in bits
+#[derive(Debug, Clone, Copy)]
+pub struct ColorField {
+    pub position: u8,
+    pub size: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FramebufferFormat {
+    /// Pixels are indices in the palette of RGB colors
+    Indexed(&'static [[u8; 3]]),
+    Rgb {
+        red: ColorField,
+        green: ColorField,
+        blue: ColorField,
+    },
+    /// Like the VGA text buffer
+    EgaText,
+}
+
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    /// Physical address
+    pub addr: u64,
+    /// Bytes per line
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub bpp: u8,
+    pub format: FramebufferFormat,
+}
+
+/// A section of the kernel ELF file
+#[derive(Debug, Clone)]
+pub struct KernelSection {
+    pub name: &'static str,
+    /// See man elf(5), for example SHT_SYMTAB (2)
+    pub type_: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub size: u64,
+    /// Index of an associated section, like the string table of a symbol table
+    pub link: u32,
+    pub entsize: u64,
+}
+
+/// The section headers of the kernel
+#[derive(Clone, Copy)]
+pub struct KernelSections {
+    headers: &'static [ElfSection],
+    /// Index of the section holding the section names
+    shstrndx: usize,
+}
+
+impl KernelSections {
+    pub fn new(headers: &'static [ElfSection], shstrndx: usize) -> Self {
+        Self { headers, shstrndx }
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<KernelSection> {
+        let header = self.headers.get(index)?;
+        let name = match (self.shstrndx, header.name) {
+            (0, _) | (_, 0) => "",
+            (shstrndx, name) => self.headers.get(shstrndx)
+                .map(|strtab| unsafe { CStr::from_ptr((strtab.addr + name as usize) as *const core::ffi::c_char) })
+                .and_then(|name| name.to_str().ok())
+                .unwrap_or_default(),
+        };
+        Some(KernelSection {
+            name,
+            type_: header.type_,
+            flags: header.flags as u64,
+            addr: header.addr as u64,
+            size: header.size as u64,
+            link: header.link,
+            entsize: header.entsize as u64,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = KernelSection> + '_ {
+        (0..self.len()).filter_map(|i| self.get(i))
+    }
+
+    /// The physical memory the loaded kernel occupies
+    pub fn image(&self) -> Range<u64> {
+        // Skip the null section and the sections that are not loaded
+        let loaded = || self.iter().skip(1).filter(|s| s.addr != 0);
+        let start = loaded().map(|s| s.addr).min().unwrap_or_default();
+        let end = loaded().map(|s| s.addr + s.size).max().unwrap_or_default();
+        start..end
+    }
+}
+
+/// Everything the kernel uses from the bootloader
+pub struct BootInfo {
+    /// Which bootloader (or boot protocol) started the kernel
+    pub loader_name: &'static str,
+    pub cmdline: &'static str,
+    memory: [Option<MemoryRegion>; MAX_REGIONS],
+    memory_len: usize,
+    modules: [Option<BootModule>; MAX_MODULES],
+    modules_len: usize,
+    pub framebuffer: Option<Framebuffer>,
+    /// A copy of the ACPI RSDP
+    pub rsdp: Option<&'static [u8]>,
+    pub kernel_sections: KernelSections,
+    /// Physical memory holding the boot information, it may not be reused
+    pub reserved: Range<u64>,
+}
+
+impl BootInfo {
+    pub fn new(kernel_sections: KernelSections) -> Self {
+        const NO_REGION: Option<MemoryRegion> = None;
+        const NO_MODULE: Option<BootModule> = None;
+        Self {
+            loader_name: "",
+            cmdline: "",
+            memory: [NO_REGION; MAX_REGIONS],
+            memory_len: 0,
+            modules: [NO_MODULE; MAX_MODULES],
+            modules_len: 0,
+            framebuffer: None,
+            rsdp: None,
+            kernel_sections,
+            reserved: 0..0,
+        }
+    }
+
+    /// Add a memory region, it is merged with the previous one if they are adjacent and of the same kind
+    pub fn add_memory(&mut self, region: MemoryRegion) {
+        if region.start >= region.end {
+            return;
+        }
+        if let Some(Some(last)) = self.memory[..self.memory_len].last_mut() {
+            if last.kind == region.kind && last.end == region.start {
+                last.end = region.end;
+                return;
+            }
+        }
+        if self.memory_len >= MAX_REGIONS {
+            panic!("Too many memory regions");
+        }
+        self.memory[self.memory_len] = Some(region);
+        self.memory_len += 1;
+    }
+
+    pub fn add_module(&mut self, module: BootModule) {
+        if self.modules_len >= MAX_MODULES {
+            panic!("Too many boot modules");
+        }
+        self.modules[self.modules_len] = Some(module);
+        self.modules_len += 1;
+    }
+
+    pub fn memory_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.memory[..self.memory_len].iter().flatten()
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &BootModule> {
+        self.modules[..self.modules_len].iter().flatten()
+    }
+
+    /// Find a module by the first word of its command line, see [BootModule::is_named]
+    pub fn module(&self, name: &str) -> Option<&BootModule> {
+        self.modules().find(|m| m.is_named(name))
+    }
+}
@@ -1,8 +1,15 @@
+use core::fmt;
+
+use log::LevelFilter;
 use spin::Once;
+
+/// Longest keyword or option on the command line
+const LEXEME_MAX: usize = 64;
+
 pub static CONFIG: Once<Config> = Once::new();
 
 /// The Runix configuration
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Config {
     /// Enables the red screen of death
     pub panic_cover: bool,
@@ -10,6 +17,14 @@ pub struct Config {
     pub print_info: bool,
     /// If a centered welcome message should be printed at boot
     pub welcome: bool,
+    /// If output should be mirrored to the serial port COM1
+    pub serial: bool,
+    /// The highest level of log messages printed to the screen, `loglevel=warn`
+    pub loglevel: LevelFilter,
+    /// If the GDB stub on COM2 takes over on breakpoints, exceptions and panics
+    pub gdb: bool,
+    /// Reboot this many seconds after a panic, `panic=10`
+    pub panic_reboot: Option<u32>,
 }
 
 impl Default for Config {
@@ -18,21 +33,49 @@ impl Default for Config {
             panic_cover: true,
             print_info: false,
             welcome: true,
+            serial: true,
+            loglevel: LevelFilter::Info,
+            gdb: false,
+            panic_reboot: None,
         }
     }
 }
 
 pub(super) fn parse(args: &str) {
+    let config = parse_args(args).unwrap_or_else(|e| panic!("{}", e));
+    CONFIG.call_once(|| config);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfError<'a> {
+    /// Not a keyword and not a known `key=value` option
+    UnknownLexeme(&'a str),
+    LexemeTooLong(&'a str),
+    /// Only ASCII is allowed
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for ConfError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfError::UnknownLexeme(lexeme) => write!(f, "Unknown lexeme: {:?}", lexeme),
+            ConfError::LexemeTooLong(lexeme) => write!(f, "Lexeme longer than {} characters: {:?}", LEXEME_MAX, lexeme),
+            ConfError::InvalidCharacter(c) => write!(f, "Invalid character in lexeme: {:?}", c),
+        }
+    }
+}
+
+/// Parse the kernel command line, an unknown last word without a value is ignored
+pub fn parse_args(args: &str) -> Result<Config, ConfError<'_>> {
     let mut config = Config::default();
 
-    let mut lexeme = [0; 64];
+    // The lexeme is the `len` characters up to the current one, all ASCII
     let mut len = 0;
 
     let mut negafier = false;
 
     macro_rules! reset {
         () => {
-            lexeme = [0; 64];
             len = 0;
             negafier = false;
         };
@@ -40,28 +83,29 @@ pub(super) fn parse(args: &str) {
 
     //let mut backslash = false;
 
-    for c in args.chars() {
+    for (i, c) in args.char_indices() {
         if c == ' ' {
             if len != 0 {
-                let as_str = core::str::from_utf8(&lexeme[0..len]).expect("Invalid character in lexeme");
-                panic!("Unknown lexeme: {:?}", as_str);
+                let as_str = &args[i - len..i];
+                if !parse_value(&mut config, as_str) {
+                    return Err(ConfError::UnknownLexeme(as_str));
+                }
             }
-            lexeme = [0; 64];
             len = 0;
             continue;
         }
-        if len >= 64 {
-            panic!("Lexeme longer than 64 characters: {:?}", lexeme);
+        if !c.is_ascii() {
+            return Err(ConfError::InvalidCharacter(c));
+        }
+        if len >= LEXEME_MAX {
+            return Err(ConfError::LexemeTooLong(&args[i - len..i]));
         }
 
-        lexeme[len] = c as u8;
         len += 1;
-
-        let as_str = core::str::from_utf8(&lexeme[0..len]).expect("Invalid character in lexeme");
+        let as_str = &args[i + 1 - len..=i];
 
         match as_str {
             "no" => {
-                lexeme = [0; 64];
                 len = 0;
                 negafier = !negafier
             },
@@ -76,10 +120,116 @@ pub(super) fn parse(args: &str) {
             "welcome" => {
                 config.welcome = !negafier;
                 reset!();
-            }
+            },
+            "serial" => {
+                config.serial = !negafier;
+                reset!();
+            },
+            "gdb" => {
+                config.gdb = !negafier;
+                reset!();
+            },
             _ => {}
         }
     }
+    if len != 0 {
+        let as_str = &args[args.len() - len..];
+        if !parse_value(&mut config, as_str) && as_str.contains('=') {
+            return Err(ConfError::UnknownLexeme(as_str));
+        }
+    }
 
-    CONFIG.call_once(|| config);
+    Ok(config)
+}
+
+/// Options with a value, `key=value`, can only be parsed once the whole lexeme is known
+fn parse_value(config: &mut Config, lexeme: &str) -> bool {
+    let Some((key, value)) = lexeme.split_once('=') else { return false };
+    match key {
+        "loglevel" => match value.parse() {
+            Ok(level) => config.loglevel = level,
+            Err(_) => return false,
+        },
+        "panic" => match value.parse() {
+            Ok(seconds) => config.panic_reboot = Some(seconds),
+            Err(_) => return false,
+        },
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn keywords() {
+        assert_eq!(parse_args(""), Ok(Config::default()));
+        let config = parse_args("no welcome gdb no serial print_info").unwrap();
+        assert!(!config.welcome && config.gdb && !config.serial && config.print_info);
+        assert!(!parse_args("nopanic_cover").unwrap().panic_cover);
+        // `no` toggles, so two of them cancel out
+        assert!(parse_args("no no welcome").unwrap().welcome);
+    }
+
+    #[test]
+    fn values() {
+        let config = parse_args("loglevel=warn panic=10").unwrap();
+        assert_eq!(config.loglevel, LevelFilter::Warn);
+        assert_eq!(config.panic_reboot, Some(10));
+        assert_eq!(parse_args("panic=soon gdb"), Err(ConfError::UnknownLexeme("panic=soon")));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_args("bogus welcome"), Err(ConfError::UnknownLexeme("bogus")));
+        assert_eq!(parse_args("no bogus welcome"), Err(ConfError::UnknownLexeme("bogus")));
+        assert_eq!(parse_args("wélcome"), Err(ConfError::InvalidCharacter('é')));
+        let long = "x".repeat(LEXEME_MAX + 1);
+        assert_eq!(parse_args(&long), Err(ConfError::LexemeTooLong(&long[..LEXEME_MAX])));
+        // Only a last word without a value is allowed to be unknown
+        assert!(parse_args("welcome trailing").is_ok());
+        assert_eq!(parse_args("welcome panic=soon"), Err(ConfError::UnknownLexeme("panic=soon")));
+        assert_eq!(parse_args("gdb loglevel=bogus"), Err(ConfError::UnknownLexeme("loglevel=bogus")));
+    }
+
+    fn keyword() -> impl Strategy<Value = (&'static str, bool)> {
+        (prop::sample::select(&["panic_cover", "print_info", "welcome", "serial", "gdb"][..]), any::<bool>())
+    }
+
+    fn get(config: &Config, keyword: &str) -> bool {
+        match keyword {
+            "panic_cover" => config.panic_cover,
+            "print_info" => config.print_info,
+            "welcome" => config.welcome,
+            "serial" => config.serial,
+            "gdb" => config.gdb,
+            _ => unreachable!(),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(args in "\\PC{0,100}") {
+            let _ = parse_args(&args);
+        }
+
+        #[test]
+        fn last_keyword_wins(keywords in prop::collection::vec(keyword(), 0..16), spaces in 1..4usize) {
+            let args: Vec<String> = keywords.iter().map(|(k, on)| format!("{}{}", if *on { "" } else { "no " }, k)).collect();
+            let config = parse_args(&args.join(&" ".repeat(spaces))).unwrap();
+            for (keyword, _) in &keywords {
+                let last = keywords.iter().rev().find(|(k, _)| k == keyword).unwrap().1;
+                prop_assert_eq!(get(&config, keyword), last, "{}", keyword);
+            }
+        }
+
+        #[test]
+        fn panic_seconds(seconds: u32, before in keyword(), after in keyword()) {
+            let args = format!("{} panic={} {}", before.0, seconds, after.0);
+            prop_assert_eq!(parse_args(&args).unwrap().panic_reboot, Some(seconds));
+        }
+    }
 }
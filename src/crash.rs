@@ -0,0 +1,225 @@
+//! Machine readable crash reports, written to COM1 and the QEMU debug console (port 0xe9) on panic.
+//! Run QEMU with `-debugcon file:crash.log` or `-serial stdio` to collect them.
+
+// The report is one `key=value` pair per line between the BEGIN and END lines.
+// Values are escaped so they fit on one line: `\n`, `\r`, `\\` and `\xNN` for other control characters.
+// Lists use numbered keys, like `backtrace.0`.
+// Panics in exception handlers report the registers of the interrupted code,
+// only those pushed by the CPU are known so the others are left out.
+//
+// ==== RUNIX CRASH REPORT BEGIN ====
+// version=1
+// message=PAGE FAULT ...
+// location=src/interrupts.rs:98:5
+// uptime_us=1234567
+// cmdline=gdb
+// register.rax=0x0000000000000000
+// backtrace.0=0x0000000000104f2e runix::interrupts::page_fault_handler+0x1e
+// log.0=[    0.054925] WARN  Unknown scancode 0xe0 0x60
+// ==== RUNIX CRASH REPORT END ====
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::backtrace::{Backtrace, Caller};
+use crate::{bootinfo, interrupts, logger, percpu, serial};
+
+/// Changes when keys are renamed or removed
+const VERSION: u32 = 1;
+/// How many of the latest log records are included
+const LOG_LINES: usize = 16;
+const DEBUGCON: u16 = 0xe9;
+
+/// The registers of the code an exception interrupted, set by exception handlers that are about to panic
+static EXCEPTION: Mutex<Option<Registers>> = Mutex::new(None);
+
+/// A snapshot of the registers, taken in the panic handler or from an exception
+#[derive(Debug, Clone)]
+pub struct Registers {
+    /// In the order of [Registers::NAMES], None if unknown
+    values: [Option<u64>; 22],
+}
+
+impl Registers {
+    const NAMES: [&'static str; 22] = [
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+        "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+        "rip", "rflags", "cr0", "cr2", "cr3", "cr4",
+    ];
+
+    /// The registers of the caller, the register holding the destination is overwritten
+    #[inline(always)]
+    pub fn here() -> Self {
+        let mut values = [0u64; 22];
+        unsafe {
+            asm!(
+                "mov [{0}], rax",
+                "mov [{0} + 8], rbx",
+                "mov [{0} + 16], rcx",
+                "mov [{0} + 24], rdx",
+                "mov [{0} + 32], rsi",
+                "mov [{0} + 40], rdi",
+                "mov [{0} + 48], rbp",
+                "mov [{0} + 56], rsp",
+                "mov [{0} + 64], r8",
+                "mov [{0} + 72], r9",
+                "mov [{0} + 80], r10",
+                "mov [{0} + 88], r11",
+                "mov [{0} + 96], r12",
+                "mov [{0} + 104], r13",
+                "mov [{0} + 112], r14",
+                "mov [{0} + 120], r15",
+                "lea {1}, [rip]",
+                "mov [{0} + 128], {1}",
+                "pushfq",
+                "pop {1}",
+                "mov [{0} + 136], {1}",
+                in(reg) values.as_mut_ptr(),
+                out(reg) _,
+            );
+        }
+        let mut registers = Self { values: values.map(Some) };
+        registers.read_control();
+        registers
+    }
+
+    /// The registers pushed by the CPU on an exception, the others are lost in an x86-interrupt handler
+    pub fn from_exception(stack_frame: &InterruptStackFrame, rbp: u64) -> Self {
+        let mut registers = Self { values: [None; 22] };
+        registers.values[6] = Some(rbp);
+        registers.values[7] = Some(stack_frame.stack_pointer.as_u64());
+        registers.values[16] = Some(stack_frame.instruction_pointer.as_u64());
+        registers.values[17] = Some(stack_frame.cpu_flags);
+        registers.read_control();
+        registers
+    }
+
+    fn read_control(&mut self) {
+        let (frame, flags) = Cr3::read_raw();
+        self.values[18] = Some(Cr0::read_raw());
+        self.values[19] = Some(Cr2::read_raw());
+        self.values[20] = Some(frame.start_address().as_u64() | flags as u64);
+        self.values[21] = Some(Cr4::read_raw());
+    }
+
+    /// Name and value of every known register
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        Self::NAMES.iter().copied().zip(self.values.iter()).filter_map(|(name, value)| Some((name, (*value)?)))
+    }
+}
+
+/// Report the registers of the interrupted code instead of the panic handler's in the next panic.
+/// Called by exception handlers right before they panic.
+pub fn exception(stack_frame: &InterruptStackFrame) {
+    let rbp = Backtrace::interrupted(stack_frame).rbp();
+    *EXCEPTION.lock() = Some(Registers::from_exception(stack_frame, rbp));
+}
+
+/// The registers given to [exception], if the panic comes from an exception handler
+pub fn take_exception() -> Option<Registers> {
+    EXCEPTION.try_lock()?.take()
+}
+
+/// The crash report of a panic, see the top of this file for the format
+pub struct CrashReport<'a> {
+    pub info: &'a PanicInfo<'a>,
+    pub registers: &'a Registers,
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "==== RUNIX CRASH REPORT BEGIN ====")?;
+        writeln!(f, "version={}", VERSION)?;
+        writeln!(f, "message={}", Escaped(self.info.message()))?;
+        match self.info.location() {
+            Some(location) => writeln!(f, "location={}", Escaped(location))?,
+            None => writeln!(f, "location=")?,
+        }
+        // The timer ticks are per-CPU
+        if percpu::is_initialized() {
+            writeln!(f, "uptime_us={}", interrupts::uptime().as_micros())?;
+        }
+        if let Some(boot_info) = bootinfo::try_get() {
+            writeln!(f, "cmdline={}", Escaped(boot_info.cmdline))?;
+        }
+        for (name, value) in self.registers.iter() {
+            writeln!(f, "register.{}={:#018x}", name, value)?;
+        }
+        for (i, frame) in self.backtrace.frames().enumerate() {
+            writeln!(f, "backtrace.{}={:#018x} {}", i, frame.return_address, Caller(frame.return_address))?;
+        }
+        let mut result = Ok(());
+        let mut i = 0;
+        logger::tail(LOG_LINES, |entry| {
+            result = result.and_then(|_| writeln!(f, "log.{}={}", i, Escaped(entry)));
+            i += 1;
+        });
+        result?;
+        writeln!(f, "==== RUNIX CRASH REPORT END ====")
+    }
+}
+
+/// Write the report to COM1 (unless serial output is disabled) and the debug console
+pub fn emit(report: &CrashReport) {
+    write!(serial::RawWriter, "{}", report).unwrap();
+    write!(Debugcon, "{}", report).unwrap();
+}
+
+/// The QEMU and Bochs debug console, ignored by real hardware
+struct Debugcon;
+
+impl fmt::Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = Port::<u8>::new(DEBUGCON);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Displays a value on one line, with the escapes described at the top of this file
+struct Escaped<T>(T);
+
+impl<T: fmt::Display> fmt::Display for Escaped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(EscapingWriter(f), "{}", self.0)
+    }
+}
+
+struct EscapingWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl fmt::Write for EscapingWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\\' => self.0.write_str("\\\\")?,
+                c if c.is_ascii_control() => write!(self.0, "\\x{:02x}", c as u8)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        assert_eq!(format!("{}", Escaped("plain")), "plain");
+        assert_eq!(format!("{}", Escaped("two\nlines\r\n")), "two\\nlines\\r\\n");
+        assert_eq!(format!("{}", Escaped("C:\\ \t\x1b[0m é")), "C:\\\\ \\x09\\x1b[0m é");
+        assert_eq!(format!("{}", Escaped(format_args!("{:?}", "a\nb"))), "\"a\\\\nb\"");
+    }
+}
@@ -1,32 +1,19 @@
-use core::slice;
-
-pub fn dump(addr: *const i8, len: usize) {
-    let longs = unsafe { slice::from_raw_parts(addr, len * 8) };
-    for ls in longs.chunks_exact(8) {
-        for l in ls {
-            print!("{:02x}", l);
-        }
-        println!();
-    }
-}
+use crate::bootinfo;
 
 pub fn print_elfsections() {
-    let mbi = crate::MBI.get().unwrap();
-    let elf = mbi.elf_symbols().next().unwrap();
-    for s in elf.sections() {
-        let name =  s.get_name(elf).unwrap_or_default();
-        let type_ = s.type_;
-        let addr = s.addr;
-        let size = s.size;
-        let flags = s.flags;
+    for s in bootinfo::get().kernel_sections.iter() {
+        let (name, type_, addr, size, flags) = (s.name, s.type_, s.addr, s.size, s.flags);
         println!("{name:16.16} type: {type_:#02x}, addr: {addr:#x}, size: {size:#x}, flags: {flags:#x}");
     }
 }
 
 pub fn print_memoryareas() {
-    let memory_map = crate::MBI.get().unwrap().memory_map().unwrap();
-    for entry in &memory_map.entries {
-        println!("    base: {:#14x}   size: {:#14x} (type {:#x})", entry.base_addr, entry.length, entry.type_)
+    let mut regions = bootinfo::get().memory_regions().peekable();
+    if regions.peek().is_none() {
+        println!("No memory map");
+    }
+    for region in regions {
+        println!("    base: {:#14x}   size: {:#14x} ({:?})", region.start, region.end - region.start, region.kind)
     }
 }
 
@@ -46,7 +33,9 @@ pub fn print_registers() {
 #[allow(dead_code)]
 #[allow(unconditional_recursion)]
 pub fn stack_overflow() {
-    stack_overflow()
+    stack_overflow();
+    // Keeps the recursion from being turned into a loop
+    volatile::Volatile::new(0).read();
 }
 
 /**
@@ -58,3 +47,14 @@ pub fn page_fault() {
     unsafe { *ptr = 69; }
 }
 
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use crate::testing::ShouldPanic;
+
+    #[test_case]
+    static STACK_OVERFLOW: ShouldPanic = ShouldPanic("debug::stack_overflow", super::stack_overflow);
+
+    #[test_case]
+    static PAGE_FAULT: ShouldPanic = ShouldPanic("debug::page_fault", super::page_fault);
+}
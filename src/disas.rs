@@ -0,0 +1,1069 @@
+//! A compact x86-64 instruction decoder for `kdebug> disas` and exception reports, in Intel syntax
+//! https://wiki.osdev.org/X86-64_Instruction_Encoding
+//! https://www.felixcloutier.com/x86/
+
+// Only 64-bit mode is decoded, and only the common integer, system and SSE instructions
+// compilers emit for the kernel. Anything else is reported as invalid rather than guessed at.
+//
+// An instruction is: legacy prefixes, REX, the opcode (0F escapes to the two-byte map),
+// ModRM and SIB selecting registers and memory, a displacement and an immediate.
+// 66, F3 and F2 select the operand size and rep for integer instructions, but the variant of SSE instructions.
+
+use core::fmt;
+
+use crate::fixup;
+use crate::symbols::Symbolized;
+
+/// Longest valid instruction
+pub const MAX_LENGTH: usize = 15;
+
+const REX_W: u8 = 8;
+const REX_R: u8 = 4;
+const REX_X: u8 = 2;
+const REX_B: u8 = 1;
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const UNARY: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const JCC: [&str; 16] = ["jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge", "jle", "jg"];
+const SETCC: [&str; 16] = [
+    "seto", "setno", "setb", "setae", "sete", "setne", "setbe", "seta",
+    "sets", "setns", "setp", "setnp", "setl", "setge", "setle", "setg",
+];
+const CMOVCC: [&str; 16] = [
+    "cmovo", "cmovno", "cmovb", "cmovae", "cmove", "cmovne", "cmovbe", "cmova",
+    "cmovs", "cmovns", "cmovp", "cmovnp", "cmovl", "cmovge", "cmovle", "cmovg",
+];
+
+const GPR64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const GPR32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+const GPR16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"];
+const GPR8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+/// Byte registers 4 to 7 without a REX prefix
+const GPR8_LEGACY: [&str; 4] = ["ah", "ch", "dh", "bh"];
+const SEGMENTS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end before the instruction does
+    Truncated,
+    /// Not an instruction, or one the decoder does not know
+    Invalid,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "(truncated)"),
+            DecodeError::Invalid => write!(f, "(bad)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    /// A general purpose register and its size in bytes. Byte registers 4 to 7 are ah to bh unless there is a REX prefix
+    Gpr { num: u8, size: u8, rex: bool },
+    Xmm(u8),
+    Control(u8),
+    Debug(u8),
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Register::Gpr { num, size: 1, rex: false } if (4..8).contains(&num) => f.write_str(GPR8_LEGACY[num as usize - 4]),
+            Register::Gpr { num, size, .. } => f.write_str(gpr_name(num, size)),
+            Register::Xmm(num) => write!(f, "xmm{}", num),
+            Register::Control(num) => write!(f, "cr{}", num),
+            Register::Debug(num) => write!(f, "dr{}", num),
+        }
+    }
+}
+
+fn gpr_name(num: u8, size: u8) -> &'static str {
+    let names = match size {
+        1 => &GPR8,
+        2 => &GPR16,
+        4 => &GPR32,
+        _ => &GPR64,
+    };
+    names[num as usize]
+}
+
+/// A memory operand, `size ptr seg:[base+index*scale+disp]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Memory {
+    /// In bytes, 0 if it is not shown (lea)
+    size: u8,
+    segment: Option<u8>,
+    base: Option<u8>,
+    index: Option<u8>,
+    scale: u8,
+    disp: i32,
+    /// Relative to the next instruction
+    rip: bool,
+    /// 32-bit address registers (67 prefix)
+    address32: bool,
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = match self.size {
+            1 => "byte ptr ",
+            2 => "word ptr ",
+            4 => "dword ptr ",
+            8 => "qword ptr ",
+            10 => "tbyte ptr ",
+            16 => "xmmword ptr ",
+            _ => "",
+        };
+        f.write_str(size)?;
+        if let Some(segment) = self.segment {
+            write!(f, "{}:", SEGMENTS[segment as usize])?;
+        }
+        let address_size = if self.address32 { 4 } else { 8 };
+        f.write_str("[")?;
+        let mut first = true;
+        if self.rip {
+            f.write_str(if self.address32 { "eip" } else { "rip" })?;
+            first = false;
+        }
+        if let Some(base) = self.base {
+            f.write_str(gpr_name(base, address_size))?;
+            first = false;
+        }
+        if let Some(index) = self.index {
+            if !first {
+                f.write_str("+")?;
+            }
+            f.write_str(gpr_name(index, address_size))?;
+            if self.scale > 1 {
+                write!(f, "*{}", self.scale)?;
+            }
+            first = false;
+        }
+        match self.disp {
+            disp if first => write!(f, "{:#x}", disp as u32)?,
+            0 => {},
+            disp if disp < 0 => write!(f, "-{:#x}", disp.unsigned_abs())?,
+            disp => write!(f, "+{:#x}", disp)?,
+        }
+        f.write_str("]")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    None,
+    Register(Register),
+    Memory(Memory),
+    /// Zero extended from the operand size
+    Immediate(u64),
+    /// A branch relative to the next instruction, replaced with the target once the length is known
+    Relative(i64),
+    Target(u64),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::None => Ok(()),
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Memory(memory) => write!(f, "{}", memory),
+            Operand::Immediate(value) => write!(f, "{:#x}", value),
+            Operand::Relative(offset) => write!(f, ".{:+#x}", offset),
+            Operand::Target(addr) => write!(f, "{:#x}", addr),
+        }
+    }
+}
+
+/// A decoded instruction, displayed in Intel syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u64,
+    pub len: usize,
+    /// `lock`, `rep`, `repe` or `repne`
+    prefix: Option<&'static str>,
+    mnemonic: &'static str,
+    operands: [Operand; 3],
+}
+
+impl Instruction {
+    /// The address a branch goes to, or a RIP relative operand refers to
+    pub fn target(&self) -> Option<u64> {
+        self.operands.iter().find_map(|operand| match operand {
+            Operand::Target(addr) => Some(*addr),
+            Operand::Memory(memory) if memory.rip => {
+                Some(self.addr.wrapping_add(self.len as u64).wrapping_add(memory.disp as i64 as u64))
+            },
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = self.prefix {
+            write!(f, "{} ", prefix)?;
+        }
+        f.write_str(self.mnemonic)?;
+        for (i, operand) in self.operands.iter().take_while(|operand| **operand != Operand::None).enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+            write!(f, "{}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// The mnemonic and operands of the instruction being decoded
+type Decoded = Result<(&'static str, [Operand; 3]), DecodeError>;
+
+macro_rules! ins {
+    ($mnemonic:expr) => {
+        Ok(($mnemonic, [Operand::None; 3]))
+    };
+    ($mnemonic:expr, $a:expr) => {
+        Ok(($mnemonic, [$a, Operand::None, Operand::None]))
+    };
+    ($mnemonic:expr, $a:expr, $b:expr) => {
+        Ok(($mnemonic, [$a, $b, Operand::None]))
+    };
+    ($mnemonic:expr, $a:expr, $b:expr, $c:expr) => {
+        Ok(($mnemonic, [$a, $b, $c]))
+    };
+}
+
+/// The operand selected by ModRM.rm
+#[derive(Debug, Clone, Copy)]
+enum Rm {
+    Register(u8),
+    Memory(Memory),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModRm {
+    /// ModRM.reg, extended by REX.R
+    reg: u8,
+    /// ModRM.reg as is, the opcode extension of group opcodes
+    ext: u8,
+    rm: Rm,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    rex: u8,
+    operand16: bool,
+    address32: bool,
+    /// F2 or F3, the last one wins
+    rep: Option<u8>,
+    lock: bool,
+    segment: Option<u8>,
+    /// Set by string instructions, which show rep
+    prefix: Option<&'static str>,
+}
+
+/// Decode the instruction at the start of `bytes`, which is at `addr`
+pub fn decode(bytes: &[u8], addr: u64) -> Result<Instruction, DecodeError> {
+    let mut decoder = Decoder {
+        bytes: &bytes[..bytes.len().min(MAX_LENGTH)],
+        pos: 0,
+        rex: 0,
+        operand16: false,
+        address32: false,
+        rep: None,
+        lock: false,
+        segment: None,
+        prefix: None,
+    };
+    loop {
+        match decoder.peek()? {
+            0x66 => decoder.operand16 = true,
+            0x67 => decoder.address32 = true,
+            0xf0 => decoder.lock = true,
+            byte @ (0xf2 | 0xf3) => decoder.rep = Some(byte),
+            // Ignored in 64-bit mode
+            0x26 | 0x2e | 0x36 | 0x3e => {},
+            0x64 => decoder.segment = Some(4),
+            0x65 => decoder.segment = Some(5),
+            _ => break,
+        }
+        decoder.pos += 1;
+    }
+    if let rex @ 0x40..=0x4f = decoder.peek()? {
+        decoder.rex = rex;
+        decoder.pos += 1;
+    }
+    let opcode = decoder.u8()?;
+    let (mnemonic, mut operands) = decoder.one_byte(opcode)?;
+    let len = decoder.pos;
+    for operand in &mut operands {
+        if let Operand::Relative(offset) = *operand {
+            *operand = Operand::Target(addr.wrapping_add(len as u64).wrapping_add(offset as u64));
+        }
+    }
+    let prefix = match decoder.lock {
+        true => Some("lock"),
+        false => decoder.prefix,
+    };
+    Ok(Instruction { addr, len, prefix, mnemonic, operands })
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.bytes.get(self.pos).copied().ok_or(DecodeError::Truncated)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    /// A little endian signed value of `size` bytes
+    fn signed(&mut self, size: usize) -> Result<i64, DecodeError> {
+        let bytes = self.bytes.get(self.pos..self.pos + size).ok_or(DecodeError::Truncated)?;
+        self.pos += size;
+        let mut le = [0; 8];
+        le[..size].copy_from_slice(bytes);
+        let shift = 64 - size as u32 * 8;
+        Ok(i64::from_le_bytes(le) << shift >> shift)
+    }
+
+    /// The operand size of most instructions
+    fn size(&self) -> u8 {
+        match (self.rex & REX_W != 0, self.operand16) {
+            (true, _) => 8,
+            (false, true) => 2,
+            (false, false) => 4,
+        }
+    }
+
+    /// The operand size of instructions that default to 64 bits, like push and near branches
+    fn size64(&self) -> u8 {
+        if self.operand16 { 2 } else { 8 }
+    }
+
+    fn gpr(&self, num: u8, size: u8) -> Operand {
+        Operand::Register(Register::Gpr { num, size, rex: self.rex != 0 })
+    }
+
+    /// The register in the low bits of the opcode, extended by REX.B
+    fn opcode_gpr(&self, opcode: u8, size: u8) -> Operand {
+        self.gpr(opcode & 7 | (self.rex & REX_B) << 3, size)
+    }
+
+    /// An immediate of the operand size, at most 4 bytes sign extended
+    fn imm(&mut self, size: u8) -> Result<Operand, DecodeError> {
+        let value = self.signed(size.min(4) as usize)?;
+        Ok(Operand::Immediate(truncate(value, size)))
+    }
+
+    /// A byte immediate sign extended to `size`
+    fn imm8(&mut self, size: u8) -> Result<Operand, DecodeError> {
+        let value = self.signed(1)?;
+        Ok(Operand::Immediate(truncate(value, size)))
+    }
+
+    fn rel(&mut self, size: usize) -> Result<Operand, DecodeError> {
+        Ok(Operand::Relative(self.signed(size)?))
+    }
+
+    fn modrm(&mut self) -> Result<ModRm, DecodeError> {
+        let byte = self.u8()?;
+        let (mode, ext, rm) = (byte >> 6, byte >> 3 & 7, byte & 7);
+        let reg = ext | (self.rex & REX_R) << 1;
+        if mode == 3 {
+            return Ok(ModRm { reg, ext, rm: Rm::Register(rm | (self.rex & REX_B) << 3) });
+        }
+        let mut memory = Memory {
+            size: 0,
+            segment: self.segment,
+            base: None,
+            index: None,
+            scale: 1,
+            disp: 0,
+            rip: false,
+            address32: self.address32,
+        };
+        if rm == 4 {
+            let sib = self.u8()?;
+            let index = sib >> 3 & 7 | (self.rex & REX_X) << 2;
+            if index != 4 {
+                memory.index = Some(index);
+                memory.scale = 1 << (sib >> 6);
+            }
+            if sib & 7 == 5 && mode == 0 {
+                memory.disp = self.signed(4)? as i32;
+            } else {
+                memory.base = Some(sib & 7 | (self.rex & REX_B) << 3);
+            }
+        } else if rm == 5 && mode == 0 {
+            memory.rip = true;
+            memory.disp = self.signed(4)? as i32;
+        } else {
+            memory.base = Some(rm | (self.rex & REX_B) << 3);
+        }
+        match mode {
+            1 => memory.disp = self.signed(1)? as i32,
+            2 => memory.disp = self.signed(4)? as i32,
+            _ => {},
+        }
+        Ok(ModRm { reg, ext, rm: Rm::Memory(memory) })
+    }
+
+    /// ModRM.rm as a general purpose register or memory of `size` bytes
+    fn rm(&self, modrm: &ModRm, size: u8) -> Operand {
+        match modrm.rm {
+            Rm::Register(num) => self.gpr(num, size),
+            Rm::Memory(memory) => Operand::Memory(Memory { size, ..memory }),
+        }
+    }
+
+    /// ModRM.rm as an XMM register or memory of `size` bytes
+    fn xmm_rm(&self, modrm: &ModRm, size: u8) -> Operand {
+        match modrm.rm {
+            Rm::Register(num) => Operand::Register(Register::Xmm(num)),
+            Rm::Memory(memory) => Operand::Memory(Memory { size, ..memory }),
+        }
+    }
+
+    /// ModRM.rm, which has to be memory
+    fn memory(&self, modrm: &ModRm, size: u8) -> Result<Operand, DecodeError> {
+        match modrm.rm {
+            Rm::Register(_) => Err(DecodeError::Invalid),
+            Rm::Memory(memory) => Ok(Operand::Memory(Memory { size, ..memory })),
+        }
+    }
+
+    fn reg(&self, modrm: &ModRm, size: u8) -> Operand {
+        self.gpr(modrm.reg, size)
+    }
+
+    fn xmm(&self, modrm: &ModRm) -> Operand {
+        Operand::Register(Register::Xmm(modrm.reg))
+    }
+
+    /// rep, repe or repne for string instructions, `compares` for cmps and scas
+    fn string(&mut self, mnemonics: [&'static str; 4], opcode: u8, compares: bool) -> Decoded {
+        self.prefix = match (self.rep, compares) {
+            (Some(0xf3), false) => Some("rep"),
+            (Some(0xf3), true) => Some("repe"),
+            (Some(_), true) => Some("repne"),
+            _ => None,
+        };
+        let size = if opcode & 1 == 0 { 1 } else { self.size() };
+        ins!(mnemonics[size.trailing_zeros() as usize])
+    }
+
+    fn one_byte(&mut self, opcode: u8) -> Decoded {
+        let size = self.size();
+        match opcode {
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let mnemonic = ALU[opcode as usize >> 3];
+                match opcode & 7 {
+                    0 | 1 => {
+                        let size = if opcode & 1 == 0 { 1 } else { size };
+                        let modrm = self.modrm()?;
+                        ins!(mnemonic, self.rm(&modrm, size), self.reg(&modrm, size))
+                    },
+                    2 | 3 => {
+                        let size = if opcode & 1 == 0 { 1 } else { size };
+                        let modrm = self.modrm()?;
+                        ins!(mnemonic, self.reg(&modrm, size), self.rm(&modrm, size))
+                    },
+                    4 => ins!(mnemonic, self.gpr(0, 1), self.imm(1)?),
+                    _ => ins!(mnemonic, self.gpr(0, size), self.imm(size)?),
+                }
+            },
+            0x0f => self.two_byte(),
+            0x50..=0x57 => ins!("push", self.opcode_gpr(opcode, self.size64())),
+            0x58..=0x5f => ins!("pop", self.opcode_gpr(opcode, self.size64())),
+            0x63 => {
+                let modrm = self.modrm()?;
+                ins!("movsxd", self.reg(&modrm, size), self.rm(&modrm, 4))
+            },
+            0x68 => ins!("push", self.imm(self.size64())?),
+            0x69 => {
+                let modrm = self.modrm()?;
+                ins!("imul", self.reg(&modrm, size), self.rm(&modrm, size), self.imm(size)?)
+            },
+            0x6a => ins!("push", self.imm8(self.size64())?),
+            0x6b => {
+                let modrm = self.modrm()?;
+                ins!("imul", self.reg(&modrm, size), self.rm(&modrm, size), self.imm8(size)?)
+            },
+            0x70..=0x7f => ins!(JCC[opcode as usize & 0xf], self.rel(1)?),
+            0x80 => {
+                let modrm = self.modrm()?;
+                ins!(ALU[modrm.ext as usize], self.rm(&modrm, 1), self.imm(1)?)
+            },
+            0x81 => {
+                let modrm = self.modrm()?;
+                ins!(ALU[modrm.ext as usize], self.rm(&modrm, size), self.imm(size)?)
+            },
+            0x83 => {
+                let modrm = self.modrm()?;
+                ins!(ALU[modrm.ext as usize], self.rm(&modrm, size), self.imm8(size)?)
+            },
+            0x84..=0x8b => {
+                let mnemonic = match opcode {
+                    0x84 | 0x85 => "test",
+                    0x86 | 0x87 => "xchg",
+                    _ => "mov",
+                };
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                match opcode {
+                    0x8a | 0x8b => ins!(mnemonic, self.reg(&modrm, size), self.rm(&modrm, size)),
+                    _ => ins!(mnemonic, self.rm(&modrm, size), self.reg(&modrm, size)),
+                }
+            },
+            0x8d => {
+                let modrm = self.modrm()?;
+                ins!("lea", self.reg(&modrm, size), self.memory(&modrm, 0)?)
+            },
+            0x8f => match self.modrm()? {
+                modrm if modrm.ext == 0 => ins!("pop", self.rm(&modrm, self.size64())),
+                _ => Err(DecodeError::Invalid),
+            },
+            0x90 if self.rex & REX_B == 0 => match self.rep {
+                Some(0xf3) => ins!("pause"),
+                _ => ins!("nop"),
+            },
+            0x91..=0x97 | 0x90 => ins!("xchg", self.opcode_gpr(opcode, size), self.gpr(0, size)),
+            0x98 => ins!(["cbw", "cwde", "cdqe"][size.trailing_zeros() as usize - 1]),
+            0x99 => ins!(["cwd", "cdq", "cqo"][size.trailing_zeros() as usize - 1]),
+            0x9c => ins!("pushfq"),
+            0x9d => ins!("popfq"),
+            0xa4 | 0xa5 => self.string(["movsb", "movsw", "movsd", "movsq"], opcode, false),
+            0xa6 | 0xa7 => self.string(["cmpsb", "cmpsw", "cmpsd", "cmpsq"], opcode, true),
+            0xa8 => ins!("test", self.gpr(0, 1), self.imm(1)?),
+            0xa9 => ins!("test", self.gpr(0, size), self.imm(size)?),
+            0xaa | 0xab => self.string(["stosb", "stosw", "stosd", "stosq"], opcode, false),
+            0xac | 0xad => self.string(["lodsb", "lodsw", "lodsd", "lodsq"], opcode, false),
+            0xae | 0xaf => self.string(["scasb", "scasw", "scasd", "scasq"], opcode, true),
+            0xb0..=0xb7 => ins!("mov", self.opcode_gpr(opcode, 1), self.imm(1)?),
+            0xb8..=0xbf => {
+                // The only instruction with a full 64-bit immediate
+                let register = self.opcode_gpr(opcode, size);
+                let value = self.signed(size as usize)?;
+                ins!("mov", register, Operand::Immediate(truncate(value, size)))
+            },
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.imm(1)?,
+                    0xd0 | 0xd1 => Operand::Immediate(1),
+                    _ => self.gpr(1, 1),
+                };
+                ins!(SHIFT[modrm.ext as usize], self.rm(&modrm, size), count)
+            },
+            0xc2 => ins!("ret", Operand::Immediate(self.signed(2)? as u16 as u64)),
+            0xc3 => ins!("ret"),
+            0xc6 | 0xc7 => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                match self.modrm()? {
+                    modrm if modrm.ext == 0 => ins!("mov", self.rm(&modrm, size), self.imm(size)?),
+                    _ => Err(DecodeError::Invalid),
+                }
+            },
+            0xc9 => ins!("leave"),
+            0xcc => ins!("int3"),
+            0xcd => ins!("int", self.imm(1)?),
+            0xcf => ins!(if size == 8 { "iretq" } else { "iretd" }),
+            0xe0 => ins!("loopne", self.rel(1)?),
+            0xe1 => ins!("loope", self.rel(1)?),
+            0xe2 => ins!("loop", self.rel(1)?),
+            0xe3 => ins!("jrcxz", self.rel(1)?),
+            0xe4..=0xe7 | 0xec..=0xef => {
+                let size = match (opcode & 1, self.operand16) {
+                    (0, _) => 1,
+                    (_, true) => 2,
+                    _ => 4,
+                };
+                let port = match opcode {
+                    0xe4..=0xe7 => self.imm(1)?,
+                    _ => self.gpr(2, 2),
+                };
+                match opcode & 2 {
+                    0 => ins!("in", self.gpr(0, size), port),
+                    _ => ins!("out", port, self.gpr(0, size)),
+                }
+            },
+            0xe8 => ins!("call", self.rel(4)?),
+            0xe9 => ins!("jmp", self.rel(4)?),
+            0xeb => ins!("jmp", self.rel(1)?),
+            0xf4 => ins!("hlt"),
+            0xf5 => ins!("cmc"),
+            0xf6 | 0xf7 => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let mnemonic = UNARY[modrm.ext as usize];
+                match modrm.ext {
+                    0 | 1 => ins!(mnemonic, self.rm(&modrm, size), self.imm(size)?),
+                    _ => ins!(mnemonic, self.rm(&modrm, size)),
+                }
+            },
+            0xf8 => ins!("clc"),
+            0xf9 => ins!("stc"),
+            0xfa => ins!("cli"),
+            0xfb => ins!("sti"),
+            0xfc => ins!("cld"),
+            0xfd => ins!("std"),
+            0xfe => match self.modrm()? {
+                modrm if modrm.ext < 2 => ins!(["inc", "dec"][modrm.ext as usize], self.rm(&modrm, 1)),
+                _ => Err(DecodeError::Invalid),
+            },
+            0xff => {
+                let modrm = self.modrm()?;
+                match modrm.ext {
+                    0 => ins!("inc", self.rm(&modrm, size)),
+                    1 => ins!("dec", self.rm(&modrm, size)),
+                    2 => ins!("call", self.rm(&modrm, 8)),
+                    4 => ins!("jmp", self.rm(&modrm, 8)),
+                    6 => ins!("push", self.rm(&modrm, self.size64())),
+                    _ => Err(DecodeError::Invalid),
+                }
+            },
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    fn two_byte(&mut self) -> Decoded {
+        let opcode = self.u8()?;
+        let size = self.size();
+        // The SSE variant: none, 66, F3 or F2
+        let variant = match (self.rep, self.operand16) {
+            (Some(0xf3), _) => 2,
+            (Some(_), _) => 3,
+            (None, true) => 1,
+            (None, false) => 0,
+        };
+        // Scalar single and double, packed is a whole register
+        let sse_size = [16, 16, 4, 8][variant];
+        match opcode {
+            0x01 => match self.peek()? {
+                0xf8 => {
+                    self.pos += 1;
+                    ins!("swapgs")
+                },
+                0xf9 => {
+                    self.pos += 1;
+                    ins!("rdtscp")
+                },
+                _ => {
+                    let modrm = self.modrm()?;
+                    let mnemonic = match modrm.ext {
+                        0 => "sgdt",
+                        1 => "sidt",
+                        2 => "lgdt",
+                        3 => "lidt",
+                        7 => "invlpg",
+                        _ => return Err(DecodeError::Invalid),
+                    };
+                    ins!(mnemonic, self.memory(&modrm, 0)?)
+                },
+            },
+            0x05 => ins!("syscall"),
+            0x06 => ins!("clts"),
+            0x07 => ins!(if size == 8 { "sysretq" } else { "sysret" }),
+            0x08 => ins!("invd"),
+            0x09 => ins!("wbinvd"),
+            0x0b => ins!("ud2"),
+            0x10 | 0x11 => {
+                let mnemonic = ["movups", "movupd", "movss", "movsd"][variant];
+                let modrm = self.modrm()?;
+                match opcode {
+                    0x10 => ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, sse_size)),
+                    _ => ins!(mnemonic, self.xmm_rm(&modrm, sse_size), self.xmm(&modrm)),
+                }
+            },
+            0x14 | 0x15 if variant < 2 => {
+                let mnemonic = [["unpcklps", "unpcklpd"], ["unpckhps", "unpckhpd"]][opcode as usize & 1][variant];
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, 16))
+            },
+            0x1f => {
+                let modrm = self.modrm()?;
+                ins!("nop", self.rm(&modrm, size))
+            },
+            0x20..=0x23 => {
+                // Always registers, whatever ModRM.mod says
+                let byte = self.u8()?;
+                let gpr = self.gpr(byte & 7 | (self.rex & REX_B) << 3, 8);
+                let num = byte >> 3 & 7 | (self.rex & REX_R) << 1;
+                let special = match opcode & 1 {
+                    0 => Operand::Register(Register::Control(num)),
+                    _ => Operand::Register(Register::Debug(num)),
+                };
+                match opcode & 2 {
+                    0 => ins!("mov", gpr, special),
+                    _ => ins!("mov", special, gpr),
+                }
+            },
+            0x28 | 0x29 if variant < 2 => {
+                let mnemonic = ["movaps", "movapd"][variant];
+                let modrm = self.modrm()?;
+                match opcode {
+                    0x28 => ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, 16)),
+                    _ => ins!(mnemonic, self.xmm_rm(&modrm, 16), self.xmm(&modrm)),
+                }
+            },
+            0x2a if variant >= 2 => {
+                let modrm = self.modrm()?;
+                let size = if self.rex & REX_W != 0 { 8 } else { 4 };
+                ins!(["cvtsi2ss", "cvtsi2sd"][variant - 2], self.xmm(&modrm), self.rm(&modrm, size))
+            },
+            0x2c | 0x2d if variant >= 2 => {
+                let mnemonic = [["cvttss2si", "cvttsd2si"], ["cvtss2si", "cvtsd2si"]][opcode as usize & 1][variant - 2];
+                let modrm = self.modrm()?;
+                let size = if self.rex & REX_W != 0 { 8 } else { 4 };
+                ins!(mnemonic, self.reg(&modrm, size), self.xmm_rm(&modrm, sse_size))
+            },
+            0x2e | 0x2f if variant < 2 => {
+                let mnemonic = [["ucomiss", "ucomisd"], ["comiss", "comisd"]][opcode as usize & 1][variant];
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, [4, 8][variant]))
+            },
+            0x30 => ins!("wrmsr"),
+            0x31 => ins!("rdtsc"),
+            0x32 => ins!("rdmsr"),
+            0x40..=0x4f => {
+                let modrm = self.modrm()?;
+                ins!(CMOVCC[opcode as usize & 0xf], self.reg(&modrm, size), self.rm(&modrm, size))
+            },
+            0x51 | 0x58 | 0x59 | 0x5c..=0x5f => {
+                let mnemonics = match opcode {
+                    0x51 => ["sqrtps", "sqrtpd", "sqrtss", "sqrtsd"],
+                    0x58 => ["addps", "addpd", "addss", "addsd"],
+                    0x59 => ["mulps", "mulpd", "mulss", "mulsd"],
+                    0x5c => ["subps", "subpd", "subss", "subsd"],
+                    0x5d => ["minps", "minpd", "minss", "minsd"],
+                    0x5e => ["divps", "divpd", "divss", "divsd"],
+                    _ => ["maxps", "maxpd", "maxss", "maxsd"],
+                };
+                let modrm = self.modrm()?;
+                ins!(mnemonics[variant], self.xmm(&modrm), self.xmm_rm(&modrm, sse_size))
+            },
+            0x54..=0x57 if variant < 2 => {
+                let mnemonic = [["andps", "andpd"], ["andnps", "andnpd"], ["orps", "orpd"], ["xorps", "xorpd"]][opcode as usize - 0x54][variant];
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, 16))
+            },
+            0x5a => {
+                let mnemonic = ["cvtps2pd", "cvtpd2ps", "cvtss2sd", "cvtsd2ss"][variant];
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, [8, 16, 4, 8][variant]))
+            },
+            0x6e if variant == 1 => {
+                let modrm = self.modrm()?;
+                match self.rex & REX_W {
+                    0 => ins!("movd", self.xmm(&modrm), self.rm(&modrm, 4)),
+                    _ => ins!("movq", self.xmm(&modrm), self.rm(&modrm, 8)),
+                }
+            },
+            0x6f | 0x7f if variant == 1 || variant == 2 => {
+                let mnemonic = ["movdqa", "movdqu"][variant - 1];
+                let modrm = self.modrm()?;
+                match opcode {
+                    0x6f => ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, 16)),
+                    _ => ins!(mnemonic, self.xmm_rm(&modrm, 16), self.xmm(&modrm)),
+                }
+            },
+            0x7e if variant == 1 => {
+                let modrm = self.modrm()?;
+                match self.rex & REX_W {
+                    0 => ins!("movd", self.rm(&modrm, 4), self.xmm(&modrm)),
+                    _ => ins!("movq", self.rm(&modrm, 8), self.xmm(&modrm)),
+                }
+            },
+            0x7e if variant == 2 => {
+                let modrm = self.modrm()?;
+                ins!("movq", self.xmm(&modrm), self.xmm_rm(&modrm, 8))
+            },
+            0x80..=0x8f => ins!(JCC[opcode as usize & 0xf], self.rel(4)?),
+            0x90..=0x9f => {
+                let modrm = self.modrm()?;
+                ins!(SETCC[opcode as usize & 0xf], self.rm(&modrm, 1))
+            },
+            0xa2 => ins!("cpuid"),
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let mnemonic = ["bt", "bts", "btr", "btc"][(opcode as usize >> 3) & 3];
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.rm(&modrm, size), self.reg(&modrm, size))
+            },
+            0xae => {
+                let modrm = self.modrm()?;
+                match (modrm.rm, modrm.ext) {
+                    (Rm::Register(_), 5) => ins!("lfence"),
+                    (Rm::Register(_), 6) => ins!("mfence"),
+                    (Rm::Register(_), 7) => ins!("sfence"),
+                    (Rm::Register(_), _) => Err(DecodeError::Invalid),
+                    (_, 0) => ins!("fxsave", self.memory(&modrm, 0)?),
+                    (_, 1) => ins!("fxrstor", self.memory(&modrm, 0)?),
+                    (_, 2) => ins!("ldmxcsr", self.memory(&modrm, 4)?),
+                    (_, 3) => ins!("stmxcsr", self.memory(&modrm, 4)?),
+                    (_, 7) => ins!("clflush", self.memory(&modrm, 1)?),
+                    _ => Err(DecodeError::Invalid),
+                }
+            },
+            0xaf => {
+                let modrm = self.modrm()?;
+                ins!("imul", self.reg(&modrm, size), self.rm(&modrm, size))
+            },
+            0xb0 | 0xb1 | 0xc0 | 0xc1 => {
+                let mnemonic = if opcode < 0xc0 { "cmpxchg" } else { "xadd" };
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.rm(&modrm, size), self.reg(&modrm, size))
+            },
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let mnemonic = if opcode < 0xbe { "movzx" } else { "movsx" };
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.reg(&modrm, size), self.rm(&modrm, 1 + (opcode & 1)))
+            },
+            0xb8 if variant == 2 => {
+                let modrm = self.modrm()?;
+                ins!("popcnt", self.reg(&modrm, size), self.rm(&modrm, size))
+            },
+            0xba => {
+                let modrm = self.modrm()?;
+                match modrm.ext {
+                    4..=7 => ins!(["bt", "bts", "btr", "btc"][modrm.ext as usize - 4], self.rm(&modrm, size), self.imm(1)?),
+                    _ => Err(DecodeError::Invalid),
+                }
+            },
+            0xbc | 0xbd => {
+                let mnemonic = match variant {
+                    2 => ["tzcnt", "lzcnt"][opcode as usize & 1],
+                    _ => ["bsf", "bsr"][opcode as usize & 1],
+                };
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.reg(&modrm, size), self.rm(&modrm, size))
+            },
+            0xc6 if variant < 2 => {
+                let modrm = self.modrm()?;
+                ins!(["shufps", "shufpd"][variant], self.xmm(&modrm), self.xmm_rm(&modrm, 16), self.imm(1)?)
+            },
+            0xc8..=0xcf => ins!("bswap", self.opcode_gpr(opcode, size)),
+            0xd6 if variant == 1 => {
+                let modrm = self.modrm()?;
+                ins!("movq", self.xmm_rm(&modrm, 8), self.xmm(&modrm))
+            },
+            0xd7 if variant == 1 => match self.modrm()? {
+                modrm @ ModRm { rm: Rm::Register(_), .. } => ins!("pmovmskb", self.reg(&modrm, 4), self.xmm_rm(&modrm, 16)),
+                _ => Err(DecodeError::Invalid),
+            },
+            0x74..=0x76 | 0xd4 | 0xdb | 0xdf | 0xeb | 0xef | 0xf8 | 0xfa | 0xfb | 0xfc | 0xfe if variant == 1 => {
+                let mnemonic = match opcode {
+                    0x74 => "pcmpeqb",
+                    0x75 => "pcmpeqw",
+                    0x76 => "pcmpeqd",
+                    0xd4 => "paddq",
+                    0xdb => "pand",
+                    0xdf => "pandn",
+                    0xeb => "por",
+                    0xef => "pxor",
+                    0xf8 => "psubb",
+                    0xfa => "psubd",
+                    0xfb => "psubq",
+                    0xfc => "paddb",
+                    _ => "paddd",
+                };
+                let modrm = self.modrm()?;
+                ins!(mnemonic, self.xmm(&modrm), self.xmm_rm(&modrm, 16))
+            },
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
+
+/// `value` as an unsigned number of `size` bytes
+fn truncate(value: i64, size: u8) -> u64 {
+    match size {
+        8 => value as u64,
+        _ => value as u64 & ((1 << (size as u32 * 8)) - 1),
+    }
+}
+
+/// Print `count` instructions from `addr`, a bad address ends the listing
+pub fn print(mut addr: u64, count: usize) {
+    for _ in 0..count {
+        let mut bytes = [0; MAX_LENGTH];
+        let mut readable = 0;
+        while let (true, Ok(byte)) = (readable < MAX_LENGTH, fixup::probe_read(addr.wrapping_add(readable as u64))) {
+            bytes[readable] = byte;
+            readable += 1;
+        }
+        if readable == 0 {
+            println!("{}", fixup::Fault { addr });
+            return;
+        }
+        match decode(&bytes[..readable], addr) {
+            Ok(instruction) => {
+                print_line(addr, &bytes[..instruction.len], &instruction);
+                if let Some(target) = instruction.target() {
+                    println!("    # {}", Symbolized(target));
+                }
+                addr = addr.wrapping_add(instruction.len as u64);
+            },
+            // The rest is not mapped
+            Err(e @ DecodeError::Truncated) => {
+                print_line(addr, &bytes[..readable], &e);
+                return;
+            },
+            Err(e @ DecodeError::Invalid) => {
+                print_line(addr, &bytes[..1], &e);
+                addr = addr.wrapping_add(1);
+            },
+        }
+    }
+}
+
+fn print_line(addr: u64, bytes: &[u8], text: &dyn fmt::Display) {
+    print!("{:#x}  ", addr);
+    for i in 0..8 {
+        match bytes.get(i) {
+            Some(byte) => print!("{:02x} ", byte),
+            None => print!("   "),
+        }
+    }
+    println!("{}{}", if bytes.len() > 8 { "..  " } else { " " }, text);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn disas(bytes: &[u8]) -> String {
+        let instruction = decode(bytes, 0x1000).unwrap();
+        assert_eq!(instruction.len, bytes.len(), "length of {}", instruction);
+        instruction.to_string()
+    }
+
+    #[test]
+    fn integer() {
+        assert_eq!(disas(&[0x55]), "push rbp");
+        assert_eq!(disas(&[0x48, 0x89, 0xe5]), "mov rbp, rsp");
+        assert_eq!(disas(&[0x48, 0x83, 0xec, 0x20]), "sub rsp, 0x20");
+        assert_eq!(disas(&[0x48, 0x83, 0xc4, 0x80]), "add rsp, 0xffffffffffffff80");
+        assert_eq!(disas(&[0x48, 0x8b, 0x45, 0xf8]), "mov rax, qword ptr [rbp-0x8]");
+        assert_eq!(disas(&[0x41, 0x8b, 0x04, 0x9c]), "mov eax, dword ptr [r12+rbx*4]");
+        assert_eq!(disas(&[0x8b, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00]), "mov eax, dword ptr [0x1000]");
+        assert_eq!(disas(&[0x66, 0x89, 0x08]), "mov word ptr [rax], cx");
+        assert_eq!(disas(&[0x40, 0x88, 0xf0]), "mov al, sil");
+        assert_eq!(disas(&[0x88, 0xf0]), "mov al, dh");
+        assert_eq!(disas(&[0x31, 0xc0]), "xor eax, eax");
+        assert_eq!(disas(&[0x48, 0xb8, 0xef, 0xbe, 0xad, 0xde, 0, 0, 0, 0]), "mov rax, 0xdeadbeef");
+        assert_eq!(disas(&[0xc6, 0x00, 0x2a]), "mov byte ptr [rax], 0x2a");
+        assert_eq!(disas(&[0x48, 0xc7, 0x00, 0xff, 0xff, 0xff, 0xff]), "mov qword ptr [rax], 0xffffffffffffffff");
+        assert_eq!(disas(&[0x0f, 0xb6, 0x07]), "movzx eax, byte ptr [rdi]");
+        assert_eq!(disas(&[0x48, 0x63, 0xc7]), "movsxd rax, edi");
+        assert_eq!(disas(&[0x48, 0xd3, 0xe0]), "shl rax, cl");
+        assert_eq!(disas(&[0xf7, 0xf1]), "div ecx");
+        assert_eq!(disas(&[0x69, 0xc0, 0x10, 0x00, 0x00, 0x00]), "imul eax, eax, 0x10");
+        assert_eq!(disas(&[0x0f, 0x44, 0xc1]), "cmove eax, ecx");
+        assert_eq!(disas(&[0x0f, 0x94, 0xc0]), "sete al");
+        assert_eq!(disas(&[0xf0, 0x48, 0x0f, 0xb1, 0x0a]), "lock cmpxchg qword ptr [rdx], rcx");
+        assert_eq!(disas(&[0xf3, 0x48, 0xab]), "rep stosq");
+        assert_eq!(disas(&[0xf3, 0xa4]), "rep movsb");
+        assert_eq!(disas(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0, 0, 0]), "mov rax, qword ptr fs:[0x28]");
+        assert_eq!(disas(&[0x65, 0x48, 0x8b, 0x00]), "mov rax, qword ptr gs:[rax]");
+        assert_eq!(disas(&[0x67, 0x8b, 0x00]), "mov eax, dword ptr [eax]");
+        assert_eq!(disas(&[0x0f, 0x1f, 0x44, 0x00, 0x00]), "nop dword ptr [rax+rax]");
+        assert_eq!(disas(&[0x41, 0x5f]), "pop r15");
+        assert_eq!(disas(&[0x49, 0x87, 0xc0]), "xchg r8, rax");
+        assert_eq!(disas(&[0x41, 0x90]), "xchg r8d, eax");
+        assert_eq!(disas(&[0x90]), "nop");
+        assert_eq!(disas(&[0xf3, 0x90]), "pause");
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(disas(&[0xe8, 0xfb, 0x0f, 0x00, 0x00]), "call 0x2000");
+        assert_eq!(disas(&[0xeb, 0xfe]), "jmp 0x1000");
+        assert_eq!(disas(&[0x74, 0x10]), "je 0x1012");
+        assert_eq!(disas(&[0x0f, 0x85, 0x00, 0x01, 0x00, 0x00]), "jne 0x1106");
+        assert_eq!(disas(&[0xff, 0xd0]), "call rax");
+        assert_eq!(disas(&[0xff, 0x24, 0xc5, 0x00, 0x20, 0x00, 0x00]), "jmp qword ptr [rax*8+0x2000]");
+        assert_eq!(disas(&[0xc3]), "ret");
+        let call = decode(&[0xe8, 0xfb, 0x0f, 0x00, 0x00], 0x1000).unwrap();
+        assert_eq!(call.target(), Some(0x2000));
+        let lea = decode(&[0x48, 0x8d, 0x05, 0xf9, 0x0f, 0x00, 0x00], 0x1000).unwrap();
+        assert_eq!((lea.to_string().as_str(), lea.target()), ("lea rax, [rip+0xff9]", Some(0x2000)));
+    }
+
+    #[test]
+    fn system() {
+        assert_eq!(disas(&[0x0f, 0x0b]), "ud2");
+        assert_eq!(disas(&[0xcc]), "int3");
+        assert_eq!(disas(&[0xcd, 0x80]), "int 0x80");
+        assert_eq!(disas(&[0xf4]), "hlt");
+        assert_eq!(disas(&[0x0f, 0x01, 0xf8]), "swapgs");
+        assert_eq!(disas(&[0x0f, 0x01, 0x38]), "invlpg [rax]");
+        assert_eq!(disas(&[0x0f, 0x20, 0xd8]), "mov rax, cr3");
+        assert_eq!(disas(&[0x0f, 0x23, 0xf8]), "mov dr7, rax");
+        assert_eq!(disas(&[0xe6, 0xf4]), "out 0xf4, al");
+        assert_eq!(disas(&[0x66, 0xed]), "in ax, dx");
+        assert_eq!(disas(&[0x48, 0xcf]), "iretq");
+        assert_eq!(disas(&[0x0f, 0xae, 0xf0]), "mfence");
+    }
+
+    #[test]
+    fn sse() {
+        assert_eq!(disas(&[0x0f, 0x28, 0xc1]), "movaps xmm0, xmm1");
+        assert_eq!(disas(&[0x0f, 0x11, 0x07]), "movups xmmword ptr [rdi], xmm0");
+        assert_eq!(disas(&[0xf2, 0x0f, 0x10, 0x45, 0xf8]), "movsd xmm0, qword ptr [rbp-0x8]");
+        assert_eq!(disas(&[0xf3, 0x0f, 0x58, 0xc1]), "addss xmm0, xmm1");
+        assert_eq!(disas(&[0x66, 0x0f, 0xef, 0xc0]), "pxor xmm0, xmm0");
+        assert_eq!(disas(&[0x0f, 0x57, 0xc0]), "xorps xmm0, xmm0");
+        assert_eq!(disas(&[0x66, 0x44, 0x0f, 0x6f, 0x07]), "movdqa xmm8, xmmword ptr [rdi]");
+        assert_eq!(disas(&[0xf3, 0x0f, 0x7f, 0x06]), "movdqu xmmword ptr [rsi], xmm0");
+        assert_eq!(disas(&[0x66, 0x48, 0x0f, 0x6e, 0xc0]), "movq xmm0, rax");
+        assert_eq!(disas(&[0xf2, 0x48, 0x0f, 0x2a, 0xc0]), "cvtsi2sd xmm0, rax");
+        assert_eq!(disas(&[0xf2, 0x0f, 0x2c, 0xc0]), "cvttsd2si eax, xmm0");
+        assert_eq!(disas(&[0x66, 0x0f, 0x2e, 0xc1]), "ucomisd xmm0, xmm1");
+        assert_eq!(disas(&[0x0f, 0xc6, 0xc1, 0x1b]), "shufps xmm0, xmm1, 0x1b");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(decode(&[], 0), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x48], 0), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x48, 0x8b, 0x45], 0), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0xe8, 0, 0], 0), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x06], 0), Err(DecodeError::Invalid));
+        assert_eq!(decode(&[0x0f, 0xff], 0), Err(DecodeError::Invalid));
+        assert_eq!(decode(&[0x8d, 0xc0], 0), Err(DecodeError::Invalid));
+        // 15 prefixes leave no room for the opcode
+        assert_eq!(decode(&[0x66; 16], 0), Err(DecodeError::Truncated));
+    }
+
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..20), addr: u64) {
+            if let Ok(instruction) = decode(&bytes, addr) {
+                prop_assert!(instruction.len >= 1 && instruction.len <= bytes.len().min(MAX_LENGTH));
+                let _ = instruction.to_string();
+                let _ = instruction.target();
+            }
+        }
+
+        #[test]
+        fn prefix_of_longer_bytes(bytes in proptest::collection::vec(any::<u8>(), MAX_LENGTH), extra: u8) {
+            // More bytes after an instruction do not change it
+            let mut longer = bytes.clone();
+            longer.push(extra);
+            prop_assert_eq!(decode(&bytes, 0), decode(&longer, 0));
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Loader for ELF64 executables
+//! See man elf(5)
+// https://en.wikipedia.org/wiki/Executable_and_Linkable_Format
+// https://refspecs.linuxbase.org/elf/x86_64-abi-0.99.pdf (3.4 Process Initialization)
+
+// Segments are mapped into the active page tables with frames from the frame allocator.
+// As the first 16MiB are identity mapped using huge pages,
+// applications have to be linked to run above that (for example at 0x40000000).
+
+use core::arch::global_asm;
+use core::ops::Range;
+
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::VirtAddr;
+
+use crate::allocator::FRAME_ALLOCATOR;
+use crate::bootinfo::BootModule;
+use crate::{paging, usermode};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Segments are not allowed to go beyond the lower half
+const USER_END: u64 = 0x0000_8000_0000_0000;
+/// Top of the stack given to applications
+const STACK_TOP: u64 = 0x0000_7fff_0000_0000;
+const STACK_PAGES: u64 = 16;
+/// Maximum amount of segments that can be loaded
+const MAX_SEGMENTS: usize = 16;
+/// Maximum amount of arguments passed to an application
+const MAX_ARGS: usize = 32;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The file is too small to hold the header or a program header
+    Truncated,
+    BadMagic,
+    Not64Bit,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine(u16),
+    BadProgramHeaderSize(u16),
+    /// A segment points beyond the end of the file or has a file size above its memory size
+    BadSegment(usize),
+    /// A segment overlaps kernel memory or is outside of the lower half
+    SegmentNotAllowed(u64),
+    TooManySegments,
+    /// The entry point is not inside an executable segment
+    BadEntry(u64),
+    TooManyArguments,
+    OutOfMemory,
+    /// The address is already mapped by something else
+    AlreadyMapped(u64),
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::Not64Bit => write!(f, "not a 64 bit ELF file"),
+            ElfError::NotLittleEndian => write!(f, "not little endian"),
+            ElfError::BadVersion => write!(f, "unknown ELF version"),
+            ElfError::NotExecutable => write!(f, "not an executable"),
+            ElfError::WrongMachine(machine) => write!(f, "wrong machine type {:#x}", machine),
+            ElfError::BadProgramHeaderSize(size) => write!(f, "bad program header size {:#x}", size),
+            ElfError::BadSegment(i) => write!(f, "program header {} is invalid", i),
+            ElfError::SegmentNotAllowed(addr) => write!(f, "segment at {:#x} is not allowed", addr),
+            ElfError::TooManySegments => write!(f, "too many segments"),
+            ElfError::BadEntry(addr) => write!(f, "entry point {:#x} is not executable", addr),
+            ElfError::TooManyArguments => write!(f, "too many arguments"),
+            ElfError::OutOfMemory => write!(f, "out of memory"),
+            ElfError::AlreadyMapped(addr) => write!(f, "{:#x} is already mapped", addr),
+        }
+    }
+}
+
+/// How to run an application
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// In ring 0, the entry point is called as `extern "C" fn(argc, argv) -> i64`
+    Kernel,
+    /// In ring 3, the entry point is started like a SysV process and has to use the exit syscall
+    User,
+}
+
+/// Read a little endian integer from `data`
+fn read<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    let end = offset.checked_add(N).ok_or(ElfError::Truncated)?;
+    data.get(offset..end).ok_or(ElfError::Truncated).map(|b| b.try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> { read(data, offset).map(u16::from_le_bytes) }
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> { read(data, offset).map(u32::from_le_bytes) }
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> { read(data, offset).map(u64::from_le_bytes) }
+
+/// A PT_LOAD program header
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// A validated ELF executable
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    segments: [Option<Segment>; MAX_SEGMENTS],
+}
+
+impl<'a> Elf<'a> {
+    /// Validate the header and program headers of an executable
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if &data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::Not64Bit);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != EV_CURRENT {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(data, 16)? != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        let machine = read_u16(data, 18)?;
+        if machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        let entry = read_u64(data, 24)?;
+        let phoff = read_u64(data, 32)? as usize;
+        let phentsize = read_u16(data, 54)?;
+        let phnum = read_u16(data, 56)? as usize;
+        if phentsize as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize(phentsize));
+        }
+
+        const NONE: Option<Segment> = None;
+        let mut segments = [NONE; MAX_SEGMENTS];
+        let mut count = 0;
+        for i in 0..phnum {
+            let ph = phoff.checked_add(i * PROGRAM_HEADER_SIZE).ok_or(ElfError::Truncated)?;
+            if read_u32(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                flags: read_u32(data, ph + 4)?,
+                offset: read_u64(data, ph + 8)?,
+                vaddr: read_u64(data, ph + 16)?,
+                filesz: read_u64(data, ph + 32)?,
+                memsz: read_u64(data, ph + 40)?,
+            };
+            if segment.filesz > segment.memsz
+                || segment.offset.checked_add(segment.filesz).map_or(true, |end| end > data.len() as u64) {
+                return Err(ElfError::BadSegment(i));
+            }
+            if segment.vaddr < paging::IDENTITY_MAPPED_END
+                || segment.vaddr.checked_add(segment.memsz).map_or(true, |end| end > USER_END) {
+                return Err(ElfError::SegmentNotAllowed(segment.vaddr));
+            }
+            if count >= MAX_SEGMENTS {
+                return Err(ElfError::TooManySegments);
+            }
+            segments[count] = Some(segment);
+            count += 1;
+        }
+
+        let elf = Self { data, entry, segments };
+        if !elf.segments().any(|s| s.flags & PF_X != 0 && s.vaddr <= entry && entry < s.vaddr + s.memsz) {
+            return Err(ElfError::BadEntry(entry));
+        }
+        Ok(elf)
+    }
+
+    /// The PT_LOAD segments
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().flatten()
+    }
+}
+
+/// An application mapped into memory
+pub struct Image {
+    pub entry: VirtAddr,
+    /// The stack pointer to start with
+    pub stack: VirtAddr,
+    pub argc: u64,
+    pub argv: VirtAddr,
+    pub mode: Mode,
+    /// Page ranges mapped for this image, including the stack
+    mapped: [Range<u64>; MAX_SEGMENTS + 1],
+}
+
+/// Copy `bytes` to `vaddr` through the identity mapping of the backing frames,
+/// so read-only pages can be written as well
+fn copy_to(mapper: &OffsetPageTable, vaddr: u64, bytes: &[u8]) {
+    let mut done = 0;
+    while done < bytes.len() {
+        let addr = vaddr + done as u64;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let frame = mapper.translate_page(page).expect("Page not mapped");
+        let offset = addr - page.start_address().as_u64();
+        let len = core::cmp::min(bytes.len() - done, (0x1000 - offset) as usize);
+        unsafe {
+            let dst = (frame.start_address().as_u64() + offset) as *mut u8;
+            core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), dst, len);
+        }
+        done += len;
+    }
+}
+
+/// Map `range` with zeroed frames. Pages that are already mapped by this image
+/// get the combined permissions, as segments may share a page.
+fn map_range(mapper: &mut OffsetPageTable, range: &Range<u64>, flags: PageTableFlags, own: &[Range<u64>]) -> Result<(), ElfError> {
+    let mut allocator = FRAME_ALLOCATOR.get().expect("Frame allocator not initialized").lock();
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(range.start));
+    let end = Page::<Size4KiB>::containing_address(VirtAddr::new(range.end - 1));
+    for page in Page::range_inclusive(start, end) {
+        let addr = page.start_address().as_u64();
+        if let TranslateResult::Mapped { flags: old, .. } = mapper.translate(page.start_address()) {
+            if !own.iter().any(|r| r.contains(&addr)) {
+                return Err(ElfError::AlreadyMapped(addr));
+            }
+            let mut merged = old | (flags & PageTableFlags::WRITABLE);
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            unsafe { mapper.update_flags(page, merged).unwrap().flush() };
+            continue;
+        }
+        let frame: PhysFrame = allocator.allocate_frame().ok_or(ElfError::OutOfMemory)?;
+        unsafe { core::ptr::write_bytes(frame.start_address().as_u64() as *mut u8, 0, 0x1000) };
+        match unsafe { mapper.map_to(page, frame, flags, &mut *allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::FrameAllocationFailed) => return Err(ElfError::OutOfMemory),
+            Err(_) => return Err(ElfError::AlreadyMapped(addr)),
+        }
+    }
+    Ok(())
+}
+
+/// Round `range` out to whole pages
+fn page_range(range: Range<u64>) -> Range<u64> {
+    (range.start & !0xfff)..((range.end + 0xfff) & !0xfff)
+}
+
+impl Image {
+    /// Map the segments of `elf` and set up a stack holding `args`
+    pub fn load(elf: &Elf, args: &[&str], mode: Mode) -> Result<Self, ElfError> {
+        if args.len() > MAX_ARGS {
+            return Err(ElfError::TooManyArguments);
+        }
+        const EMPTY: Range<u64> = 0..0;
+        let mut image = Image {
+            entry: VirtAddr::new(elf.entry),
+            stack: VirtAddr::new(STACK_TOP),
+            argc: args.len() as u64,
+            argv: VirtAddr::zero(),
+            mode,
+            mapped: [EMPTY; MAX_SEGMENTS + 1],
+        };
+        let user = match mode {
+            Mode::User => PageTableFlags::USER_ACCESSIBLE,
+            Mode::Kernel => PageTableFlags::empty(),
+        };
+
+        let mut mapper = unsafe { paging::mapper() };
+        let result = (|| {
+            for (i, segment) in elf.segments().enumerate() {
+                if segment.memsz == 0 {
+                    continue;
+                }
+                let mut flags = PageTableFlags::PRESENT | user;
+                if segment.flags & PF_W != 0 {
+                    flags |= PageTableFlags::WRITABLE;
+                }
+                if segment.flags & PF_X == 0 {
+                    flags |= PageTableFlags::NO_EXECUTE;
+                }
+                let range = page_range(segment.vaddr..segment.vaddr + segment.memsz);
+                map_range(&mut mapper, &range, flags, &image.mapped[..i])?;
+                image.mapped[i] = range;
+                let file = &elf.data[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+                copy_to(&mapper, segment.vaddr, file);
+            }
+
+            let stack = STACK_TOP - STACK_PAGES * 0x1000..STACK_TOP;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | user;
+            map_range(&mut mapper, &stack, flags, &[])?;
+            image.mapped[MAX_SEGMENTS] = stack;
+            image.push_args(&mapper, args)
+        })();
+
+        if let Err(e) = result {
+            image.unload();
+            return Err(e);
+        }
+        Ok(image)
+    }
+
+    /// Lay out the stack like the SysV ABI describes:
+    /// argc, the argv pointers, a null pointer, an empty environment and an empty auxiliary vector.
+    /// The argument strings are placed above that.
+    fn push_args(&mut self, mapper: &OffsetPageTable, args: &[&str]) -> Result<(), ElfError> {
+        // Checked before copying anything, long enough strings would run past the mapped stack
+        let size = args.iter().fold(0u64, |size, arg| size.saturating_add(arg.len() as u64 + 1));
+        if size > 0x1000 {
+            return Err(ElfError::TooManyArguments);
+        }
+        let mut top = STACK_TOP;
+        let mut pointers = [0u64; MAX_ARGS];
+        for (i, arg) in args.iter().enumerate() {
+            top -= arg.len() as u64 + 1;
+            copy_to(mapper, top, arg.as_bytes());
+            copy_to(mapper, top + arg.len() as u64, &[0]);
+            pointers[i] = top;
+        }
+
+        // argc, argv, null, envp null, AT_NULL
+        let words = 1 + args.len() + 1 + 1 + 2;
+        top = (top - words as u64 * 8) & !0xf;
+        self.stack = VirtAddr::new(top);
+        self.argv = VirtAddr::new(top + 8);
+        copy_to(mapper, top, &(args.len() as u64).to_le_bytes());
+        for (i, pointer) in pointers[..args.len()].iter().enumerate() {
+            copy_to(mapper, top + 8 + i as u64 * 8, &pointer.to_le_bytes());
+        }
+        // The rest of the stack is already zeroed
+        Ok(())
+    }
+
+    /// Run the image, returning its exit code
+    pub fn run(&self) -> i64 {
+        match self.mode {
+            Mode::User => usermode::enter(self.entry, self.stack),
+            Mode::Kernel => unsafe { kernel_enter(self.entry.as_u64(), self.stack.as_u64(), self.argc, self.argv.as_u64()) },
+        }
+    }
+
+    /// Unmap the image. The frames are not reclaimed as the frame allocator can't free.
+    pub fn unload(&self) {
+        let mut mapper = unsafe { paging::mapper() };
+        for range in self.mapped.iter().filter(|r| !r.is_empty()) {
+            let start = Page::<Size4KiB>::containing_address(VirtAddr::new(range.start));
+            let end = Page::<Size4KiB>::containing_address(VirtAddr::new(range.end - 1));
+            for page in Page::range_inclusive(start, end) {
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+            }
+        }
+    }
+}
+
+extern "C" {
+    fn kernel_enter(entry: u64, stack: u64, argc: u64, argv: u64) -> i64;
+}
+
+// Call the entry point on the application stack
+global_asm!("
+.global kernel_enter
+kernel_enter:
+    push rbp
+    mov rbp, rsp
+    mov rax, rdi
+    mov rsp, rsi
+    mov rdi, rdx
+    mov rsi, rcx
+    call rax
+    mov rsp, rbp
+    pop rbp
+    ret
+");
+
+/// Load and run the ELF executable in `module`, the module command line is used as arguments
+pub fn run_module(module: &BootModule, mode: Mode) -> Result<i64, ElfError> {
+    let elf = Elf::parse(module.data())?;
+    let cmdline = module.cmdline;
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for arg in cmdline.split_ascii_whitespace() {
+        if argc >= MAX_ARGS {
+            return Err(ElfError::TooManyArguments);
+        }
+        args[argc] = arg;
+        argc += 1;
+    }
+    let image = Image::load(&elf, &args[..argc], mode)?;
+    let code = image.run();
+    image.unload();
+    Ok(code)
+}
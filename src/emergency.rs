@@ -0,0 +1,88 @@
+//! Output that keeps working when the kernel is in a bad state:
+//! locks held by the code an exception interrupted, and panics while panicking.
+
+// Only one CPU runs, so a lock that is held while interrupts are disabled (as the output paths do)
+// belongs to code that was interrupted by an exception and will not run until the handler returns.
+// Waiting for it deadlocks, so the output paths steal such locks instead.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, MutexGuard};
+use volatile::Volatile;
+
+use crate::serial;
+use crate::vga::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// How often to retry a held lock before stealing it, in case another CPU does hold it
+const LOCK_ATTEMPTS: usize = 1000;
+/// Nested panics so far
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+/// Position of the next character [RawVga] writes
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Lock `mutex`, forcing it open if it stays locked.
+/// Only for output paths with interrupts disabled, where the holder can only be the interrupted code.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    for _ in 0..LOCK_ATTEMPTS {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        core::hint::spin_loop();
+    }
+    // The interrupted holder unlocks it again when it continues, which is harmless for a spin lock
+    unsafe { mutex.force_unlock() };
+    mutex.lock()
+}
+
+/// Called first by the panic handler, returns how many panics were already in progress
+pub fn enter_panic() -> usize {
+    PANICS.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Writes to the VGA text buffer and COM1 without taking any lock
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        RawVga.write_str(s)?;
+        serial::RawWriter.write_str(s)
+    }
+}
+
+/// Writes yellow on black straight to the VGA text buffer, wrapping around to the top when full
+pub struct RawVga;
+
+impl fmt::Write for RawVga {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let vga = 0xb8000 as *mut Volatile<u16>;
+        for byte in s.bytes() {
+            let cursor = CURSOR.load(Ordering::Relaxed) % (BUFFER_WIDTH * BUFFER_HEIGHT);
+            let next = match byte {
+                b'\n' => (cursor / BUFFER_WIDTH + 1) * BUFFER_WIDTH,
+                _ => {
+                    let byte = if byte.is_ascii() && !byte.is_ascii_control() { byte } else { b'?' };
+                    unsafe { (*vga.add(cursor)).write(0x0e00 | byte as u16) };
+                    cursor + 1
+                },
+            };
+            CURSOR.store(next, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steal_lock() {
+        let mutex = Mutex::new(1);
+        // Held by "interrupted code" that never continues
+        core::mem::forget(mutex.lock());
+        *lock(&mutex) += 1;
+        assert_eq!(*lock(&mutex), 2);
+        assert!(!mutex.is_locked());
+    }
+}
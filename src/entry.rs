@@ -1,15 +1,34 @@
-#![no_std]
-#![no_main]
+// Unit tests run on the host, see the Makefile. Tests for the kernel target run in QEMU, see testing.rs
+#![cfg_attr(any(not(test), target_os = "none"), no_std)]
+#![cfg_attr(any(not(test), target_os = "none"), no_main)]
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::testing::runner))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
 #![feature(const_mut_refs)]
 #![feature(ptr_metadata)]
 #![feature(abi_x86_interrupt)]
 #![feature(const_trait_impl)]
 #![feature(ascii_char)]
 
+#[cfg(not(test))]
 mod panic;
 #[macro_use]
 mod vga;
+mod serial;
+mod emergency;
+mod logger;
+mod gdbstub;
+mod watchpoint;
+mod fixup;
+mod disas;
+mod crash;
+mod rsod;
+#[macro_use]
+mod percpu;
+mod bootinfo;
 mod multiboot;
+mod multiboot1;
+mod pvh;
 mod conf;
 mod interrupts;
 mod gdt;
@@ -17,6 +36,14 @@ mod kdebug;
 mod debug;
 mod allocator;
 mod pci;
+mod paging;
+mod syscall;
+mod usermode;
+mod elf;
+mod symbols;
+mod backtrace;
+#[cfg(all(test, target_os = "none"))]
+mod testing;
 
 static WELCOME_STRING :&'static str = "Welcome to Runix!";
 
@@ -25,6 +52,7 @@ use vga::Color;
 use multiboot::BootInformation;
 use spin::Once;
 
+/// The raw multiboot information, for debugging. Everything else uses [bootinfo::get]
 pub static MBI: Once<&'static BootInformation> = Once::new();
 
 #[macro_export]
@@ -50,22 +78,42 @@ macro_rules! hlt_loop {
     };
 }
 
+/// Set in EAX by a multiboot2 bootloader
+const MULTIBOOT2_MAGIC: u32 = 0x36d76289;
+
 #[no_mangle]
 // https://en.wikipedia.org/wiki/VGA_text_mode
-#[allow(improper_ctypes_definitions)]
-pub extern fn runix(mbi_pointer: *const BootInformation) -> ! {
+pub extern "C" fn runix(mbi_addr: usize, magic: u32) -> ! {
+    serial::init();
     gdt::init_gdt();
+    percpu::init();
+    logger::init();
+    paging::init();
+    syscall::init();
     interrupts::init();
     vga::clear();
 
-    let mbi = BootInformation::load(mbi_pointer);
+    let mbi_pointer: *const BootInformation = match magic {
+        MULTIBOOT2_MAGIC => core::ptr::from_raw_parts(mbi_addr as *const (), 0),
+        multiboot1::BOOTLOADER_MAGIC => multiboot1::translate(mbi_addr),
+        pvh::START_INFO_MAGIC => pvh::translate(mbi_addr),
+        _ => panic!("Unknown bootloader magic: {:#x}", magic),
+    };
+
+    let mbi = BootInformation::load(mbi_pointer).unwrap_or_else(|e| panic!("Invalid multiboot information: {}", e));
 
     MBI.call_once(|| mbi);
+    let boot_info = bootinfo::init(mbi.boot_info());
 
-    conf::parse(mbi.boot_command_line().expect("Could not get cmdline").to_str().unwrap());
+    conf::parse(boot_info.cmdline);
+    serial::set_mirror(conf::CONFIG.get().unwrap().serial);
+    logger::set_console_level(conf::CONFIG.get().unwrap().loglevel);
+    if conf::CONFIG.get().unwrap().gdb {
+        gdbstub::init();
+    }
 
     for tag in mbi.tags() {
-        if let multiboot::Tag::Unknown(type_, data) = tag {
+        if let Ok(multiboot::Tag::Unknown(type_, data)) = tag {
             wprintln!(" Unknown multiboot tag: type {} size: {:#x}", type_, data.len());
         }
     }
@@ -75,17 +123,12 @@ pub extern fn runix(mbi_pointer: *const BootInformation) -> ! {
     }
 
     if conf::CONFIG.get().unwrap().print_info {
-        let bootloader_name = mbi.bootloader_name().unwrap();    
-        println!("Booted from: {}", &bootloader_name.to_str().unwrap());
-      
-        let elf = mbi.elf_symbols().next().expect("No ELF symbols");
-        println!(
-            "Kernel at: {:#x?} - {:#x?}",
-            elf.sections().into_iter().skip(1).map(|s| s.addr).min().unwrap(),
-            elf.sections().into_iter().map(|s| s.addr + s.size).max().unwrap()
-        );
-    
-        println!("Multiboot at: {:#7x?} - {:#7x?}", mbi_pointer, mbi_pointer as *const () as usize + mbi.total_size as usize);  
+        println!("Booted from: {}", boot_info.loader_name);
+
+        let kernel = boot_info.kernel_sections.image();
+        println!("Kernel at: {:#x?} - {:#x?}", kernel.start, kernel.end);
+
+        println!("Boot information at: {:#7x?} - {:#7x?}", boot_info.reserved.start, boot_info.reserved.end);
 
         // let (PML4T, flags) = x86_64::registers::control::Cr3::read();
         // println!("PML4T at {:#x?}", PML4T);
@@ -93,6 +136,9 @@ pub extern fn runix(mbi_pointer: *const BootInformation) -> ! {
 
     allocator::init();
 
+    #[cfg(all(test, target_os = "none"))]
+    test_main();
+
     kdebug::kdebug();
 
 }
@@ -0,0 +1,225 @@
+//! Exception fixups: code that may fault on purpose, like probing memory or copying from user space
+//! https://www.kernel.org/doc/html/latest/x86/exception-tables.html
+
+// Each fixup is a region of code and a recovery address, placed in the .fixup section by the assembly
+// that contains the region (see `fixup_entry!`). When a page fault or #GP happens in a region,
+// the handler does not panic but returns to the recovery address, with every register as it was at the fault.
+// The recovery code reports the error from there.
+
+use core::arch::asm;
+use core::fmt;
+use core::ptr::addr_of;
+
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::paging;
+
+extern "C" {
+    static __fixup_start: Fixup;
+    static __fixup_end: Fixup;
+}
+
+/// The assembly registering the local labels `start..end` as a fixup region recovering at `recovery`
+/// ```ignore
+/// asm!("2:", "mov {value}, byte ptr [{addr}]", "3:", crate::fixup_entry!("2b", "3b", "4f"), ...)
+/// ```
+#[macro_export]
+macro_rules! fixup_entry {
+    ($start:literal, $end:literal, $recovery:literal) => {
+        concat!(
+            ".pushsection .fixup, \"a\"\n",
+            ".balign 8\n",
+            ".quad ", $start, ", ", $end, ", ", $recovery, "\n",
+            ".popsection",
+        )
+    };
+}
+
+/// An entry of the .fixup section
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fixup {
+    start: u64,
+    end: u64,
+    recovery: u64,
+}
+
+/// An access faulted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// The first byte that could not be accessed
+    pub addr: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad address {:#x}", self.addr)
+    }
+}
+
+fn table() -> &'static [Fixup] {
+    unsafe {
+        let start = addr_of!(__fixup_start);
+        let len = (addr_of!(__fixup_end) as usize - start as usize) / core::mem::size_of::<Fixup>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// The recovery address of the region containing `rip`
+fn search(table: &[Fixup], rip: u64) -> Option<u64> {
+    table.iter().find(|fixup| (fixup.start..fixup.end).contains(&rip)).map(|fixup| fixup.recovery)
+}
+
+/// Called by the page fault and #GP handlers, returns true if the faulting code recovers by itself
+pub fn fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let Some(recovery) = search(table(), stack_frame.instruction_pointer.as_u64()) else { return false };
+    unsafe { stack_frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(recovery)) };
+    true
+}
+
+/// Read a byte, which may be unmapped or not even canonical
+pub fn probe_read(addr: u64) -> Result<u8, Fault> {
+    let value: u8;
+    let failed: u32;
+    unsafe {
+        asm!(
+            "xor {failed:e}, {failed:e}",
+            "2:",
+            "mov {value}, byte ptr [{addr}]",
+            "3:",
+            crate::fixup_entry!("2b", "3b", "4f"),
+            "jmp 5f",
+            "4:",
+            "mov {failed:e}, 1",
+            "5:",
+            addr = in(reg) addr,
+            value = out(reg_byte) value,
+            failed = out(reg) failed,
+            options(nostack, readonly),
+        );
+    }
+    match failed {
+        0 => Ok(value),
+        _ => Err(Fault { addr }),
+    }
+}
+
+/// Write a byte, which may be unmapped, read-only or not even canonical
+pub fn probe_write(addr: u64, value: u8) -> Result<(), Fault> {
+    let failed: u32;
+    unsafe {
+        asm!(
+            "xor {failed:e}, {failed:e}",
+            "2:",
+            "mov byte ptr [{addr}], {value}",
+            "3:",
+            crate::fixup_entry!("2b", "3b", "4f"),
+            "jmp 5f",
+            "4:",
+            "mov {failed:e}, 1",
+            "5:",
+            addr = in(reg) addr,
+            value = in(reg_byte) value,
+            failed = out(reg) failed,
+            options(nostack),
+        );
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(Fault { addr }),
+    }
+}
+
+/// Copy `dst.len()` bytes from user memory at `src`.
+/// The page tables are checked first, so user code can not pass kernel addresses,
+/// and a page that goes away during the copy ends it with an error instead of a panic.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Fault> {
+    let len = dst.len() as u64;
+    // try_new sign extends addresses with only bit 47 set instead of rejecting them
+    let start = match VirtAddr::try_new(src) {
+        Ok(start) if start.as_u64() == src => start,
+        _ => return Err(Fault { addr: src }),
+    };
+    if !paging::is_user_accessible(start, len, false) {
+        return Err(Fault { addr: src });
+    }
+    let remaining: u64;
+    unsafe {
+        asm!(
+            "2:",
+            "rep movsb",
+            "3:",
+            // rcx is left at the number of bytes not copied
+            crate::fixup_entry!("2b", "3b", "3b"),
+            inout("rcx") len => remaining,
+            inout("rsi") src => _,
+            inout("rdi") dst.as_mut_ptr() => _,
+            options(nostack, preserves_flags),
+        );
+    }
+    match remaining {
+        0 => Ok(()),
+        _ => Err(Fault { addr: src + len - remaining }),
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_regions() {
+        let table = [
+            Fixup { start: 0x1000, end: 0x1004, recovery: 0x1010 },
+            Fixup { start: 0x2000, end: 0x2002, recovery: 0x2002 },
+        ];
+        assert_eq!(search(&table, 0x1000), Some(0x1010));
+        assert_eq!(search(&table, 0x1003), Some(0x1010));
+        assert_eq!(search(&table, 0x1004), None);
+        assert_eq!(search(&table, 0x2001), Some(0x2002));
+        assert_eq!(search(&table, 0xfff), None);
+        assert_eq!(search(&[], 0x1000), None);
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use super::*;
+
+    #[test_case]
+    fn probe_mapped() {
+        static mut BYTE: u8 = 0x5a;
+        let addr = unsafe { core::ptr::addr_of_mut!(BYTE) } as u64;
+        assert_eq!(probe_read(addr), Ok(0x5a));
+        assert_eq!(probe_write(addr, 0xa5), Ok(()));
+        assert_eq!(probe_read(addr), Ok(0xa5));
+    }
+
+    #[test_case]
+    fn probe_unmapped() {
+        let addr = paging::IDENTITY_MAPPED_END;
+        assert_eq!(probe_read(addr), Err(Fault { addr }));
+        assert_eq!(probe_write(addr, 0), Err(Fault { addr }));
+    }
+
+    #[test_case]
+    fn probe_non_canonical() {
+        // #GP instead of a page fault
+        assert_eq!(probe_read(0x8000_0000_0000_0000), Err(Fault { addr: 0x8000_0000_0000_0000 }));
+    }
+
+    #[test_case]
+    fn copy_from_kernel() {
+        let mut buffer = [0; 4];
+        let src = buffer.as_ptr() as u64;
+        assert_eq!(copy_from_user(&mut buffer, src), Err(Fault { addr: src }));
+    }
+
+    #[test_case]
+    fn copy_from_non_canonical() {
+        let mut buffer = [0; 4];
+        assert_eq!(copy_from_user(&mut buffer, 0x8000_0000_0000_0000), Err(Fault { addr: 0x8000_0000_0000_0000 }));
+        assert_eq!(copy_from_user(&mut buffer, 0x0000_8000_0000_0000), Err(Fault { addr: 0x0000_8000_0000_0000 }));
+    }
+}
@@ -0,0 +1,626 @@
+//! A GDB remote serial protocol stub on COM2, enabled with the `gdb` boot option
+//! https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+// The stub takes over on int3 (hbreak!), debug exceptions, exceptions in the kernel and panics.
+// It only talks while the kernel is stopped: it sends a stop reply and handles packets until GDB continues.
+// Ctrl-C in GDB sends 0x03, which the COM2 interrupt turns into a breakpoint.
+// Exceptions other than #DB and #BP do not save every register, the missing ones are reported as unavailable
+// and nothing can be changed, as the kernel is not going to continue anyway.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::trap::TrapFrame;
+use crate::serial::{self, SerialPort};
+use crate::fixup;
+
+/// COM2, COM1 mirrors the console
+const PORT: usize = 1;
+/// Largest packet, in both directions
+const PACKET_SIZE: usize = 0x1000;
+/// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 to r15, rip, eflags, cs, ss, ds, es, fs and gs
+const REGISTERS: usize = 24;
+const RIP: usize = 16;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+
+// Signals reported to GDB
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set while the stub runs, so a fault in the stub does not enter it again
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static BREAKPOINTS: Mutex<Breakpoints> = Mutex::new(Breakpoints([None; MAX_BREAKPOINTS]));
+
+/// Let the stub take over, COM2 has to be initialized
+pub fn init() {
+    if serial::PORTS[PORT].lock().is_none() {
+        wprintln!("No serial port for GDB");
+        return;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// How the stopped code continues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Execute one instruction, then trap again
+    Step,
+}
+
+/// Stop at a breakpoint or after a single step
+pub fn trap(frame: &mut TrapFrame) {
+    let mut registers = Registers::from_trap(frame);
+    let Some(resume) = enter(&mut registers, SIGTRAP, true) else { return };
+    registers.apply(frame);
+    match resume {
+        Resume::Step => frame.rflags |= TRAP_FLAG,
+        Resume::Continue => frame.rflags &= !TRAP_FLAG,
+    }
+}
+
+/// Stop at an exception in the kernel, the registers are read-only
+pub fn exception(stack_frame: &InterruptStackFrame, vector: u8) {
+    let rbp = Backtrace::interrupted(stack_frame).rbp();
+    let mut registers = Registers::from_exception(stack_frame, rbp);
+    let signal = match vector {
+        0 => SIGFPE,
+        6 => SIGILL,
+        17 => SIGBUS,
+        _ => SIGSEGV,
+    };
+    enter(&mut registers, signal, false);
+}
+
+/// Stop in the panic handler, for post-mortem debugging
+pub fn panic() {
+    let mut registers = Registers::here();
+    enter(&mut registers, SIGABRT, false);
+}
+
+/// Check COM2 for a Ctrl-C from GDB, called by its interrupt handler
+pub fn interrupt_requested() -> bool {
+    let mut requested = false;
+    if let Some(port) = serial::PORTS[PORT].lock().as_mut() {
+        while let Some(byte) = port.receive() {
+            requested |= byte == 0x03;
+        }
+    }
+    requested
+}
+
+fn enter(registers: &mut Registers, signal: u8, writable: bool) -> Option<Resume> {
+    if !is_enabled() || ACTIVE.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    let resume = session(registers, signal, writable);
+    ACTIVE.store(false, Ordering::Release);
+    Some(resume)
+}
+
+/// Talk to GDB until it continues
+fn session(registers: &mut Registers, signal: u8, writable: bool) -> Resume {
+    let mut port = serial::PORTS[PORT].lock();
+    let Some(port) = port.as_mut() else { return Resume::Continue };
+    let mut breakpoints = BREAKPOINTS.lock();
+    let mut stub = Stub { registers, writable, signal, memory: KernelMemory, breakpoints: &mut breakpoints };
+
+    let mut packet = [0; PACKET_SIZE];
+    let mut reply = Packet::new();
+    stub.stop_reply(&mut reply);
+    send(port, &reply);
+    loop {
+        let Some(len) = receive(port, &mut packet) else {
+            // GDB did not get the last reply
+            send(port, &reply);
+            continue;
+        };
+        reply.clear();
+        let resume = stub.handle(&packet[..len], &mut reply);
+        if resume.is_none() || reply.len > 0 {
+            send(port, &reply);
+        }
+        if let Some(resume) = resume {
+            return resume;
+        }
+    }
+}
+
+fn read_byte(port: &mut SerialPort) -> u8 {
+    loop {
+        if let Some(byte) = port.receive() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for a packet and acknowledge it, returns its length.
+/// None if GDB asks for the last reply again.
+fn receive(port: &mut SerialPort, buffer: &mut [u8; PACKET_SIZE]) -> Option<usize> {
+    loop {
+        match read_byte(port) {
+            b'$' => {},
+            b'-' => return None,
+            // Acknowledgements and Ctrl-C
+            _ => continue,
+        }
+        let mut len = 0;
+        let mut checksum = 0u8;
+        loop {
+            let byte = read_byte(port);
+            if byte == b'#' {
+                break;
+            }
+            if len < PACKET_SIZE {
+                buffer[len] = byte;
+                len += 1;
+            }
+            checksum = checksum.wrapping_add(byte);
+        }
+        let expected = [read_byte(port), read_byte(port)];
+        if parse_hex(&expected) == Some(checksum as u64) && len < PACKET_SIZE {
+            port.send(b'+');
+            return Some(len);
+        }
+        port.send(b'-');
+    }
+}
+
+fn send(port: &mut SerialPort, packet: &Packet) {
+    let data = packet.as_bytes();
+    let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    port.send(b'$');
+    for &byte in data {
+        port.send(byte);
+    }
+    port.send(b'#');
+    port.send(HEX[(checksum >> 4) as usize]);
+    port.send(HEX[(checksum & 0xf) as usize]);
+    port.flush();
+}
+
+/// Registers in the order of the `g` packet, None if they were not saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registers(pub [Option<u64>; REGISTERS]);
+
+impl Registers {
+    /// Registers after rip are 32 bits in the `g` packet
+    fn size(n: usize) -> usize {
+        if n <= RIP { 8 } else { 4 }
+    }
+
+    fn from_trap(f: &TrapFrame) -> Self {
+        let mut registers = [None; REGISTERS];
+        let values = [
+            f.rax, f.rbx, f.rcx, f.rdx, f.rsi, f.rdi, f.rbp, f.rsp,
+            f.r8, f.r9, f.r10, f.r11, f.r12, f.r13, f.r14, f.r15,
+            f.rip, f.rflags, f.cs, f.ss,
+        ];
+        for (register, value) in registers.iter_mut().zip(values) {
+            *register = Some(value);
+        }
+        Self(registers)
+    }
+
+    /// Write back the registers that are known
+    fn apply(&self, f: &mut TrapFrame) {
+        let fields = [
+            &mut f.rax, &mut f.rbx, &mut f.rcx, &mut f.rdx, &mut f.rsi, &mut f.rdi, &mut f.rbp, &mut f.rsp,
+            &mut f.r8, &mut f.r9, &mut f.r10, &mut f.r11, &mut f.r12, &mut f.r13, &mut f.r14, &mut f.r15,
+            &mut f.rip, &mut f.rflags, &mut f.cs, &mut f.ss,
+        ];
+        for (field, value) in fields.into_iter().zip(self.0) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+    }
+
+    fn from_exception(stack_frame: &InterruptStackFrame, rbp: u64) -> Self {
+        let mut registers = [None; REGISTERS];
+        registers[6] = Some(rbp);
+        registers[7] = Some(stack_frame.stack_pointer.as_u64());
+        registers[RIP] = Some(stack_frame.instruction_pointer.as_u64());
+        registers[17] = Some(stack_frame.cpu_flags);
+        registers[18] = Some(stack_frame.code_segment);
+        registers[19] = Some(stack_frame.stack_segment);
+        Self(registers)
+    }
+
+    /// The registers of the caller
+    #[inline(always)]
+    fn here() -> Self {
+        let (rip, rsp, rbp): (u64, u64, u64);
+        unsafe {
+            asm!("lea {}, [rip]", "mov {}, rsp", "mov {}, rbp", out(reg) rip, out(reg) rsp, out(reg) rbp,
+                options(nomem, nostack, preserves_flags));
+        }
+        let mut registers = [None; REGISTERS];
+        registers[6] = Some(rbp);
+        registers[7] = Some(rsp);
+        registers[RIP] = Some(rip);
+        Self(registers)
+    }
+}
+
+/// Access to the memory of the stopped code
+pub trait Memory {
+    fn read(&mut self, addr: u64) -> Option<u8>;
+    /// Returns false if `addr` is not writable
+    fn write(&mut self, addr: u64, value: u8) -> bool;
+}
+
+/// Memory through the active page tables, unmapped addresses fail instead of faulting
+pub struct KernelMemory;
+
+impl Memory for KernelMemory {
+    fn read(&mut self, addr: u64) -> Option<u8> {
+        fixup::probe_read(addr).ok()
+    }
+
+    fn write(&mut self, addr: u64, value: u8) -> bool {
+        fixup::probe_write(addr, value).is_ok()
+    }
+}
+
+/// Software breakpoints, the original byte is saved when it is replaced with int3
+struct Breakpoints([Option<(u64, u8)>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    fn insert(&mut self, addr: u64, memory: &mut impl Memory) -> bool {
+        if self.0.iter().flatten().any(|&(a, _)| a == addr) {
+            return true;
+        }
+        let Some(slot) = self.0.iter_mut().find(|b| b.is_none()) else { return false };
+        let Some(original) = memory.read(addr) else { return false };
+        if !memory.write(addr, INT3) {
+            return false;
+        }
+        *slot = Some((addr, original));
+        true
+    }
+
+    fn remove(&mut self, addr: u64, memory: &mut impl Memory) -> bool {
+        match self.0.iter_mut().find(|b| matches!(b, Some((a, _)) if *a == addr)) {
+            Some(slot) => {
+                let (addr, original) = slot.take().unwrap();
+                memory.write(addr, original)
+            },
+            None => false,
+        }
+    }
+
+    fn clear(&mut self, memory: &mut impl Memory) {
+        for slot in &mut self.0 {
+            if let Some((addr, original)) = slot.take() {
+                memory.write(addr, original);
+            }
+        }
+    }
+}
+
+/// A reply being built
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Self { data: [0; PACKET_SIZE], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Anything that does not fit is dropped, replies are kept small enough
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(&[HEX[(byte >> 4) as usize], HEX[(byte & 0xf) as usize]]);
+    }
+
+    fn push_register(&mut self, value: Option<u64>, size: usize) {
+        match value {
+            Some(value) => value.to_le_bytes()[..size].iter().for_each(|&b| self.push_hex(b)),
+            None => (0..size).for_each(|_| self.push(b"xx")),
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    u64::from_str_radix(core::str::from_utf8(s).ok()?, 16).ok()
+}
+
+/// Split `addr,len` with an optional `:data` after it
+fn parse_range(s: &[u8]) -> Option<(u64, u64, Option<&[u8]>)> {
+    let (range, data) = match s.iter().position(|&b| b == b':') {
+        Some(colon) => (&s[..colon], Some(&s[colon + 1..])),
+        None => (s, None),
+    };
+    let comma = range.iter().position(|&b| b == b',')?;
+    Some((parse_hex(&range[..comma])?, parse_hex(&range[comma + 1..])?, data))
+}
+
+/// Decode pairs of hex digits, None for `xx`
+fn hex_bytes(s: &[u8]) -> impl Iterator<Item = Option<u8>> + '_ {
+    s.chunks(2).map(|pair| parse_hex(pair).map(|b| b as u8))
+}
+
+/// The protocol, independent of the serial port
+struct Stub<'a, M: Memory> {
+    registers: &'a mut Registers,
+    /// If the registers can be changed
+    writable: bool,
+    /// Why the code stopped
+    signal: u8,
+    memory: M,
+    breakpoints: &'a mut Breakpoints,
+}
+
+impl<M: Memory> Stub<'_, M> {
+    fn stop_reply(&self, reply: &mut Packet) {
+        reply.push(b"S");
+        reply.push_hex(self.signal);
+    }
+
+    /// Handle a packet and put the reply in `reply`, empty if the packet is not supported.
+    /// Returns how to continue for packets that resume the code.
+    fn handle(&mut self, packet: &[u8], reply: &mut Packet) -> Option<Resume> {
+        match packet {
+            b"?" => self.stop_reply(reply),
+            b"g" => {
+                for (n, &value) in self.registers.0.iter().enumerate() {
+                    reply.push_register(value, Registers::size(n));
+                }
+            },
+            [b'G', data @ ..] => {
+                if !self.writable {
+                    reply.push(b"E01");
+                    return None;
+                }
+                let mut data = data;
+                for n in 0..REGISTERS {
+                    let size = Registers::size(n) * 2;
+                    if data.len() < size {
+                        break;
+                    }
+                    if let Some(value) = parse_register(&data[..size]) {
+                        self.registers.0[n] = Some(value);
+                    }
+                    data = &data[size..];
+                }
+                reply.push(b"OK");
+            },
+            [b'p', n @ ..] => match parse_hex(n) {
+                Some(n) if (n as usize) < REGISTERS => reply.push_register(self.registers.0[n as usize], Registers::size(n as usize)),
+                // Not supported, GDB reads everything with `g` then
+                _ => {},
+            },
+            [b'P', rest @ ..] => {
+                let assignment = rest.iter().position(|&b| b == b'=')
+                    .and_then(|eq| Some((parse_hex(&rest[..eq])? as usize, parse_register(&rest[eq + 1..])?)));
+                match assignment {
+                    Some((n, value)) if n < REGISTERS && self.writable => {
+                        self.registers.0[n] = Some(value);
+                        reply.push(b"OK");
+                    },
+                    _ => reply.push(b"E01"),
+                }
+            },
+            [b'm', rest @ ..] => match parse_range(rest) {
+                Some((addr, len, None)) => {
+                    // Two characters per byte
+                    let len = len.min(PACKET_SIZE as u64 / 2);
+                    for addr in addr..addr.saturating_add(len) {
+                        match self.memory.read(addr) {
+                            Some(byte) => reply.push_hex(byte),
+                            None if reply.len == 0 => {
+                                reply.push(b"E14");
+                                break;
+                            },
+                            // Return what could be read
+                            None => break,
+                        }
+                    }
+                },
+                _ => reply.push(b"E01"),
+            },
+            [b'M', rest @ ..] => match parse_range(rest) {
+                Some((addr, len, Some(data))) if data.len() as u64 == len * 2 => {
+                    let written = hex_bytes(data).zip(addr..)
+                        .all(|(byte, addr)| byte.is_some_and(|byte| self.memory.write(addr, byte)));
+                    reply.push(if written { b"OK" } else { b"E14" });
+                },
+                _ => reply.push(b"E01"),
+            },
+            // Software breakpoints, the kind (length) is always 1 on x86
+            [b'Z', b'0', b',', rest @ ..] | [b'z', b'0', b',', rest @ ..] => match parse_range(rest) {
+                Some((addr, _, None)) => {
+                    let done = match packet[0] {
+                        b'Z' => self.breakpoints.insert(addr, &mut self.memory),
+                        _ => self.breakpoints.remove(addr, &mut self.memory),
+                    };
+                    reply.push(if done { b"OK" } else { b"E14" });
+                },
+                _ => reply.push(b"E01"),
+            },
+            [b'c', addr @ ..] | [b's', addr @ ..] => {
+                if let Some(addr) = parse_hex(addr) {
+                    if self.writable {
+                        self.registers.0[RIP] = Some(addr);
+                    }
+                }
+                return Some(match packet[0] {
+                    b'c' => Resume::Continue,
+                    _ => Resume::Step,
+                });
+            },
+            // Detach and kill, the kernel keeps running without breakpoints
+            [b'D', ..] | b"k" => {
+                self.breakpoints.clear(&mut self.memory);
+                if packet[0] == b'D' {
+                    reply.push(b"OK");
+                }
+                return Some(Resume::Continue);
+            },
+            // There is only one thread
+            [b'H', ..] | [b'T', ..] => reply.push(b"OK"),
+            b"qAttached" => reply.push(b"1"),
+            _ if packet.starts_with(b"qSupported") => reply.push(b"PacketSize=1000"),
+            _ => {},
+        }
+        None
+    }
+}
+
+/// A little endian register value, None if it is unavailable (`xx`)
+fn parse_register(s: &[u8]) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (i, byte) in hex_bytes(s).enumerate() {
+        *bytes.get_mut(i)? = byte?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// 16 bytes at 0x1000
+    struct TestMemory([u8; 16]);
+
+    impl Memory for TestMemory {
+        fn read(&mut self, addr: u64) -> Option<u8> {
+            self.0.get(addr.checked_sub(0x1000)? as usize).copied()
+        }
+
+        fn write(&mut self, addr: u64, value: u8) -> bool {
+            match addr.checked_sub(0x1000).and_then(|i| self.0.get_mut(i as usize)) {
+                Some(byte) => {
+                    *byte = value;
+                    true
+                },
+                None => false,
+            }
+        }
+    }
+
+    fn handle(stub: &mut Stub<TestMemory>, packet: &[u8]) -> (String, Option<Resume>) {
+        let mut reply = Packet::new();
+        let resume = stub.handle(packet, &mut reply);
+        (String::from_utf8(reply.as_bytes().to_vec()).unwrap(), resume)
+    }
+
+    fn reply(stub: &mut Stub<TestMemory>, packet: &[u8]) -> String {
+        handle(stub, packet).0
+    }
+
+    #[test]
+    fn registers() {
+        let mut registers = Registers([None; REGISTERS]);
+        registers.0[0] = Some(0x1122334455667788);
+        registers.0[17] = Some(0x202);
+        let mut breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+        let mut stub = Stub { registers: &mut registers, writable: true, signal: SIGTRAP, memory: TestMemory([0; 16]), breakpoints: &mut breakpoints };
+
+        let g = reply(&mut stub, b"g");
+        assert_eq!(g.len(), 17 * 16 + 7 * 8);
+        assert!(g.starts_with("8877665544332211xxxxxxxxxxxxxxxx"));
+        assert_eq!(&g[17 * 16..17 * 16 + 8], "02020000");
+        assert_eq!(reply(&mut stub, b"p10"), "x".repeat(16));
+        assert_eq!(reply(&mut stub, b"p40"), "");
+
+        assert_eq!(reply(&mut stub, b"P10=efbeadde00000000"), "OK");
+        assert_eq!(stub.registers.0[RIP], Some(0xdeadbeef));
+        assert_eq!(reply(&mut stub, b"P40=00"), "E01");
+        // Unavailable values are left alone
+        let g = format!("{}{}", "01".repeat(8), "xx".repeat(8));
+        assert_eq!(reply(&mut stub, format!("G{}", g).as_bytes()), "OK");
+        assert_eq!(stub.registers.0[0], Some(0x0101010101010101));
+        assert_eq!(stub.registers.0[1], None);
+        assert_eq!(stub.registers.0[RIP], Some(0xdeadbeef));
+
+        stub.writable = false;
+        assert_eq!(reply(&mut stub, b"P0=00"), "E01");
+        assert_eq!(reply(&mut stub, b"G00"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut registers = Registers([None; REGISTERS]);
+        let mut breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+        let mut stub = Stub { registers: &mut registers, writable: true, signal: SIGTRAP, memory: TestMemory([0; 16]), breakpoints: &mut breakpoints };
+
+        assert_eq!(reply(&mut stub, b"M1002,3:abcdef"), "OK");
+        assert_eq!(reply(&mut stub, b"m1001,4"), "00abcdef");
+        // Partial reads stop at the end
+        assert_eq!(reply(&mut stub, b"m100e,4"), "0000");
+        assert_eq!(reply(&mut stub, b"m2000,4"), "E14");
+        assert_eq!(reply(&mut stub, b"M100f,2:0102"), "E14");
+        assert_eq!(reply(&mut stub, b"M1000,2:01"), "E01");
+        assert_eq!(reply(&mut stub, b"m1000"), "E01");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut registers = Registers([None; REGISTERS]);
+        let mut breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+        let mut stub = Stub { registers: &mut registers, writable: true, signal: SIGTRAP, memory: TestMemory([0x90; 16]), breakpoints: &mut breakpoints };
+
+        assert_eq!(reply(&mut stub, b"Z0,1004,1"), "OK");
+        assert_eq!(reply(&mut stub, b"Z0,1008,1"), "OK");
+        assert_eq!(stub.memory.0[4], INT3);
+        assert_eq!(reply(&mut stub, b"z0,1004,1"), "OK");
+        assert_eq!(stub.memory.0[4], 0x90);
+        assert_eq!(reply(&mut stub, b"z0,1004,1"), "E14");
+        assert_eq!(reply(&mut stub, b"Z0,2000,1"), "E14");
+        // Detaching removes the remaining ones
+        assert_eq!(handle(&mut stub, b"D"), ("OK".to_string(), Some(Resume::Continue)));
+        assert_eq!(stub.memory.0, [0x90; 16]);
+    }
+
+    #[test]
+    fn resume() {
+        let mut registers = Registers([None; REGISTERS]);
+        let mut breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+        let mut stub = Stub { registers: &mut registers, writable: true, signal: SIGSEGV, memory: TestMemory([0; 16]), breakpoints: &mut breakpoints };
+
+        assert_eq!(reply(&mut stub, b"?"), "S0b");
+        assert_eq!(handle(&mut stub, b"c"), (String::new(), Some(Resume::Continue)));
+        assert_eq!(handle(&mut stub, b"s1234"), (String::new(), Some(Resume::Step)));
+        assert_eq!(stub.registers.0[RIP], Some(0x1234));
+        assert_eq!(reply(&mut stub, b"qSupported:multiprocess+;swbreak+"), "PacketSize=1000");
+        assert_eq!(handle(&mut stub, b"vMustReplyEmpty"), (String::new(), None));
+    }
+}
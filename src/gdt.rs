@@ -3,38 +3,94 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use core::ops::Range;
+use core::ptr::{addr_of, addr_of_mut};
 use spin::Once;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-static TSS: Once<TaskStateSegment> = Once::new();
+
+// The TSS has to be mutable so rsp0 can be switched
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Stack used when an interrupt or syscall arrives from ring 3
+const KERNEL_STACK_SIZE: usize = 4096 * 16;
+static mut KERNEL_STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 *8;
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
 fn init_tss() {
-    let mut tss = TaskStateSegment::new();
-    const DOUBLE_FAULT_STACK_SIZE: usize = 4096 *8;
-    static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];    
-    let stack_start = VirtAddr::from_ptr(unsafe {&DOUBLE_FAULT_STACK});
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =  stack_start + DOUBLE_FAULT_STACK_SIZE;
-    TSS.call_once(|| tss);
+    let stack_start = VirtAddr::from_ptr(unsafe {addr_of!(DOUBLE_FAULT_STACK)});
+    unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =  stack_start + DOUBLE_FAULT_STACK_SIZE;
+    }
+    set_kernel_stack(VirtAddr::from_ptr(unsafe {addr_of!(KERNEL_STACK)}) + KERNEL_STACK_SIZE);
 }
 
-static GDT: Once<GlobalDescriptorTable> = Once::new();
+/// Set the stack the CPU switches to when entering ring 0 from ring 3.
+/// This is both rsp0 in the TSS (for interrupts) and the per-CPU `syscall` stack.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_top };
+    if crate::percpu::is_initialized() {
+        crate::percpu::HEADER.with(|h| h.kernel_stack.set(stack_top.as_u64() as usize));
+    }
+}
+
+/// The stack the CPU switches to when entering ring 0 from ring 3
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*addr_of!(TSS)).privilege_stack_table[0] }
+}
+
+/// The kernel stack and the double fault stack
+pub fn stacks() -> [Range<u64>; 2] {
+    let kernel_stack = unsafe {addr_of!(KERNEL_STACK)} as u64;
+    let double_fault_stack = unsafe {addr_of!(DOUBLE_FAULT_STACK)} as u64;
+    [
+        kernel_stack..kernel_stack + KERNEL_STACK_SIZE as u64,
+        double_fault_stack..double_fault_stack + DOUBLE_FAULT_STACK_SIZE as u64,
+    ]
+}
+
+/// The selectors of all segments in the GDT
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
 
 /// Create and load the GDT.
 /// This also causes TSS to be initialized.
 pub fn init_gdt() {
-    if !TSS.is_completed() { init_tss() }
+    init_tss();
     let mut gdt = GlobalDescriptorTable::new();
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.get().expect("TSS not yet initialized")));
-    GDT.call_once(|| gdt);
-    GDT.get().unwrap().load();
+    // The order of these is dictated by the STAR MSR used for syscall/sysret:
+    // kernel data has to follow kernel code, and user code has to follow user data
+    let selectors = Selectors {
+        kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+        kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+        user_data: gdt.add_entry(Descriptor::user_data_segment()),
+        user_code: gdt.add_entry(Descriptor::user_code_segment()),
+        tss: gdt.add_entry(Descriptor::tss_segment(unsafe {&*addr_of!(TSS)})),
+    };
+    GDT.call_once(|| (gdt, selectors));
+    GDT.get().unwrap().0.load();
 
     // Enable our new GDT
     unsafe {
-        use x86_64::instructions::segmentation::CS;
+        use x86_64::instructions::segmentation::{CS, SS};
         use x86_64::instructions::tables::load_tss;
-        CS::set_reg(code_selector);
-        load_tss(tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 }
 
+/// Get the segment selectors, the GDT has to be loaded
+pub fn selectors() -> Selectors {
+    GDT.get().expect("GDT not yet initialized").1
+}
@@ -8,9 +8,15 @@
 use x86_64::structures::idt::{InterruptStackFrame, InterruptDescriptorTable, ExceptionVector, ExceptionVector::*, PageFaultErrorCode};
 use x86_64::set_general_handler;
 use spin::Once;
-use crate::gdt;
+use core::cell::Cell;
+use core::fmt;
+use core::time::Duration;
+use crate::{crash, disas, fixup, gdbstub, gdt, percpu, serial, usermode};
+use crate::backtrace::Backtrace;
+use crate::symbols::Symbolized;
 pub mod pic8259;
 pub mod keyboard;
+pub mod trap;
 
 /// Statically allocated IDT
 // Make sure you have enough stack size for this
@@ -22,8 +28,9 @@ pub fn init_idt() {
     let mut idt = InterruptDescriptorTable::new();
     set_general_handler!(&mut idt, generic_interrupt_handler);
     set_general_handler!(&mut idt, generic_exception_handler, 0..0x20);
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    trap::set_handlers(&mut idt);
     idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_handler);
     let double_fault_entry = idt.double_fault.set_handler_fn(double_fault_handler);
     unsafe {
         // register the double fault handler with a clean stack
@@ -31,6 +38,8 @@ pub fn init_idt() {
     }
     idt[Timer as usize].set_handler_fn(timer);
     idt[Keyboard as usize].set_handler_fn(keyboard::handler);
+    idt[Com2 as usize].set_handler_fn(com2);
+    idt[Com1 as usize].set_handler_fn(com1);
     IDT.call_once(|| idt);
     IDT.get().unwrap().load();
 }
@@ -39,72 +48,164 @@ pub fn init_idt() {
 pub fn init() {
     init_idt();
     pic8259::init_pic();
+    serial::enable_interrupts();
+    pic8259::unmask(Com2 as u8 - pic8259::PIC1_OFFSET);
+    pic8259::unmask(Com1 as u8 - pic8259::PIC1_OFFSET);
     x86_64::instructions::interrupts::enable();
 }
 
 #[repr(u8)]
 enum InterruptIndex {
     Timer = pic8259::PIC1_OFFSET,
-    Keyboard
+    Keyboard,
+    /// Also COM4
+    Com2 = pic8259::PIC1_OFFSET + 3,
+    /// Also COM3
+    Com1,
 }
 
 // There is an enum with exception numbers:
 // https://docs.rs/x86_64/latest/src/x86_64/structures/idt.rs.html#1137-1206
 
 fn generic_exception_handler(stack_frame: InterruptStackFrame, index: u8, err_code : Option<u64>) {
-    wprintln!("Unimplemented exception {:#x} (ex: {:?}) (err: {:x?})\n{:?}", index, exception_get_name(index).unwrap(), err_code, stack_frame);
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    let vector = exception_get_name(index);
+    let name: &dyn fmt::Debug = match &vector {
+        Some(vector) => vector,
+        None => &"Reserved",
+    };
+    if from_user(&stack_frame) {
+        exprintln!("User code caused exception {:#x} (ex: {:?}) (err: {:x?})\n{:?}", index, name, err_code, stack_frame);
+        usermode::exit(-1);
+    }
+    wprintln!("Unimplemented exception {:#x} (ex: {:?}) (err: {:x?}) in {}\n{:?}", index, name, err_code, rip(&stack_frame), stack_frame);
+    // Faults point at the faulting instruction, traps at the one after it
+    disas::print(stack_frame.instruction_pointer.as_u64(), 1);
+    print!("Backtrace:\n{}", Backtrace::interrupted(&stack_frame));
+    gdbstub::exception(&stack_frame, index);
 }
 
-fn generic_interrupt_handler(_stack_frame: InterruptStackFrame, index: u8, _err_code : Option<u64>) {
+fn generic_interrupt_handler(stack_frame: InterruptStackFrame, index: u8, _err_code : Option<u64>) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
     pic8259::send_eoi(index);
     wprintln!("Unimplemented interrupt {:#x}", index);
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, err_code : u64) -> ! {
-    panic!("DOUBLE FAULT {:#x} \n{:?}", err_code, stack_frame);
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    crash::exception(&stack_frame);
+    panic!("DOUBLE FAULT {:#x} in {}\n{:?}", err_code, rip(&stack_frame), stack_frame);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    exprintln!("HARDWARE BREAKPOINT\n{:?}", stack_frame);
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    let addr = x86_64::registers::control::Cr2::read();
+    if from_user(&stack_frame) {
+        exprintln!("User code caused PAGE FAULT {:?} at {:#x}\n{:?}", error_code, addr, stack_frame);
+        usermode::exit(-1);
+    }
+    if fixup::fixup(&mut stack_frame) {
+        return;
+    }
+    gdbstub::exception(&stack_frame, Page as u8);
+    crash::exception(&stack_frame);
+    panic!("PAGE FAULT {:#?} at {:#x} in {}\n{:?}", error_code, addr, rip(&stack_frame), stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    let addr = x86_64::registers::control::Cr2::read();
-    panic!("PAGE FAULT {:#?} at {:#x}\n{:?}", error_code, addr, stack_frame);
+/// Only differs from the generic handler in recovering in fixup regions
+extern "x86-interrupt" fn general_protection_handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    if !from_user(&stack_frame) && fixup::fixup(&mut stack_frame) {
+        return;
+    }
+    generic_exception_handler(stack_frame, GeneralProtection as u8, Some(error_code));
 }
 
-extern "x86-interrupt" fn timer(_stack_frame: InterruptStackFrame) {
+percpu! {
+    /// Timer interrupts received by this CPU
+    static TICKS: Cell<u64> = Cell::new(0);
+}
+
+/// Timer interrupts received by this CPU
+pub fn ticks() -> u64 {
+    TICKS.with(|t| t.get())
+}
+
+/// Time since interrupts were enabled, the PIT runs at its default rate of 1193182 / 65536 Hz
+pub fn uptime() -> Duration {
+    let nanos = ticks() as u128 * 65536 * 1_000_000_000 / 1_193_182;
+    Duration::from_nanos(nanos as u64)
+}
+
+extern "x86-interrupt" fn timer(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    TICKS.with(|t| t.set(t.get() + 1));
     pic8259::send_eoi(Timer as u8);
 }
 
-/// Get the exception form exception vector as an enum
+extern "x86-interrupt" fn com1(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    serial::irq_handler();
+    pic8259::send_eoi(Com1 as u8);
+}
+
+extern "x86-interrupt" fn com2(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(&stack_frame);
+    serial::irq_handler();
+    pic8259::send_eoi(Com2 as u8);
+    // GDB sends Ctrl-C to stop the kernel
+    if gdbstub::is_enabled() && gdbstub::interrupt_requested() {
+        crate::hbreak!();
+    }
+}
+
+/// The interrupted instruction as `function+0x1c`
+fn rip(stack_frame: &InterruptStackFrame) -> Symbolized {
+    Symbolized(stack_frame.instruction_pointer.as_u64())
+}
+
+/// If the interrupt arrived while running in ring 3
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
+/// Get the exception form exception vector as an enum, None for reserved vectors
 fn exception_get_name(code: u8) -> Option<ExceptionVector> {
-    match code {
-        0..0x20 => Some(match code {
-            x if x == Division as u8 => Division,
-            x if x == Debug as u8 => Debug,
-            x if x == NonMaskableInterrupt as u8 => NonMaskableInterrupt,
-            x if x == Breakpoint as u8 => Breakpoint,
-            x if x == Overflow as u8 => BoundRange,
-            x if x == InvalidOpcode as u8 => InvalidOpcode,
-            x if x == DeviceNotAvailable as u8 => DeviceNotAvailable,
-            x if x == Double as u8 => Double,
-            x if x == InvalidTss as u8 => InvalidTss,
-            x if x == SegmentNotPresent as u8 => SegmentNotPresent,
-            x if x == Stack as u8 => Stack,
-            x if x == GeneralProtection as u8 => GeneralProtection,
-            x if x == Page as u8 => Page,
-            x if x == X87FloatingPoint as u8 => X87FloatingPoint,
-            x if x == AlignmentCheck as u8 => AlignmentCheck,
-            x if x == MachineCheck as u8 => MachineCheck,
-            x if x == SimdFloatingPoint as u8 => SimdFloatingPoint,
-            x if x == Virtualization as u8 => Virtualization,
-            x if x == ControlProtection as u8 => ControlProtection,
-            x if x == HypervisorInjection as u8 => HypervisorInjection,
-            x if x == VmmCommunication as u8 => VmmCommunication,
-            x if x == Security as u8 => Security,
-            _ => unreachable!()
-        }),
-        _ => None
+    const VECTORS: [ExceptionVector; 23] = [
+        Division, Debug, NonMaskableInterrupt, Breakpoint, Overflow, BoundRange, InvalidOpcode,
+        DeviceNotAvailable, Double, InvalidTss, SegmentNotPresent, Stack, GeneralProtection, Page,
+        X87FloatingPoint, AlignmentCheck, MachineCheck, SimdFloatingPoint, Virtualization,
+        ControlProtection, HypervisorInjection, VmmCommunication, Security,
+    ];
+    VECTORS.into_iter().find(|&vector| vector as u8 == code)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exception_names() {
+        assert_eq!(exception_get_name(0x04), Some(Overflow));
+        assert_eq!(exception_get_name(0x05), Some(BoundRange));
+        assert_eq!(exception_get_name(0x0e), Some(Page));
+        for reserved in [0x09, 0x0f, 0x16, 0x1b, 0x1f] {
+            assert_eq!(exception_get_name(reserved), None);
+        }
+    }
+
+    #[test]
+    fn exception_names_match_vectors() {
+        // Intel SDM volume 3A, table 6-1, and the AMD APM for 0x1c to 0x1e
+        let vectors = [
+            (0x00, Division), (0x01, Debug), (0x02, NonMaskableInterrupt), (0x03, Breakpoint),
+            (0x06, InvalidOpcode), (0x07, DeviceNotAvailable), (0x08, Double), (0x0a, InvalidTss),
+            (0x0b, SegmentNotPresent), (0x0c, Stack), (0x0d, GeneralProtection), (0x10, X87FloatingPoint),
+            (0x11, AlignmentCheck), (0x12, MachineCheck), (0x13, SimdFloatingPoint), (0x14, Virtualization),
+            (0x15, ControlProtection), (0x1c, HypervisorInjection), (0x1d, VmmCommunication), (0x1e, Security),
+        ];
+        for (code, vector) in vectors {
+            assert_eq!(exception_get_name(code), Some(vector), "vector {:#x}", code);
+        }
+        assert!((0x20..=u8::MAX).all(|code| exception_get_name(code).is_none()));
     }
 }
@@ -21,7 +21,8 @@ static KEYBUFFER: RwLock<KeyBuffer> = RwLock::new(KeyBuffer {buffer: [KeyCode::U
 // I should not be able to receive keyboard interrupts during
 // the handling of a keyboard interrupt. So a deadlock should not occur.
 #[allow(const_item_mutation)]
-pub(super) extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+pub(super) extern "x86-interrupt" fn handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::percpu::enter_interrupt(&stack_frame);
     let scancode = unsafe {PS2.read()};
     if let Some(keyevent) = ps2::decode_scancode(scancode) {
         if keyevent.state == ps2::State::Press {
@@ -44,7 +45,7 @@ pub struct KeyReader {
 }
 
 impl KeyReader {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {index: 0}
     }
     /// Try to get a key immediately
@@ -61,6 +62,7 @@ impl KeyReader {
         }
     }
     /// Waits for a key using a hlt loop
+    #[allow(dead_code)]
     pub fn get_key(&mut self) -> KeyCode {
         loop {
             if let Some(key) = self.try_key() { return key; }
@@ -70,36 +72,53 @@ impl KeyReader {
 }
 
 pub mod ps2 {
-    use core::sync::atomic::{Ordering, AtomicBool};
     use bit_field::BitField;
+    use spin::Mutex;
     use num_enum::TryFromPrimitive;
 
-    #[derive(Debug)]
+    #[derive(Debug, PartialEq)]
     pub struct KeyEvent {
         pub state: State,
         pub key: KeyCode,
     }
 
     pub fn decode_scancode(scancode: ScanCode) -> Option<KeyEvent> {
-        // If the last scancode was 0xE0 then LAST_MULTIMEDIA is set
-        // which will cause the first bit to be set when looking up [KeyCode]
-        // (normally the fist bit is reserved for state)
-        static LAST_MULTIMEDIA: AtomicBool = AtomicBool::new(false);
-        let state = State::from(scancode);
-        let key = KeyCode::try_from(*scancode.clone()
-                            .set_bit(7, LAST_MULTIMEDIA.load(Ordering::Relaxed)))
-                            .unwrap_or(KeyCode::Unknown);
-        if key == KeyCode::Unknown {
+        static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+        let event = DECODER.lock().decode(scancode);
+        if let Some(KeyEvent { key: KeyCode::Unknown, .. }) = event {
             wprintln!("Unknown scancode {:#x} {:#x}", scancode, scancode.clone().set_bit(7, false));
         }
+        event
+    }
 
-        if key == KeyCode::MultiMedia {
-            LAST_MULTIMEDIA.store(true, Ordering::Relaxed);
-            return None;
-        } else {
-            LAST_MULTIMEDIA.store(false, Ordering::Relaxed);
+    /// Turns scancodes into key events, remembering the 0xE0 prefix of multimedia keys
+    pub struct Decoder {
+        // If the last scancode was 0xE0
+        // the first bit is set when looking up [KeyCode]
+        // (normally the fist bit is reserved for state)
+        last_multimedia: bool,
+    }
+
+    impl Decoder {
+        pub const fn new() -> Self {
+            Self { last_multimedia: false }
+        }
+
+        /// Returns None for the 0xE0 prefix
+        pub fn decode(&mut self, scancode: ScanCode) -> Option<KeyEvent> {
+            let state = State::from(scancode);
+            let key = KeyCode::try_from(*scancode.clone()
+                                .set_bit(7, self.last_multimedia))
+                                .unwrap_or(KeyCode::Unknown);
+
+            if key == KeyCode::MultiMedia {
+                self.last_multimedia = true;
+                return None;
+            } else {
+                self.last_multimedia = false;
+            }
+            Some(KeyEvent {state, key})
         }
-        Some(KeyEvent {state, key})
     }
 
     //pub struct ScanCode (pub u8);
@@ -301,3 +320,84 @@ pub mod ps2 {
         }
     }
 }
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::ps2::*;
+    use bit_field::BitField;
+    use proptest::prelude::*;
+
+    /// Every key that has a character
+    fn printable() -> impl Iterator<Item = (KeyCode, char)> {
+        (0..=u8::MAX).filter_map(|code| {
+            let key = KeyCode::try_from(code).ok()?;
+            Some((key, char::try_from(key).ok()?))
+        })
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(0x1e), Some(KeyEvent { state: State::Press, key: KeyCode::A }));
+        assert_eq!(decoder.decode(0x9e), Some(KeyEvent { state: State::Release, key: KeyCode::A }));
+        assert_eq!(decoder.decode(0x00), Some(KeyEvent { state: State::Press, key: KeyCode::Unknown }));
+    }
+
+    #[test]
+    fn multimedia_prefix() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(0xe0), None);
+        assert_eq!(decoder.decode(0x49), Some(KeyEvent { state: State::Press, key: KeyCode::PageUp }));
+        assert_eq!(decoder.decode(0xe0), None);
+        assert_eq!(decoder.decode(0xc9), Some(KeyEvent { state: State::Release, key: KeyCode::PageUp }));
+        // The prefix only applies to the next scancode
+        assert_eq!(decoder.decode(0x49), Some(KeyEvent { state: State::Press, key: KeyCode::NumPad9 }));
+    }
+
+    #[test]
+    fn characters() {
+        let chars: Vec<char> = printable().map(|(_, c)| c).collect();
+        for c in ('a'..='z').chain('0'..='9') {
+            assert!(chars.contains(&c), "{:?} can not be typed", c);
+        }
+        assert!(chars.iter().all(|c| c.is_ascii_graphic() || *c == ' ' || *c == '\n'));
+        assert_eq!(char::try_from(KeyCode::Enter), Ok('\n'));
+        assert_eq!(char::try_from(KeyCode::LeftShift), Err(()));
+    }
+
+    #[test]
+    fn letters_are_unique() {
+        let mut letters: Vec<char> = printable().map(|(_, c)| c).filter(char::is_ascii_lowercase).collect();
+        letters.sort();
+        assert_eq!(letters.iter().collect::<String>(), "abcdefghijklmnopqrstuvwxyz");
+    }
+
+    proptest! {
+        #[test]
+        fn state_is_bit_7(scancode in any::<u8>().prop_filter("prefix", |s| s & 0x7f != 0x60)) {
+            let event = Decoder::new().decode(scancode).unwrap();
+            prop_assert_eq!(event.state == State::Release, scancode.get_bit(7));
+            let key = KeyCode::try_from(scancode & 0x7f).unwrap_or(KeyCode::Unknown);
+            prop_assert_eq!(event.key, key);
+        }
+
+        #[test]
+        fn prefix_sets_bit_7(scancode in any::<u8>().prop_filter("prefix", |s| s & 0x7f != 0x60)) {
+            let mut decoder = Decoder::new();
+            prop_assert_eq!(decoder.decode(0xe0), None);
+            let event = decoder.decode(scancode).unwrap();
+            prop_assert_eq!(event.key, KeyCode::try_from(scancode | 0x80).unwrap_or(KeyCode::Unknown));
+        }
+
+        #[test]
+        fn forgets_prefix(scancodes: Vec<u8>, scancode in any::<u8>()) {
+            // After any scancode but the prefix, the decoder is as good as new
+            let mut decoder = Decoder::new();
+            for scancode in scancodes {
+                decoder.decode(scancode);
+            }
+            decoder.decode(0x01);
+            prop_assert_eq!(decoder.decode(scancode), Decoder::new().decode(scancode));
+        }
+    }
+}
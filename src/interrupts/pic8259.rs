@@ -29,6 +29,22 @@ pub fn send_eoi(irq: u8) {
     unsafe {PIC1.command.write(0x20)};
 }
 
+/// Let the PIC deliver interrupts of `irq`
+pub fn unmask(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            let mask = PIC2.data.read();
+            PIC2.data.write(mask & !(1 << (irq - 8)));
+            // The slave is connected to line 2 of the master
+            let mask = PIC1.data.read();
+            PIC1.data.write(mask & !(1 << 2));
+        } else {
+            let mask = PIC1.data.read();
+            PIC1.data.write(mask & !(1 << irq));
+        }
+    }
+}
+
 /// Reinitiliaze the PIC to use an offset above 0x20
 pub fn init_pic() {
 
@@ -0,0 +1,138 @@
+//! Entry points for the debug exception (#DB) and breakpoint (#BP)
+//! that save every general purpose register, so debuggers can inspect and change the interrupted code
+
+use core::arch::global_asm;
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+use crate::{gdbstub, watchpoint};
+use crate::kdebug::nested;
+use crate::symbols::Symbolized;
+
+/// The registers pushed by `trap_common`, followed by what the CPU pushed
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// The exception number
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn from_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+extern "C" {
+    fn debug_entry();
+    fn breakpoint_entry();
+}
+
+// Neither exception has an error code, the vector is pushed in its place.
+// The CPU aligned the stack to 16 bytes before pushing 5 values, after 16 more it is off by 8.
+global_asm!("
+.global debug_entry
+debug_entry:
+    push 1
+    jmp trap_common
+
+.global breakpoint_entry
+breakpoint_entry:
+    push 3
+    jmp trap_common
+
+trap_common:
+    test qword ptr [rsp + 16], 3
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sub rsp, 8
+    cld
+    call {dispatch}
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 8
+    test qword ptr [rsp + 8], 3
+    jz 2f
+    swapgs
+2:
+    iretq
+",
+    dispatch = sym dispatch,
+);
+
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.debug.set_handler_addr(VirtAddr::from_ptr(debug_entry as *const ()));
+        idt.breakpoint.set_handler_addr(VirtAddr::from_ptr(breakpoint_entry as *const ()));
+    }
+}
+
+extern "C" fn dispatch(frame: &mut TrapFrame) {
+    if frame.vector == 1 && watchpoint::hit(frame) {
+        return;
+    }
+    if frame.from_user() {
+        match frame.vector {
+            1 => wprintln!("Unexpected debug exception in {}\n{:x?}", Symbolized(frame.rip), frame),
+            _ => exprintln!("HARDWARE BREAKPOINT in {}\n{:x?}", Symbolized(frame.rip), frame),
+        }
+        return;
+    }
+    if gdbstub::is_enabled() && !nested::owns(frame) {
+        gdbstub::trap(frame);
+        return;
+    }
+    nested::trap(frame);
+}
@@ -2,43 +2,71 @@
 
 use core::ptr::{addr_of, slice_from_raw_parts};
 
-use crate::{debug, keyboard, pci, vga};
+use log::LevelFilter;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::backtrace::Backtrace;
+use crate::{bootinfo, debug, disas, elf, gdbstub, interrupts, keyboard, logger, pci, percpu, symbols, vga, watchpoint};
+
+mod memory;
+pub mod nested;
+
+/// Longest command line
+const LINE_LENGTH: usize = 64;
+
+/// Shared by the shell and nested sessions, so neither reads keys the other already did
+static KEYS: Mutex<keyboard::KeyReader> = Mutex::new(keyboard::KeyReader::new());
 
 pub fn kdebug() -> ! {
-    let mut kr = keyboard::KeyReader::new();
     loop {
         print!("kdebug> ");
-        let mut command: [u8; 32] = [0; 32];
-        let mut index = 0;
-        loop {
-            let key = kr.get_key();
-            if let Ok(c) = <keyboard::ps2::KeyCode as TryInto<char>>::try_into(key) {
-                if c != '\n' {
-                    if index < 32 {
-                        command[index] = c as u8;
-                        index += 1;
-                        print!("{}", c);
-                    }
-                } else {
-                    let cmd = &command[..index];
-                    println!();
-                    handle_cmd(cmd);
-                    break;
+        let mut command = [0; LINE_LENGTH];
+        let cmd = read_command(&mut command);
+        handle_cmd(cmd);
+    }
+}
+
+/// Read a line of up to LINE_LENGTH characters
+fn read_command(command: &mut [u8; LINE_LENGTH]) -> &[u8] {
+    let mut index = 0;
+    loop {
+        let key = next_key();
+        if let Ok(c) = <keyboard::ps2::KeyCode as TryInto<char>>::try_into(key) {
+            if c != '\n' {
+                if index < LINE_LENGTH {
+                    command[index] = c as u8;
+                    index += 1;
+                    print!("{}", c);
                 }
             } else {
-                if key == keyboard::ps2::KeyCode::Backspace {
-                    if index > 0 {
-                        index -= 1;
-                        vga::PRINTER.lock().col -= 1;
-                        print!(" "); // clear character
-                        vga::PRINTER.lock().col -= 1;
-                    }
+                println!();
+                return &command[..index];
+            }
+        } else {
+            if key == keyboard::ps2::KeyCode::Backspace {
+                if index > 0 {
+                    index -= 1;
+                    vga::PRINTER.lock().col -= 1;
+                    print!(" "); // clear character
+                    vga::PRINTER.lock().col -= 1;
                 }
             }
         }
     }
 }
 
+/// Wait for a key using a hlt loop
+fn next_key() -> keyboard::ps2::KeyCode {
+    loop {
+        // A trap in an interrupt handler must not find the reader locked
+        if let Some(key) = without_interrupts(|| KEYS.lock().try_key()) {
+            return key;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
 fn handle_cmd(cmd: &[u8]) {
     match cmd {
         b"help" => {
@@ -51,6 +79,25 @@ fn handle_cmd(cmd: &[u8]) {
             println!("pagefault");
             println!("scanpci");
             println!("mbitags");
+            println!("modules");
+            println!("run <module> [user|kernel]");
+            println!("percpu");
+            println!("sym <addr>");
+            println!("bt");
+            println!("dmesg [error|warn|info|debug|trace]");
+            println!("gdb");
+            println!("watch <addr> [r|w|x] [len]");
+            println!("unwatch [slot]");
+            println!("watches");
+            println!("break <addr|symbol>");
+            println!("unbreak [addr|symbol]");
+            println!("breaks");
+            println!("x <addr|symbol> [count] [b|w|d|q]");
+            println!("w <addr|symbol> <value> [b|w|d|q]");
+            println!("fill <addr> <len> <byte>");
+            println!("search <hex bytes> [start] [len]");
+            println!("hexdump <addr|symbol> [len]");
+            println!("disas <addr|symbol> [count]");
             println!("clean");
         },
         b"sections" => debug::print_elfsections(),
@@ -72,9 +119,97 @@ fn handle_cmd(cmd: &[u8]) {
         b"mbitags" => {
             let mbi = crate::MBI.get().unwrap();
             for tag in mbi.tags() {
-                println!("{tag:?}");
+                match tag {
+                    Ok(tag) => println!("{tag:?}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+        },
+        b"modules" => {
+            for module in bootinfo::get().modules() {
+                println!(
+                    "{:#x} - {:#x} ({:#x} bytes) {}",
+                    module.start,
+                    module.end,
+                    module.end - module.start,
+                    module.cmdline
+                );
+            }
+        },
+        [b'r', b'u', b'n', b' ', args @ ..] => run(args),
+        b"bt" => print!("{}", Backtrace::here()),
+        [b's', b'y', b'm', b' ', addr @ ..] => {
+            let Some(addr) = parse_number(addr) else {
+                println!("Usage: sym <addr>");
+                return;
+            };
+            match symbols::symbolize(addr) {
+                Some((name, offset)) => println!("{:#x} is {}+{:#x}", addr, symbols::Demangle(name), offset),
+                None if symbols::table().is_none() => println!("No symbol table loaded"),
+                None => println!("No symbol at {:#x}", addr),
+            }
+        },
+        b"dmesg" => dmesg(LevelFilter::Trace),
+        [b'd', b'm', b'e', b's', b'g', b' ', level @ ..] => {
+            match core::str::from_utf8(level).ok().and_then(|l| l.trim().parse().ok()) {
+                Some(level) => dmesg(level),
+                None => println!("Usage: dmesg [error|warn|info|debug|trace]"),
+            }
+        },
+        b"gdb" => {
+            if gdbstub::is_enabled() {
+                crate::hbreak!();
+            } else {
+                println!("The GDB stub is not enabled, boot with the gdb option");
+            }
+        },
+        [b'w', b'a', b't', b'c', b'h', b' ', args @ ..] => watch(args),
+        b"unwatch" => watchpoint::clear_all(),
+        [b'u', b'n', b'w', b'a', b't', b'c', b'h', b' ', slot @ ..] => {
+            match parse_number(slot).and_then(|slot| watchpoint::clear(slot as usize)) {
+                Some(watchpoint) => println!("Removed {}", watchpoint),
+                None => println!("No watchpoint in that slot"),
+            }
+        },
+        b"watches" => {
+            for (slot, watchpoint) in watchpoint::list().iter().enumerate() {
+                if let Some(watchpoint) = watchpoint {
+                    println!("{}: {}", slot, watchpoint);
+                }
             }
         },
+        [b'b', b'r', b'e', b'a', b'k', b' ', location @ ..] => {
+            let Some(addr) = parse_location(location) else {
+                println!("Usage: break <addr|symbol>");
+                return;
+            };
+            match nested::set(addr) {
+                Ok(()) => println!("Breakpoint at {:#x} in {}", addr, symbols::Symbolized(addr)),
+                Err(e) => println!("{}", e),
+            }
+        },
+        b"unbreak" => nested::remove_all(),
+        [b'u', b'n', b'b', b'r', b'e', b'a', b'k', b' ', location @ ..] => {
+            match parse_location(location) {
+                Some(addr) if nested::remove(addr) => {},
+                _ => println!("No breakpoint there"),
+            }
+        },
+        b"breaks" => {
+            for addr in nested::list() {
+                println!("{:#x} in {}", addr, symbols::Symbolized(addr));
+            }
+        },
+        [b'x', b' ', args @ ..] => memory::examine(args),
+        [b'w', b' ', args @ ..] => memory::write_value(args),
+        [b'f', b'i', b'l', b'l', b' ', args @ ..] => memory::fill(args),
+        [b's', b'e', b'a', b'r', b'c', b'h', b' ', args @ ..] => memory::search(args),
+        [b'h', b'e', b'x', b'd', b'u', b'm', b'p', b' ', args @ ..] => memory::hexdump(args),
+        [b'd', b'i', b's', b'a', b's', b' ', args @ ..] => disassemble(args),
+        b"percpu" => {
+            println!("CPU {} area at {:#x} ({:#x} bytes)", percpu::cpu_id(), percpu::area_addr(), percpu::area_size());
+            println!("Timer ticks: {}", interrupts::ticks());
+        },
         b"clear" => {
             vga::clear();
             vga::PRINTER.lock().col = 0;
@@ -85,3 +220,107 @@ fn handle_cmd(cmd: &[u8]) {
         }
     }
 }
+
+/// Print the kernel log up to `level`
+fn dmesg(level: LevelFilter) {
+    logger::for_each(|entry| {
+        if entry.level <= level {
+            println!("{}", entry);
+        }
+    });
+}
+
+/// Set a hardware watchpoint, `r` watches reads and writes as the CPU can not watch only reads
+fn watch(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some(addr) = words.next().and_then(|addr| parse_number(addr.as_bytes())) else {
+        println!("Usage: watch <addr> [r|w|x] [len]");
+        return;
+    };
+    let access = match words.next() {
+        None | Some("w") => watchpoint::Access::Write,
+        Some("r") => watchpoint::Access::ReadWrite,
+        Some("x") => watchpoint::Access::Execute,
+        Some(access) => {
+            println!("Unknown access {:?}", access);
+            return;
+        }
+    };
+    let len = match words.next().map(|len| len.parse()) {
+        None => 1,
+        Some(Ok(len)) => len,
+        Some(Err(_)) => {
+            println!("Usage: watch <addr> [r|w|x] [len]");
+            return;
+        }
+    };
+    match watchpoint::Watchpoint::new(addr, access, len).and_then(watchpoint::set) {
+        Ok(slot) => println!("Watchpoint {} set", slot),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Disassemble `count` instructions, 8 by default
+fn disassemble(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some(addr) = words.next().and_then(|addr| parse_location(addr.as_bytes())) else {
+        println!("Usage: disas <addr|symbol> [count]");
+        return;
+    };
+    let count = match words.next().map(|count| count.parse()) {
+        None => 8,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => {
+            println!("Usage: disas <addr|symbol> [count]");
+            return;
+        }
+    };
+    println!("{}:", symbols::Symbolized(addr));
+    disas::print(addr, count);
+}
+
+/// Run the module whose command line starts with the given name (or file name)
+fn run(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        println!("Usage: run <module> [user|kernel]");
+        return;
+    };
+    let mode = match words.next() {
+        None | Some("user") => elf::Mode::User,
+        Some("kernel") => elf::Mode::Kernel,
+        Some(mode) => {
+            println!("Unknown mode {:?}", mode);
+            return;
+        }
+    };
+
+    let Some(module) = bootinfo::get().module(name) else {
+        println!("No module named {:?}", name);
+        return;
+    };
+
+    match elf::run_module(module, mode) {
+        Ok(code) => println!("{} exited with {}", name, code),
+        Err(e) => eprintln!("Could not run {}: {}", name, e),
+    }
+}
+
+/// An address, or the address of a symbol. Hexadecimal numbers that are also symbol names need 0x
+fn parse_location(s: &[u8]) -> Option<u64> {
+    let name = core::str::from_utf8(s).ok()?.trim();
+    if name.starts_with("0x") {
+        return parse_number(s);
+    }
+    symbols::lookup(name).or_else(|| parse_number(s))
+}
+
+/// Parse a hexadecimal number, with or without 0x
+fn parse_number(s: &[u8]) -> Option<u64> {
+    let s = core::str::from_utf8(s).ok()?.trim();
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
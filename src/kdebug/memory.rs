@@ -0,0 +1,308 @@
+//! The memory commands of kdebug: `x`, `w`, `fill`, `search` and `hexdump`
+
+// Every access is a probe (see fixup.rs), so a bad address is reported instead of panicking in the page fault handler.
+
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use crate::fixup::{probe_read, probe_write, Fault};
+use crate::paging::{self, IDENTITY_MAPPED_END};
+
+use super::{parse_location, parse_number};
+
+/// Bytes per line of `x` and `hexdump`
+const LINE: usize = 16;
+const PAGE_SIZE: u64 = 0x1000;
+/// Longest byte string `search` looks for
+const MAX_NEEDLE: usize = 16;
+/// `search` stops after this many matches
+const MAX_MATCHES: usize = 16;
+const HEXDUMP_DEFAULT: u64 = 0x80;
+
+/// Copy the memory at `addr` into `buffer`
+fn read(addr: u64, buffer: &mut [u8]) -> Result<(), Fault> {
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = probe_read(addr.wrapping_add(i as u64))?;
+    }
+    Ok(())
+}
+
+/// Write `len` bytes from `byte(i)` to `addr`
+fn write(addr: u64, len: u64, byte: impl Fn(usize) -> u8) -> Result<(), Fault> {
+    for i in 0..len {
+        probe_write(addr.wrapping_add(i), byte(i as usize))?;
+    }
+    Ok(())
+}
+
+/// `b`, `w`, `d` or `q`, in bytes
+fn parse_unit(s: &str) -> Option<usize> {
+    match s {
+        "b" => Some(1),
+        "w" => Some(2),
+        "d" => Some(4),
+        "q" => Some(8),
+        _ => None,
+    }
+}
+
+/// The smallest unit that holds `value`
+fn unit_of(value: u64) -> usize {
+    match value {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x10000..=0xffff_ffff => 4,
+        _ => 8,
+    }
+}
+
+/// Hexadecimal bytes like `deadbeef`
+fn parse_bytes(s: &str) -> Option<([u8; MAX_NEEDLE], usize)> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.is_empty() || s.len() % 2 != 0 || s.len() / 2 > MAX_NEEDLE {
+        return None;
+    }
+    let mut bytes = [0; MAX_NEEDLE];
+    for (i, pair) in s.as_bytes().chunks(2).enumerate() {
+        bytes[i] = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some((bytes, s.len() / 2))
+}
+
+/// A line of `x`: the address, then the little endian values of `unit` bytes each
+struct Values<'a> {
+    addr: u64,
+    bytes: &'a [u8],
+    unit: usize,
+}
+
+impl fmt::Display for Values<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}:", self.addr)?;
+        for value in self.bytes.chunks(self.unit) {
+            let mut le = [0; 8];
+            le[..value.len()].copy_from_slice(value);
+            write!(f, " {:0width$x}", u64::from_le_bytes(le), width = self.unit * 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of `hexdump`, like `hexdump -C`
+struct HexdumpLine<'a> {
+    addr: u64,
+    bytes: &'a [u8],
+}
+
+impl fmt::Display for HexdumpLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x} ", self.addr)?;
+        for i in 0..LINE {
+            if i % 8 == 0 {
+                f.write_str(" ")?;
+            }
+            match self.bytes.get(i) {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => f.write_str("   ")?,
+            }
+        }
+        f.write_str(" |")?;
+        for &byte in self.bytes {
+            let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            write!(f, "{}", c)?;
+        }
+        f.write_str("|")
+    }
+}
+
+/// Print `len` bytes at `addr` a line at a time, stopping at the first unmapped line
+fn lines(addr: u64, len: u64, mut print_line: impl FnMut(u64, &[u8])) {
+    let mut buffer = [0; LINE];
+    let end = addr.saturating_add(len);
+    let mut line = addr;
+    while line < end {
+        let bytes = &mut buffer[..(end - line).min(LINE as u64) as usize];
+        if let Err(e) = read(line, bytes) {
+            println!("{}", e);
+            return;
+        }
+        print_line(line, bytes);
+        line += bytes.len() as u64;
+    }
+}
+
+/// `x <addr> [count] [b|w|d|q]`, count is in units and defaults to a line
+pub fn examine(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some(addr) = words.next().and_then(|addr| parse_location(addr.as_bytes())) else {
+        println!("Usage: x <addr> [count] [b|w|d|q]");
+        return;
+    };
+    let mut unit = 1;
+    let mut count = None;
+    for word in words {
+        match (parse_unit(word), parse_number(word.as_bytes())) {
+            (Some(u), _) => unit = u,
+            (None, Some(n)) => count = Some(n),
+            (None, None) => {
+                println!("Usage: x <addr> [count] [b|w|d|q]");
+                return;
+            },
+        }
+    }
+    let len = count.map_or(LINE as u64, |count| count.saturating_mul(unit as u64));
+    lines(addr, len, |addr, bytes| println!("{}", Values { addr, bytes, unit }));
+}
+
+/// `w <addr> <value> [b|w|d|q]`, the unit defaults to the smallest that holds the value
+pub fn write_value(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let addr = words.next().and_then(|addr| parse_location(addr.as_bytes()));
+    let value = words.next().and_then(|value| parse_number(value.as_bytes()));
+    let (Some(addr), Some(value)) = (addr, value) else {
+        println!("Usage: w <addr> <value> [b|w|d|q]");
+        return;
+    };
+    let unit = match words.next() {
+        None => unit_of(value),
+        Some(unit) => match parse_unit(unit) {
+            Some(unit) => unit,
+            None => {
+                println!("Usage: w <addr> <value> [b|w|d|q]");
+                return;
+            },
+        },
+    };
+    if unit < 8 && value >> (unit * 8) != 0 {
+        println!("{:#x} does not fit in {} bytes", value, unit);
+        return;
+    }
+    let bytes = value.to_le_bytes();
+    match write(addr, unit as u64, |i| bytes[i]) {
+        Ok(()) => println!("{}", Values { addr, bytes: &bytes[..unit], unit }),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// `fill <addr> <len> <byte>`
+pub fn fill(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace().map(|word| parse_number(word.as_bytes()));
+    let (Some(Some(addr)), Some(Some(len)), Some(Some(byte))) = (words.next(), words.next(), words.next()) else {
+        println!("Usage: fill <addr> <len> <byte>");
+        return;
+    };
+    let Ok(byte) = u8::try_from(byte) else {
+        println!("{:#x} is not a byte", byte);
+        return;
+    };
+    if let Err(e) = write(addr, len, |_| byte) {
+        println!("{}", e);
+    }
+}
+
+/// `search <bytes> [start] [len]`, by default in the identity mapped memory
+pub fn search(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some((needle, needle_len)) = words.next().and_then(parse_bytes) else {
+        println!("Usage: search <hex bytes> [start] [len]");
+        return;
+    };
+    let needle = &needle[..needle_len];
+    let start = words.next().and_then(|start| parse_number(start.as_bytes())).unwrap_or(0);
+    let len = words.next().and_then(|len| parse_number(len.as_bytes())).unwrap_or(IDENTITY_MAPPED_END - start.min(IDENTITY_MAPPED_END));
+    let end = start.saturating_add(len);
+
+    let mut matches = 0;
+    let mut page = start & !(PAGE_SIZE - 1);
+    let mut buffer = [0; MAX_NEEDLE];
+    while page < end {
+        // The last page of the address space ends at u64::MAX
+        let page_end = page.checked_add(PAGE_SIZE);
+        // Skip unmapped and non-canonical pages without faulting on every byte,
+        // try_new sign extends addresses with only bit 47 set instead of rejecting them
+        let canonical = VirtAddr::try_new(page).ok().filter(|canonical| canonical.as_u64() == page);
+        if canonical.is_some_and(|page| paging::is_mapped(page, 1, false)) {
+            for addr in page.max(start)..page_end.unwrap_or(u64::MAX).min(end.saturating_sub(needle.len() as u64 - 1)) {
+                let candidate = match page_end.is_none_or(|page_end| addr + needle.len() as u64 <= page_end) {
+                    true => unsafe { core::slice::from_raw_parts(addr as *const u8, needle.len()) },
+                    // A match across into the next page, which may be unmapped
+                    false => match read(addr, &mut buffer[..needle.len()]) {
+                        Ok(()) => &buffer[..needle.len()],
+                        Err(_) => continue,
+                    },
+                };
+                if candidate == needle {
+                    if matches == MAX_MATCHES {
+                        println!("...");
+                        return;
+                    }
+                    println!("{:#x}", addr);
+                    matches += 1;
+                }
+            }
+        }
+        match page_end {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+    if matches == 0 {
+        println!("Not found");
+    }
+}
+
+/// `hexdump <addr> [len]`
+pub fn hexdump(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or_default();
+    let mut words = args.split_ascii_whitespace();
+    let Some(addr) = words.next().and_then(|addr| parse_location(addr.as_bytes())) else {
+        println!("Usage: hexdump <addr> [len]");
+        return;
+    };
+    let len = words.next().and_then(|len| parse_number(len.as_bytes())).unwrap_or(HEXDUMP_DEFAULT);
+    lines(addr, len, |addr, bytes| println!("{}", HexdumpLine { addr, bytes }));
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let bytes = [0xef, 0xbe, 0xad, 0xde, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(format!("{}", Values { addr: 0x1000, bytes: &bytes, unit: 4 }), "0x0000000000001000: deadbeef 00000001");
+        assert_eq!(format!("{}", Values { addr: 0x1000, bytes: &bytes[..3], unit: 1 }), "0x0000000000001000: ef be ad");
+        assert_eq!(format!("{}", Values { addr: 0, bytes: &bytes, unit: 8 }), "0x0000000000000000: 00000001deadbeef");
+    }
+
+    #[test]
+    fn hexdump_line() {
+        let line = format!("{}", HexdumpLine { addr: 0x100000, bytes: b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0" });
+        assert_eq!(line, "0000000000100000  7f 45 4c 46 02 01 01 00  00 00 00 00 00 00 00 00  |.ELF............|");
+        let short = format!("{}", HexdumpLine { addr: 0x10, bytes: b"hi" });
+        assert_eq!(short, format!("0000000000000010  68 69 {}  |hi|", " ".repeat(3 * 14 + 1 - 1)));
+    }
+
+    #[test]
+    fn bytes() {
+        let (bytes, len) = parse_bytes("deadBEEF").unwrap();
+        assert_eq!(&bytes[..len], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_bytes("0x00").map(|(_, len)| len), Some(1));
+        assert!(parse_bytes("abc").is_none());
+        assert!(parse_bytes("zz").is_none());
+        assert!(parse_bytes("").is_none());
+        assert!(parse_bytes(&"00".repeat(MAX_NEEDLE + 1)).is_none());
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_unit("d"), Some(4));
+        assert_eq!(parse_unit("x"), None);
+        assert_eq!((unit_of(0xff), unit_of(0x100), unit_of(0x1_0000), unit_of(0x1_0000_0000)), (1, 2, 4, 8));
+    }
+}
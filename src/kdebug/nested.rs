@@ -0,0 +1,274 @@
+//! A nested kdebug session on the code stopped by int3 or a debug exception,
+//! and the software breakpoints set with `break`
+
+// While stopped, the breakpoints are disarmed (their original bytes are back), so the session can not hit them.
+// Continuing arms them again, except one at RIP: that instruction is single stepped first (RFLAGS.TF),
+// and the breakpoint is armed in the debug exception after it.
+// The session waits for keyboard interrupts, so it hangs if it stopped an interrupt handler before its EOI.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+
+use crate::gdbstub::{KernelMemory, Memory};
+use crate::interrupts::trap::TrapFrame;
+use crate::symbols::Symbolized;
+
+const MAX_BREAKPOINTS: usize = 16;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = RFlags::TRAP_FLAG.bits();
+
+static BREAKPOINTS: Mutex<Breakpoints> = Mutex::new(Breakpoints { slots: [None; MAX_BREAKPOINTS], armed: true });
+/// Set by `step`, so the next single step stops again
+static STEPPING: AtomicBool = AtomicBool::new(false);
+/// Set while stepping over a breakpoint at RIP, the breakpoints are armed in the next debug exception
+static STEPPING_OVER: AtomicBool = AtomicBool::new(false);
+/// Set while a session runs, an int3 in the session itself does not start another one
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakError {
+    Full,
+    /// The address is not mapped or not writable
+    Memory,
+}
+
+impl fmt::Display for BreakError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakError::Full => write!(f, "All {} breakpoints are in use", MAX_BREAKPOINTS),
+            BreakError::Memory => write!(f, "The address is not writable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte int3 replaces
+    original: u8,
+}
+
+struct Breakpoints {
+    slots: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// If int3 is written to the breakpoints
+    armed: bool,
+}
+
+impl Breakpoints {
+    fn contains(&self, addr: u64) -> bool {
+        self.slots.iter().flatten().any(|b| b.addr == addr)
+    }
+
+    fn insert(&mut self, addr: u64, memory: &mut impl Memory) -> Result<(), BreakError> {
+        if self.contains(addr) {
+            return Ok(());
+        }
+        let slot = self.slots.iter().position(Option::is_none).ok_or(BreakError::Full)?;
+        let original = memory.read(addr).ok_or(BreakError::Memory)?;
+        if self.armed && !memory.write(addr, INT3) {
+            return Err(BreakError::Memory);
+        }
+        self.slots[slot] = Some(Breakpoint { addr, original });
+        Ok(())
+    }
+
+    fn remove(&mut self, addr: u64, memory: &mut impl Memory) -> bool {
+        let Some(slot) = self.slots.iter_mut().find(|b| matches!(b, Some(b) if b.addr == addr)) else { return false };
+        let breakpoint = slot.take().unwrap();
+        if self.armed {
+            memory.write(breakpoint.addr, breakpoint.original);
+        }
+        true
+    }
+
+    fn arm(&mut self, memory: &mut impl Memory) {
+        for breakpoint in self.slots.iter().flatten() {
+            memory.write(breakpoint.addr, INT3);
+        }
+        self.armed = true;
+    }
+
+    fn disarm(&mut self, memory: &mut impl Memory) {
+        if self.armed {
+            for breakpoint in self.slots.iter().flatten() {
+                memory.write(breakpoint.addr, breakpoint.original);
+            }
+        }
+        self.armed = false;
+    }
+}
+
+/// Stop whenever `addr` is executed
+pub fn set(addr: u64) -> Result<(), BreakError> {
+    BREAKPOINTS.lock().insert(addr, &mut KernelMemory)
+}
+
+/// Returns false if there is no breakpoint at `addr`
+pub fn remove(addr: u64) -> bool {
+    BREAKPOINTS.lock().remove(addr, &mut KernelMemory)
+}
+
+pub fn remove_all() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    while let Some(breakpoint) = breakpoints.slots.iter().flatten().next().copied() {
+        breakpoints.remove(breakpoint.addr, &mut KernelMemory);
+    }
+}
+
+/// The addresses of the breakpoints
+pub fn list() -> impl Iterator<Item = u64> {
+    let slots = BREAKPOINTS.lock().slots;
+    slots.into_iter().flatten().map(|b| b.addr)
+}
+
+/// The address of the int3 that trapped, rip points after it
+fn int3_addr(frame: &TrapFrame) -> u64 {
+    frame.rip.wrapping_sub(1)
+}
+
+/// If the trap was caused by a breakpoint or step of kdebug, which the GDB stub must leave alone
+pub fn owns(frame: &TrapFrame) -> bool {
+    match frame.vector {
+        3 => BREAKPOINTS.lock().contains(int3_addr(frame)),
+        _ => STEPPING.load(Ordering::Relaxed) || STEPPING_OVER.load(Ordering::Relaxed),
+    }
+}
+
+/// Called on int3 and debug exceptions in the kernel
+pub fn trap(frame: &mut TrapFrame) {
+    let reason = match frame.vector {
+        3 if BREAKPOINTS.lock().contains(int3_addr(frame)) => {
+            // Run the replaced instruction when continuing
+            frame.rip = int3_addr(frame);
+            "Breakpoint"
+        },
+        3 => "int3",
+        _ => {
+            if STEPPING_OVER.swap(false, Ordering::Relaxed) {
+                BREAKPOINTS.lock().arm(&mut KernelMemory);
+                if !STEPPING.load(Ordering::Relaxed) {
+                    frame.rflags &= !TRAP_FLAG;
+                    return;
+                }
+            }
+            match STEPPING.load(Ordering::Relaxed) {
+                true => "Stepped",
+                false => "Debug exception",
+            }
+        },
+    };
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        wprintln!("{} in the kdebug session at {}, ignored", reason, Symbolized(frame.rip));
+        return;
+    }
+    session(frame, reason);
+    ACTIVE.store(false, Ordering::Release);
+}
+
+/// Handle commands until `step` or `continue`
+fn session(frame: &mut TrapFrame, reason: &str) {
+    BREAKPOINTS.lock().disarm(&mut KernelMemory);
+    STEPPING.store(false, Ordering::Relaxed);
+    frame.rflags &= !TRAP_FLAG;
+    println!("{} at {:#x} in {}", reason, frame.rip, Symbolized(frame.rip));
+
+    // The interrupt gate disabled interrupts, the keyboard needs them
+    interrupts::enable();
+    loop {
+        print!("kdebug (stopped)> ");
+        let mut command = [0; super::LINE_LENGTH];
+        match super::read_command(&mut command) {
+            b"step" | b"s" => {
+                STEPPING.store(true, Ordering::Relaxed);
+                frame.rflags |= TRAP_FLAG;
+                break;
+            },
+            b"continue" | b"c" => {
+                let mut breakpoints = BREAKPOINTS.lock();
+                if breakpoints.contains(frame.rip) {
+                    STEPPING_OVER.store(true, Ordering::Relaxed);
+                    frame.rflags |= TRAP_FLAG;
+                } else {
+                    breakpoints.arm(&mut KernelMemory);
+                }
+                break;
+            },
+            b"regs" => print_registers(frame),
+            b"help" => {
+                println!("step, continue, regs");
+                super::handle_cmd(b"help");
+            },
+            cmd => super::handle_cmd(cmd),
+        }
+    }
+    interrupts::disable();
+}
+
+fn print_registers(frame: &TrapFrame) {
+    let registers = [
+        ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx), ("rdx", frame.rdx),
+        ("rsi", frame.rsi), ("rdi", frame.rdi), ("rbp", frame.rbp), ("rsp", frame.rsp),
+        ("r8", frame.r8), ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11),
+        ("r12", frame.r12), ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15),
+        ("rip", frame.rip), ("rflags", frame.rflags), ("cs", frame.cs), ("ss", frame.ss),
+    ];
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            print!("{:>6} {:#018x}  ", name, value);
+        }
+        println!();
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    struct TestMemory([u8; 32]);
+
+    impl Memory for TestMemory {
+        fn read(&mut self, addr: u64) -> Option<u8> {
+            self.0.get(addr as usize).copied()
+        }
+
+        fn write(&mut self, addr: u64, value: u8) -> bool {
+            self.0.get_mut(addr as usize).map(|b| *b = value).is_some()
+        }
+    }
+
+    #[test]
+    fn arm_and_disarm() {
+        let mut memory = TestMemory([0x90; 32]);
+        let mut breakpoints = Breakpoints { slots: [None; MAX_BREAKPOINTS], armed: true };
+        assert_eq!(breakpoints.insert(2, &mut memory), Ok(()));
+        assert_eq!(breakpoints.insert(32, &mut memory), Err(BreakError::Memory));
+        assert_eq!(memory.0[2], INT3);
+
+        breakpoints.disarm(&mut memory);
+        assert_eq!(memory.0[2], 0x90);
+        // Inserted while stopped, written when continuing
+        breakpoints.insert(5, &mut memory).unwrap();
+        assert_eq!(memory.0[5], 0x90);
+        breakpoints.arm(&mut memory);
+        assert_eq!((memory.0[2], memory.0[5]), (INT3, INT3));
+
+        assert!(breakpoints.remove(5, &mut memory));
+        assert!(!breakpoints.remove(5, &mut memory));
+        assert_eq!(memory.0[5], 0x90);
+    }
+
+    #[test]
+    fn full() {
+        let mut memory = TestMemory([0; 32]);
+        let mut breakpoints = Breakpoints { slots: [None; MAX_BREAKPOINTS], armed: false };
+        for addr in 0..MAX_BREAKPOINTS as u64 {
+            breakpoints.insert(addr, &mut memory).unwrap();
+        }
+        assert_eq!(breakpoints.insert(3, &mut memory), Ok(()));
+        assert_eq!(breakpoints.insert(20, &mut memory), Err(BreakError::Full));
+    }
+}
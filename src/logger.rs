@@ -0,0 +1,298 @@
+//! Kernel log, a [log] implementation that keeps the records in a ring buffer.
+//! Records up to the console log level (see the `loglevel=` boot option) are also printed.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+
+use crate::{emergency, interrupts, vga};
+
+/// Size of the ring buffer, the oldest records are dropped when it is full
+const BUFFER_SIZE: usize = 16 * 1024;
+/// Longer messages are truncated
+pub const MAX_MESSAGE: usize = 512;
+/// Microseconds since boot, the level and the length of the message
+const HEADER_SIZE: usize = 8 + 1 + 2;
+
+static LOGGER: Logger = Logger;
+static BUFFER: Mutex<LogBuffer<BUFFER_SIZE>> = Mutex::new(LogBuffer::new());
+/// The highest level printed to the screen, a [LevelFilter] as usize
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// Install the logger, records are kept at every level
+pub fn init() {
+    log::set_logger(&LOGGER).expect("Logger is already set");
+    log::set_max_level(LevelFilter::Trace);
+}
+
+pub fn set_console_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+fn console_level() -> LevelFilter {
+    match CONSOLE_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Call `f` with every record in the log, oldest first
+pub fn for_each(mut f: impl FnMut(&Entry)) {
+    // Copy the records one at a time, so the lock is not held while `f` runs
+    let mut position = 0;
+    loop {
+        let entry = x86_64::instructions::interrupts::without_interrupts(|| {
+            BUFFER.lock().next_entry(&mut position)
+        });
+        match entry {
+            Some(entry) => f(&entry),
+            None => break,
+        }
+    }
+}
+
+/// Call `f` with the last `count` records, oldest first.
+/// Does nothing and returns false if the log is locked, so it is safe to call when panicking.
+pub fn tail(count: usize, mut f: impl FnMut(&Entry)) -> bool {
+    let Some(buffer) = BUFFER.try_lock() else { return false };
+    let mut position = 0;
+    let mut total = 0;
+    while buffer.next_entry(&mut position).is_some() {
+        total += 1;
+    }
+    position = 0;
+    let mut i = 0;
+    while let Some(entry) = buffer.next_entry(&mut position) {
+        if i + count >= total {
+            f(&entry);
+        }
+        i += 1;
+    }
+    true
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut message = TruncatingWriter { buffer: [0; MAX_MESSAGE], len: 0 };
+        // The writer never fails, it truncates
+        write!(message, "{}", record.args()).unwrap();
+        let timestamp = interrupts::uptime().as_micros() as u64;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            emergency::lock(&BUFFER).push(timestamp, record.level(), message.as_str());
+        });
+
+        if record.level() <= console_level() {
+            match (record.level(), record.target()) {
+                (Level::Error, "exception") => vga::print_err("EXCEPTION"),
+                (Level::Error, _) => vga::print_err("ERR"),
+                (Level::Warn, _) => vga::print_err("WARNING"),
+                _ => {},
+            }
+            vga::print_args(format_args!("{}\n", record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Formats into a fixed buffer, cutting off what does not fit at a character boundary
+struct TruncatingWriter {
+    buffer: [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+impl TruncatingWriter {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Write for TruncatingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MAX_MESSAGE - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// A record copied out of the log
+pub struct Entry {
+    /// Microseconds since boot
+    pub timestamp: u64,
+    pub level: Level,
+    message: [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+impl Entry {
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or("<invalid>")
+    }
+}
+
+impl fmt::Display for Entry {
+    /// Like dmesg, `[    1.234567] WARN  message`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:5}.{:06}] {:5} {}", self.timestamp / 1_000_000, self.timestamp % 1_000_000, self.level, self.message())
+    }
+}
+
+/// Records packed into a ring of bytes, each is a header followed by the message
+pub struct LogBuffer<const N: usize> {
+    data: [u8; N],
+    /// Offset of the oldest record
+    start: usize,
+    len: usize,
+    /// Bytes of records dropped so far
+    dropped: usize,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        Self { data: [0; N], start: 0, len: 0, dropped: 0 }
+    }
+
+    /// Append a record, dropping the oldest ones to make room.
+    /// The message has to fit in the buffer and in [MAX_MESSAGE].
+    pub fn push(&mut self, timestamp: u64, level: Level, message: &str) {
+        let size = HEADER_SIZE + message.len();
+        assert!(size <= N && message.len() <= MAX_MESSAGE, "Log message too long");
+        while N - self.len < size {
+            let (_, _, len) = self.header(self.start);
+            self.start = (self.start + HEADER_SIZE + len) % N;
+            self.len -= HEADER_SIZE + len;
+            self.dropped += HEADER_SIZE + len;
+        }
+        let mut header = [0; HEADER_SIZE];
+        header[0..8].copy_from_slice(&timestamp.to_le_bytes());
+        header[8] = level as u8;
+        header[9..11].copy_from_slice(&(message.len() as u16).to_le_bytes());
+        let end = (self.start + self.len) % N;
+        self.write(end, &header);
+        self.write((end + HEADER_SIZE) % N, message.as_bytes());
+        self.len += size;
+    }
+
+    /// Copy the record at `position` and advance it to the next one, start with 0.
+    /// Positions count every byte ever pushed, so records dropped in between are skipped.
+    pub fn next_entry(&self, position: &mut usize) -> Option<Entry> {
+        let offset = position.saturating_sub(self.dropped);
+        if offset >= self.len {
+            return None;
+        }
+        let at = (self.start + offset) % N;
+        let (timestamp, level, len) = self.header(at);
+        let mut entry = Entry { timestamp, level, message: [0; MAX_MESSAGE], len };
+        self.read((at + HEADER_SIZE) % N, &mut entry.message[..len]);
+        *position = self.dropped + offset + HEADER_SIZE + len;
+        Some(entry)
+    }
+
+    fn header(&self, at: usize) -> (u64, Level, usize) {
+        let mut header = [0; HEADER_SIZE];
+        self.read(at, &mut header);
+        let level = match header[8] {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        };
+        (
+            u64::from_le_bytes(header[0..8].try_into().unwrap()),
+            level,
+            u16::from_le_bytes(header[9..11].try_into().unwrap()) as usize,
+        )
+    }
+
+    fn write(&mut self, at: usize, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.data[(at + i) % N] = b;
+        }
+    }
+
+    fn read(&self, at: usize, bytes: &mut [u8]) {
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.data[(at + i) % N];
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn entries<const N: usize>(buffer: &LogBuffer<N>) -> Vec<(u64, Level, String)> {
+        let mut position = 0;
+        core::iter::from_fn(|| buffer.next_entry(&mut position))
+            .map(|e| (e.timestamp, e.level, e.message().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn push_and_read() {
+        let mut buffer = LogBuffer::<256>::new();
+        assert!(entries(&buffer).is_empty());
+        buffer.push(1, Level::Warn, "first");
+        buffer.push(2_500_000, Level::Error, "second");
+        assert_eq!(entries(&buffer), [
+            (1, Level::Warn, "first".to_string()),
+            (2_500_000, Level::Error, "second".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn drops_oldest() {
+        // Room for two records of 20 bytes
+        let mut buffer = LogBuffer::<{ 2 * (HEADER_SIZE + 20) + 5 }>::new();
+        let message = |i: u64| format!("{:020}", i);
+        for i in 0..10 {
+            buffer.push(i, Level::Info, &message(i));
+            let entries = entries(&buffer);
+            assert_eq!(entries.last(), Some(&(i, Level::Info, message(i))));
+            assert!(entries.len() <= 2);
+        }
+        assert_eq!(entries(&buffer).len(), 2);
+    }
+
+    #[test]
+    fn position_skips_dropped() {
+        let mut buffer = LogBuffer::<{ 2 * (HEADER_SIZE + 1) }>::new();
+        buffer.push(0, Level::Info, "a");
+        buffer.push(1, Level::Info, "b");
+        let mut position = 0;
+        assert_eq!(buffer.next_entry(&mut position).unwrap().message(), "a");
+        // Drops a and b while reading
+        buffer.push(2, Level::Info, "c");
+        buffer.push(3, Level::Info, "d");
+        assert_eq!(buffer.next_entry(&mut position).unwrap().message(), "c");
+        assert_eq!(buffer.next_entry(&mut position).unwrap().message(), "d");
+        assert!(buffer.next_entry(&mut position).is_none());
+    }
+
+    #[test]
+    fn truncate() {
+        let mut writer = TruncatingWriter { buffer: [0; MAX_MESSAGE], len: 0 };
+        write!(writer, "{}", "a".repeat(MAX_MESSAGE - 1)).unwrap();
+        write!(writer, "é").unwrap();
+        assert_eq!(writer.as_str().len(), MAX_MESSAGE - 1);
+        let entry = Entry { timestamp: 1_234_567, level: Level::Warn, message: [b'x'; MAX_MESSAGE], len: 3 };
+        assert_eq!(format!("{}", entry), "[    1.234567] WARN  xxx");
+    }
+}
@@ -5,6 +5,8 @@ use core::ptr::addr_of;
 use core::ffi::CStr;
 use core::mem;
 
+use crate::bootinfo::{BootInfo, BootModule, ColorField, Framebuffer, FramebufferFormat, KernelSections, MemoryKind, MemoryRegion};
+
 #[repr(C)]
 pub struct BootInformation {
     /// `total_size` contains the total size of boot information including this field and terminating tag in bytes.
@@ -74,19 +76,6 @@ pub struct ElfSection {
     pub entsize: usize,
 }
 
-impl ElfSection {
-    /// Attempt to get the name of this section
-    /// by finding the .shstrtab section
-    pub fn get_name(&self, elf: &'static ElfSymbol) -> Option<&'static str> {
-        if elf.shndx > 0 && self.name > 0 {
-            let section = &elf.sections()[elf.shndx as usize];
-            unsafe { CStr::from_ptr((section.addr + self.name as usize) as *const i8).to_str().ok() }
-        } else {
-            None
-        }
-    }
-}
-
 #[derive(Debug)]
 #[repr(C)]
 /// Image load base physical address.
@@ -96,27 +85,72 @@ pub struct ImageLoadBase {
     pub load_base_addr: u32,
 }
 
+/// A boot module loaded by the bootloader
+#[repr(C)]
+pub struct Module {
+    /// Physical address of the first byte of the module
+    pub mod_start: u32,
+    /// Physical address of the first byte after the module
+    pub mod_end: u32,
+    /// Zero terminated command line of the module
+    string: [u8],
+}
+
+impl Module {
+    pub fn cmdline(&self) -> &CStr {
+        CStr::from_bytes_until_nul(&self.string).unwrap_or_default()
+    }
+
+    /// The contents of the module
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.mod_start as *const u8, (self.mod_end - self.mod_start) as usize) }
+    }
+}
+
+impl core::fmt::Debug for Module {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Module")
+        .field("mod_start", &format_args!("{:#x}", self.mod_start))
+        .field("mod_end", &format_args!("{:#x}", self.mod_end))
+        .field("cmdline", &self.cmdline())
+        .finish()
+    }
+}
+
 #[derive(Debug)]
 #[repr(u32)]
 pub enum Tag {
     End = 0,
     BootCommandLine(&'static CStr) = 1,
     BootLoaderName(&'static CStr) = 2,
+    Module(&'static Module) = 3,
     BasicMemInfo(&'static BasicMemInfo) = 4,
     BIOSBootDevice(&'static BIOSBootDevice) = 5,
     MemoryMap(&'static MemoryMap) = 6,
     FrameBufferInfo(&'static FrameBufferInfo) = 8,
     ElfSymbol(&'static ElfSymbol) = 9,
     APMTable(&'static APMTable) = 10,
+    /// Physical address of the 32-bit EFI system table
+    EFI32SystemTable(u32) = 11,
+    /// Physical address of the 64-bit EFI system table
+    EFI64SystemTable(u64) = 12,
     ACPIOldRSDP(&'static [u8]) = 14,
     ACPINewRSDP(&'static [u8]) = 15,
     NetworkInfo(&'static [u8]) = 16,
+    EFIMemoryMap(&'static EFIMemoryMap) = 17,
+    /// Present if the bootloader did not call ExitBootServices
+    EFIBootServicesNotTerminated = 18,
+    /// Pointer to the 32-bit EFI image handle
+    EFI32ImageHandle(u32) = 19,
+    /// Pointer to the 64-bit EFI image handle
+    EFI64ImageHandle(u64) = 20,
     ImageLoadBase(&'static ImageLoadBase) = 21,
     /// Any tag not explicitly added here. With type
     Unknown(u32, &'static [u8]),
 }
 
-#[derive(Debug)]
+// The spec lists a u8 as reserved field, but GRUB uses a u16.
+// So color_info starts at offset 24 (32 including the tag header).
 #[repr(C)]
 pub struct FrameBufferInfo {
     pub framebuffer_addr: u64,
@@ -125,11 +159,83 @@ pub struct FrameBufferInfo {
     pub framebuffer_height: u32,
     pub framebuffer_bpp: u8,
     pub framebuffer_type: u8,
-    _reserved: u8,
-    /// This still has to be expanded
+    _reserved: u16,
+    /// Use [FrameBufferInfo::color_info] to decode this
     color_info: [u8],
 }
 
+/// Size of [FrameBufferInfo] without the color info
+const FRAMEBUFFER_INFO_SIZE: usize = 24;
+
+/// How colors are described, based on `framebuffer_type`
+#[derive(Debug)]
+pub enum ColorInfo {
+    /// Type 0, the palette
+    Indexed(&'static [PaletteEntry]),
+    /// Type 1, direct RGB
+    Rgb {
+        red_field_position: u8,
+        red_mask_size: u8,
+        green_field_position: u8,
+        green_mask_size: u8,
+        blue_field_position: u8,
+        blue_mask_size: u8,
+    },
+    /// Type 2, EGA text mode, there is no color info
+    EgaText,
+    /// Unknown type, or the color info is too short for the type
+    Unknown(u8, &'static [u8]),
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct PaletteEntry {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl FrameBufferInfo {
+    pub fn color_info(&'static self) -> ColorInfo {
+        let info = &self.color_info;
+        match self.framebuffer_type {
+            0 if info.len() >= 2 => {
+                let num_colors = u16::from_le_bytes([info[0], info[1]]) as usize;
+                let num_colors = core::cmp::min(num_colors, (info.len() - 2) / mem::size_of::<PaletteEntry>());
+                ColorInfo::Indexed(unsafe {
+                    core::slice::from_raw_parts(info[2..].as_ptr() as *const PaletteEntry, num_colors)
+                })
+            },
+            1 if info.len() >= 6 => ColorInfo::Rgb {
+                red_field_position: info[0],
+                red_mask_size: info[1],
+                green_field_position: info[2],
+                green_mask_size: info[3],
+                blue_field_position: info[4],
+                blue_mask_size: info[5],
+            },
+            2 => ColorInfo::EgaText,
+            type_ => ColorInfo::Unknown(type_, info),
+        }
+    }
+}
+
+impl core::fmt::Debug for FrameBufferInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Safe as tags always live in the static MBI
+        let this: &'static Self = unsafe { &*(self as *const Self) };
+        f.debug_struct("FrameBufferInfo")
+        .field("framebuffer_addr", &format_args!("{:#x}", self.framebuffer_addr))
+        .field("framebuffer_pitch", &self.framebuffer_pitch)
+        .field("framebuffer_width", &self.framebuffer_width)
+        .field("framebuffer_height", &self.framebuffer_height)
+        .field("framebuffer_bpp", &self.framebuffer_bpp)
+        .field("framebuffer_type", &self.framebuffer_type)
+        .field("color_info", &this.color_info())
+        .finish()
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct BasicMemInfo {
@@ -163,6 +269,61 @@ pub struct MemoryMapEntry {
     _reserved: u32,
 }
 
+/// The memory map as given by EFI GetMemoryMap()
+#[repr(C)]
+pub struct EFIMemoryMap {
+    /// Size of a descriptor, this may be bigger than [EFIMemoryDescriptor]
+    pub descriptor_size: u32,
+    pub descriptor_version: u32,
+    descriptors: [u8],
+}
+
+impl EFIMemoryMap {
+    pub fn descriptors(&'static self) -> impl Iterator<Item = &'static EFIMemoryDescriptor> {
+        let size = self.descriptor_size as usize;
+        let count = if size >= mem::size_of::<EFIMemoryDescriptor>() { self.descriptors.len() / size } else { 0 };
+        let start = self.descriptors.as_ptr();
+        (0..count).map(move |i| unsafe { &*(start.add(i * size) as *const EFIMemoryDescriptor) })
+    }
+}
+
+impl core::fmt::Debug for EFIMemoryMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EFIMemoryMap")
+        .field("descriptor_size", &self.descriptor_size)
+        .field("descriptor_version", &self.descriptor_version)
+        .field("descriptors", &(self.descriptors.len() / self.descriptor_size.max(1) as usize))
+        .finish()
+    }
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct EFIMemoryDescriptor {
+    pub type_: u32,
+    _padding: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+impl EFIMemoryDescriptor {
+    /// EfiConventionalMemory
+    pub const CONVENTIONAL: u32 = 7;
+
+    /// If the memory can be used once boot services are terminated.
+    /// This excludes memory used by the bootloader itself (EfiLoaderCode and EfiLoaderData).
+    pub fn is_usable(&self, boot_services_terminated: bool) -> bool {
+        match self.type_ {
+            Self::CONVENTIONAL => true,
+            // EfiBootServicesCode and EfiBootServicesData
+            3 | 4 => boot_services_terminated,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct APMTable {
@@ -177,32 +338,96 @@ pub struct APMTable {
     pub dseg_len: u16
 }
 
+/// Problems with the multiboot information given by the bootloader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MbiError {
+    /// The pointer to the multiboot information is null
+    Null,
+    /// The multiboot information is not aligned to 8 bytes
+    Misaligned(usize),
+    /// `total_size` is too small to hold the header and end tag
+    TooSmall(u32),
+    /// A tag has an invalid size, or goes beyond `total_size`
+    TruncatedTag { offset: usize, type_: u32, size: u32 },
+    /// A string in a tag is not zero terminated
+    BadString { offset: usize, type_: u32 },
+    /// A module ends before it starts
+    BadModule { offset: usize, start: u32, end: u32 },
+    /// The tags ended without an end tag
+    NoEndTag,
+}
+
+impl core::fmt::Display for MbiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MbiError::Null => write!(f, "multiboot information pointer is null"),
+            MbiError::Misaligned(addr) => write!(f, "multiboot information at {:#x} is not 8 byte aligned", addr),
+            MbiError::TooSmall(size) => write!(f, "multiboot information total_size {:#x} is too small", size),
+            MbiError::TruncatedTag { offset, type_, size } => write!(f, "tag type {} at offset {:#x} has invalid size {:#x}", type_, offset, size),
+            MbiError::BadString { offset, type_ } => write!(f, "tag type {} at offset {:#x} has an unterminated string", type_, offset),
+            MbiError::BadModule { offset, start, end } => write!(f, "module at offset {:#x} ends at {:#x} before its start {:#x}", offset, end, start),
+            MbiError::NoEndTag => write!(f, "multiboot information has no end tag"),
+        }
+    }
+}
+
 pub struct TagIter {
     mbi: &'static BootInformation,
     i: usize,
+    /// Set after the end tag or an error
+    done: bool,
 }
 
 impl Iterator for TagIter {
-    type Item = Tag;
+    type Item = Result<Tag, MbiError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i >= self.mbi.total_size as usize - 8 {
-            panic!("Have not found end tag")
+        if self.done {
+            return None;
         }
+        match self.parse_next() {
+            Ok(Some(tag)) => Some(Ok(tag)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl TagIter {
+    /// Parse the tag at `self.i`, returns None on the end tag
+    fn parse_next(&mut self) -> Result<Option<Tag>, MbiError> {
+        let tags = &self.mbi.tags;
 
         // Align to an 8th byte
         if self.i % 8 != 0  {
             self.i += 8 - (self.i % 8);
         }
 
-        let type_ = u32::from_le_bytes(self.mbi.tags[self.i..self.i+4].try_into().unwrap());
-        let size = u32::from_le_bytes(self.mbi.tags[self.i+4..self.i+8].try_into().unwrap()) as usize - 8;
+        let offset = self.i;
+        if offset + 8 > tags.len() {
+            return Err(MbiError::NoEndTag);
+        }
+
+        let type_ = u32::from_le_bytes(tags[offset..offset+4].try_into().unwrap());
+        let tag_size = u32::from_le_bytes(tags[offset+4..offset+8].try_into().unwrap());
+        let truncated = MbiError::TruncatedTag { offset, type_, size: tag_size };
+        if (tag_size as usize) < 8 || tag_size as usize > tags.len() - offset {
+            return Err(truncated);
+        }
+        let size = tag_size as usize - 8;
+        let data: &'static [u8] = &tags[offset+8..offset+8+size];
 
         if type_ == 0 {
-            return None
+            return Ok(None)
         }
 
-        let addr = addr_of!(self.mbi.tags) as *const () as usize + (self.i + 8);
+        let addr = data.as_ptr() as usize;
         self.i += size + 8;
 
         /*
@@ -211,102 +436,607 @@ impl Iterator for TagIter {
          * x/2wx addr+size
          */
 
-        return match type_ {
+        // Make sure the fixed part of a tag is there
+        let require = |min: usize| if size < min { Err(truncated.clone()) } else { Ok(()) };
+        let string = |bytes: &'static [u8]| CStr::from_bytes_until_nul(bytes).map_err(|_| MbiError::BadString { offset, type_ });
+
+        return Ok(Some(match type_ {
             1 => {
-                Some(Tag::BootCommandLine(unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                Tag::BootCommandLine(string(data)?)
             },
             2 => {
-                Some(Tag::BootLoaderName(unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                Tag::BootLoaderName(string(data)?)
+            },
+            3 => {
+                require(8)?;
+                string(&data[8..])?;
+                let start = u32::from_le_bytes(data[0..4].try_into().unwrap());
+                let end = u32::from_le_bytes(data[4..8].try_into().unwrap());
+                if end < start {
+                    return Err(MbiError::BadModule { offset, start, end });
+                }
+                Tag::Module(unsafe {
+                    &*ptr::from_raw_parts(addr as *const (), size - 8)
+                })
             },
             4 => {
-                Some(Tag::BasicMemInfo(unsafe {
+                require(mem::size_of::<BasicMemInfo>())?;
+                Tag::BasicMemInfo(unsafe {
                     &*ptr::from_raw_parts(addr as *const (), ())
-                }))
+                })
             },
             5 => {
-                Some(Tag::BIOSBootDevice(unsafe {
+                require(mem::size_of::<BIOSBootDevice>())?;
+                Tag::BIOSBootDevice(unsafe {
                     &*ptr::from_raw_parts(addr as *const (), ())
-                }))
+                })
             },
             6 => {
+                require(8)?;
                 let entries = (size - 8) / mem::size_of::<MemoryMapEntry>();
-                Some(Tag::MemoryMap(unsafe {
+                Tag::MemoryMap(unsafe {
                     &*ptr::from_raw_parts(addr as *const (), entries)
-                }))
+                })
             },
             8 => {
-                Some(Tag::FrameBufferInfo(unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                require(FRAMEBUFFER_INFO_SIZE)?;
+                Tag::FrameBufferInfo(unsafe {
+                    &*ptr::from_raw_parts(addr as *const (), size - FRAMEBUFFER_INFO_SIZE)
+                })
             },
             9 => {
-                Some(Tag::ElfSymbol(unsafe {
-                    &*ptr::from_raw_parts((addr) as *const (), size)
-                }))
+                require(12)?;
+                let num = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+                if num.saturating_mul(mem::size_of::<ElfSection>()) > size - 12 {
+                    return Err(truncated);
+                }
+                Tag::ElfSymbol(unsafe {
+                    &*ptr::from_raw_parts((addr) as *const (), (size - 12) / mem::size_of::<ElfSection>())
+                })
             },
             10 => {
-                Some(Tag::APMTable(unsafe {
+                require(mem::size_of::<APMTable>())?;
+                Tag::APMTable(unsafe {
                     &*ptr::from_raw_parts(addr as *const (), ())
-                }))
+                })
+            },
+            11 => {
+                require(4)?;
+                Tag::EFI32SystemTable(u32::from_le_bytes(data[0..4].try_into().unwrap()))
+            },
+            12 => {
+                require(8)?;
+                Tag::EFI64SystemTable(u64::from_le_bytes(data[0..8].try_into().unwrap()))
             },
             14 => {
-                Some(Tag::ACPIOldRSDP(unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                Tag::ACPIOldRSDP(data)
             },
             15 => {
-                Some(Tag::ACPINewRSDP(unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                Tag::ACPINewRSDP(data)
+            },
+            16 => {
+                Tag::NetworkInfo(data)
+            },
+            17 => {
+                require(8)?;
+                Tag::EFIMemoryMap(unsafe {
+                    &*ptr::from_raw_parts(addr as *const (), size - 8)
+                })
+            },
+            18 => Tag::EFIBootServicesNotTerminated,
+            19 => {
+                require(4)?;
+                Tag::EFI32ImageHandle(u32::from_le_bytes(data[0..4].try_into().unwrap()))
+            },
+            20 => {
+                require(8)?;
+                Tag::EFI64ImageHandle(u64::from_le_bytes(data[0..8].try_into().unwrap()))
             },
             21 => {
-                Some(Tag::ImageLoadBase(unsafe {
+                require(mem::size_of::<ImageLoadBase>())?;
+                Tag::ImageLoadBase(unsafe {
                     &*ptr::from_raw_parts(addr as *const (), ())
-                }))
+                })
             },
             _ => {
-                // panic!(
-                //     "Unknown tag with type {} and size {:#x} at {:#x}",
-                //     type_,
-                //     size,
-                //     addr_of!(self.mbi.tags) as *const () as usize + self.i
-                // );
-                Some(Tag::Unknown(type_, unsafe {
-                    &*ptr::from_raw_parts(addr as *const (), size)
-                }))
+                Tag::Unknown(type_, data)
             }
-        }
+        }))
     }
 }
 
 impl BootInformation {
-    pub fn load(ptr: *const BootInformation) -> &'static Self {
+    /// Load and validate the multiboot information.
+    /// Every tag is checked here, so the iterators on [BootInformation] can skip errors.
+    pub fn load(ptr: *const BootInformation) -> Result<&'static Self, MbiError> {
+        if ptr.is_null() {
+            return Err(MbiError::Null);
+        }
+        if ptr as *const () as usize % 8 != 0 {
+            return Err(MbiError::Misaligned(ptr as *const () as usize));
+        }
         let total_size = unsafe {*(ptr as *const u32)};
-        unsafe {&*ptr::from_raw_parts(ptr as *const (), total_size as usize)}
+        // The header and an end tag
+        if total_size < 16 {
+            return Err(MbiError::TooSmall(total_size));
+        }
+        let mbi: &'static Self = unsafe {&*ptr::from_raw_parts(ptr as *const (), total_size as usize - 8)};
+        for tag in mbi.tags() {
+            tag?;
+        }
+        Ok(mbi)
     }
 
     /// Iterate over the tags
     pub fn tags(&'static self) -> TagIter {
-        TagIter { mbi: &self, i: 0 }
+        TagIter { mbi: &self, i: 0, done: false }
+    }
+
+    /// Iterate over the tags, stopping at the first invalid one
+    fn valid_tags(&'static self) -> impl Iterator<Item = Tag> {
+        self.tags().map_while(Result::ok)
     }
 
     pub fn bootloader_name(&'static self) -> Option<&'static CStr> {
-        self.tags().find_map(|t| if let Tag::BootLoaderName(bln) = t {Some(bln)} else {None})
+        self.valid_tags().find_map(|t| if let Tag::BootLoaderName(bln) = t {Some(bln)} else {None})
     }
 
     pub fn boot_command_line(&'static self) -> Option<&'static CStr> {
-        self.tags().find_map(|t| if let Tag::BootCommandLine(bcl) = t {Some(bcl)} else {None})
+        self.valid_tags().find_map(|t| if let Tag::BootCommandLine(bcl) = t {Some(bcl)} else {None})
     }
 
     pub fn memory_map(&'static self) -> Option<&'static MemoryMap> {
-        self.tags().find_map(|t| if let Tag::MemoryMap(mm) = t {Some(mm)} else {None})
+        self.valid_tags().find_map(|t| if let Tag::MemoryMap(mm) = t {Some(mm)} else {None})
+    }
+
+    pub fn efi_memory_map(&'static self) -> Option<&'static EFIMemoryMap> {
+        self.valid_tags().find_map(|t| if let Tag::EFIMemoryMap(mm) = t {Some(mm)} else {None})
+    }
+
+    pub fn efi_boot_services_terminated(&'static self) -> bool {
+        !self.valid_tags().any(|t| matches!(t, Tag::EFIBootServicesNotTerminated))
+    }
+
+    pub fn modules(&'static self) -> impl Iterator<Item = &'static Module> {
+        self.valid_tags().filter_map(|t| if let Tag::Module(m) = t {Some(m)} else {None})
+    }
+
+    /// A copy of the ACPI RSDP, the new one (ACPI 2.0+) is preferred
+    pub fn rsdp(&'static self) -> Option<&'static [u8]> {
+        self.valid_tags().find_map(|t| if let Tag::ACPINewRSDP(r) = t {Some(r)} else {None})
+            .or_else(|| self.valid_tags().find_map(|t| if let Tag::ACPIOldRSDP(r) = t {Some(r)} else {None}))
+    }
+
+    pub fn framebuffer_info(&'static self) -> Option<&'static FrameBufferInfo> {
+        self.valid_tags().find_map(|t| if let Tag::FrameBufferInfo(fb) = t {Some(fb)} else {None})
     }
 
     pub fn elf_symbols(&'static self) -> impl Iterator<Item = &'static ElfSymbol> {
-        self.tags().filter_map(|t| if let Tag::ElfSymbol(es) = t {Some(es)} else {None})
+        self.valid_tags().filter_map(|t| if let Tag::ElfSymbol(es) = t {Some(es)} else {None})
+    }
+}
+
+impl BootInformation {
+    /// Fill in the bootloader agnostic [BootInfo]
+    pub fn boot_info(&'static self) -> BootInfo {
+        let elf = self.elf_symbols().next().expect("No ELF symbols");
+        let mut info = BootInfo::new(KernelSections::new(elf.sections(), elf.shndx as usize));
+
+        info.loader_name = self.bootloader_name().and_then(|n| n.to_str().ok()).unwrap_or_default();
+        info.cmdline = self.boot_command_line().and_then(|c| c.to_str().ok()).unwrap_or_default();
+
+        if let Some(memory_map) = self.memory_map() {
+            for entry in &memory_map.entries {
+                let kind = match entry.type_ {
+                    1 => MemoryKind::Usable,
+                    3 => MemoryKind::AcpiReclaimable,
+                    4 => MemoryKind::AcpiNvs,
+                    5 => MemoryKind::Bad,
+                    _ => MemoryKind::Reserved,
+                };
+                let Some(end) = entry.base_addr.checked_add(entry.length) else {
+                    wprintln!("Skipping memory map entry {:#x} with length {:#x} past the end of memory", entry.base_addr, entry.length);
+                    continue;
+                };
+                info.add_memory(MemoryRegion { start: entry.base_addr, end, kind });
+            }
+        } else if let Some(memory_map) = self.efi_memory_map() {
+            let terminated = self.efi_boot_services_terminated();
+            for descriptor in memory_map.descriptors() {
+                let kind = match descriptor.type_ {
+                    _ if descriptor.is_usable(terminated) => MemoryKind::Usable,
+                    // EfiUnusableMemory, EfiACPIReclaimMemory and EfiACPIMemoryNVS
+                    8 => MemoryKind::Bad,
+                    9 => MemoryKind::AcpiReclaimable,
+                    10 => MemoryKind::AcpiNvs,
+                    _ => MemoryKind::Reserved,
+                };
+                let start = descriptor.physical_start;
+                let Some(end) = descriptor.number_of_pages.checked_mul(0x1000).and_then(|len| start.checked_add(len)) else {
+                    wprintln!("Skipping EFI memory descriptor {:#x} with {:#x} pages past the end of memory", start, descriptor.number_of_pages);
+                    continue;
+                };
+                info.add_memory(MemoryRegion { start, end, kind });
+            }
+        }
+
+        for module in self.modules() {
+            info.add_module(BootModule {
+                start: module.mod_start as u64,
+                end: module.mod_end as u64,
+                cmdline: module.cmdline().to_str().unwrap_or_default(),
+            });
+        }
+
+        info.framebuffer = self.framebuffer_info().and_then(|fb| {
+            let format = match fb.color_info() {
+                ColorInfo::Indexed(palette) => FramebufferFormat::Indexed(unsafe {
+                    core::slice::from_raw_parts(palette.as_ptr() as *const [u8; 3], palette.len())
+                }),
+                ColorInfo::Rgb { red_field_position, red_mask_size, green_field_position, green_mask_size, blue_field_position, blue_mask_size } => FramebufferFormat::Rgb {
+                    red: ColorField { position: red_field_position, size: red_mask_size },
+                    green: ColorField { position: green_field_position, size: green_mask_size },
+                    blue: ColorField { position: blue_field_position, size: blue_mask_size },
+                },
+                ColorInfo::EgaText => FramebufferFormat::EgaText,
+                ColorInfo::Unknown(..) => return None,
+            };
+            Some(Framebuffer {
+                addr: fb.framebuffer_addr,
+                pitch: fb.framebuffer_pitch,
+                width: fb.framebuffer_width,
+                height: fb.framebuffer_height,
+                bpp: fb.framebuffer_bpp,
+                format,
+            })
+        });
+
+        info.rsdp = self.rsdp();
+
+        let start = addr_of!(*self) as *const u8 as u64;
+        info.reserved = start..start + self.total_size as u64;
+
+        info
+    }
+}
+
+/// Writes multiboot2 tags into a buffer, to translate other boot protocols
+pub struct TagWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    has_elf_symbols: bool,
+}
+
+impl<'a> TagWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        // Room for total_size and the reserved field
+        Self { buffer, len: 8, has_elf_symbols: false }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            panic!("Translated multiboot information does not fit in {:#x} bytes", self.buffer.len());
+        }
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+    }
+
+    /// Write a tag, `f` writes its contents
+    pub fn tag(&mut self, type_: u32, f: impl FnOnce(&mut Self)) {
+        self.has_elf_symbols |= type_ == 9;
+        let start = self.len;
+        self.write(&type_.to_le_bytes());
+        self.write(&0u32.to_le_bytes());
+        f(self);
+        let size = (self.len - start) as u32;
+        self.buffer[start+4..start+8].copy_from_slice(&size.to_le_bytes());
+        while self.len % 8 != 0 {
+            self.write(&[0]);
+        }
+    }
+
+    /// Describe the loaded kernel as a single ELF section,
+    /// for protocols that do not pass the section headers
+    fn kernel_image(&mut self) {
+        extern "C" {
+            static __kernel_start: u8;
+            static __bss_end: u8;
+        }
+        let start = addr_of!(__kernel_start) as usize;
+        let end = addr_of!(__bss_end) as usize;
+        let image = ElfSection {
+            name: 0,
+            type_: 1, // SHT_PROGBITS
+            flags: 0x7, // SHF_WRITE | SHF_ALLOC | SHF_EXECINSTR
+            addr: start,
+            offset: 0,
+            size: end - start,
+            link: 0,
+            info: 0,
+            addr_align: 0x1000,
+            entsize: 0,
+        };
+        let size = mem::size_of::<ElfSection>();
+        self.tag(9, |w| {
+            w.write(&2u32.to_le_bytes());
+            w.write(&(size as u32).to_le_bytes());
+            w.write(&0u32.to_le_bytes());
+            // The first section is always the null section
+            w.write(&[0; mem::size_of::<ElfSection>()]);
+            w.write(unsafe { core::slice::from_raw_parts(addr_of!(image) as *const u8, size) });
+        });
+    }
+
+    /// Write the end tag and total size, returns the total size
+    pub fn finish(mut self) -> usize {
+        self.tag(0, |_| ());
+        let total_size = self.len as u32;
+        self.buffer[0..4].copy_from_slice(&total_size.to_le_bytes());
+        self.len
+    }
+}
+
+/// Buffer for multiboot information translated from other boot protocols,
+/// multiboot2 tags are 8 byte aligned
+static mut TRANSLATED: [u64; 2048] = [0; 2048];
+
+/// Let `f` write translated multiboot information with a [TagWriter].
+/// Returns a pointer for [BootInformation::load].
+pub fn translate(f: impl FnOnce(&mut TagWriter)) -> *const BootInformation {
+    let buffer = unsafe { &mut *ptr::addr_of_mut!(TRANSLATED) };
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, mem::size_of_val(buffer)) };
+    let mut writer = TagWriter::new(buffer);
+    f(&mut writer);
+    if !writer.has_elf_symbols {
+        writer.kernel_image();
+    }
+    writer.finish();
+    ptr::from_raw_parts(buffer.as_ptr() as *const (), 0)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Build a multiboot information structure out of `(type, data)` tags.
+    /// An end tag is added if `end` is set.
+    fn build(tags: &[(u32, &[u8])], end: bool) -> Vec<u8> {
+        let mut bytes = vec![0u8; 8];
+        for (type_, data) in tags {
+            bytes.extend(type_.to_le_bytes());
+            bytes.extend((data.len() as u32 + 8).to_le_bytes());
+            bytes.extend(*data);
+            while bytes.len() % 8 != 0 {
+                bytes.push(0);
+            }
+        }
+        if end {
+            bytes.extend(0u32.to_le_bytes());
+            bytes.extend(8u32.to_le_bytes());
+        }
+        let total_size = bytes.len() as u32;
+        bytes[0..4].copy_from_slice(&total_size.to_le_bytes());
+        bytes
+    }
+
+    /// Leak `bytes` into 8 byte aligned memory
+    fn leak(bytes: &[u8]) -> *const BootInformation {
+        let words: &'static mut [u64] = Vec::leak(vec![0u64; (bytes.len() + 7) / 8 + 1]);
+        let ptr = words.as_mut_ptr() as *mut u8;
+        unsafe { ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len()) };
+        ptr::from_raw_parts(ptr as *const (), 0)
+    }
+
+    fn load(tags: &[(u32, &[u8])]) -> Result<&'static BootInformation, MbiError> {
+        BootInformation::load(leak(&build(tags, true)))
+    }
+
+    fn load_err(ptr: *const BootInformation) -> MbiError {
+        BootInformation::load(ptr).err().expect("Multiboot information should be invalid")
+    }
+
+    #[test]
+    fn strings() {
+        let mbi = load(&[(1, b"no welcome\0"), (2, b"GRUB 2.12\0")]).unwrap();
+        assert_eq!(mbi.boot_command_line().unwrap().to_str(), Ok("no welcome"));
+        assert_eq!(mbi.bootloader_name().unwrap().to_str(), Ok("GRUB 2.12"));
+        assert_eq!(mbi.tags().count(), 2);
+    }
+
+    #[test]
+    fn unterminated_string() {
+        assert_eq!(load(&[(1, b"welcome")]).err().unwrap(), MbiError::BadString { offset: 0, type_: 1 });
+    }
+
+    #[test]
+    fn null_and_misaligned() {
+        assert_eq!(load_err(ptr::from_raw_parts(ptr::null::<()>(), 0)), MbiError::Null);
+        let ptr = leak(&build(&[], true)) as *const u8;
+        let misaligned = ptr::from_raw_parts(unsafe { ptr.add(4) } as *const (), 0);
+        assert!(matches!(load_err(misaligned), MbiError::Misaligned(_)));
+    }
+
+    #[test]
+    fn too_small() {
+        let mut bytes = build(&[], true);
+        bytes[0..4].copy_from_slice(&8u32.to_le_bytes());
+        assert_eq!(load_err(leak(&bytes)), MbiError::TooSmall(8));
+    }
+
+    #[test]
+    fn no_end_tag() {
+        let bytes = build(&[(1, b"welcome\0")], false);
+        assert_eq!(load_err(leak(&bytes)), MbiError::NoEndTag);
+    }
+
+    #[test]
+    fn tag_beyond_total_size() {
+        let mut bytes = build(&[(1, b"welcome\0")], true);
+        bytes[12..16].copy_from_slice(&0x100u32.to_le_bytes());
+        assert_eq!(
+            load_err(leak(&bytes)),
+            MbiError::TruncatedTag { offset: 0, type_: 1, size: 0x100 }
+        );
+    }
+
+    #[test]
+    fn tag_size_too_small() {
+        let mut bytes = build(&[(1, b"welcome\0")], true);
+        bytes[12..16].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(load_err(leak(&bytes)), MbiError::TruncatedTag { size: 4, .. }));
+    }
+
+    #[test]
+    fn fixed_size_tag_too_small() {
+        assert!(matches!(load(&[(4, &[0; 4])]).err(), Some(MbiError::TruncatedTag { type_: 4, .. })));
+    }
+
+    #[test]
+    fn module_ends_before_start() {
+        let mut data = Vec::new();
+        data.extend(0x201000u32.to_le_bytes());
+        data.extend(0x200000u32.to_le_bytes());
+        data.extend(b"\0");
+        assert_eq!(load(&[(3, &data)]).err(), Some(MbiError::BadModule { offset: 0, start: 0x201000, end: 0x200000 }));
+    }
+
+    #[test]
+    fn errors_stop_iteration() {
+        let mut bytes = build(&[(1, b"welcome\0"), (2, b"GRUB\0")], true);
+        bytes[12..16].copy_from_slice(&0x100u32.to_le_bytes());
+        // Skip validation in load
+        let mbi: &'static BootInformation = unsafe { &*ptr::from_raw_parts(leak(&bytes) as *const (), bytes.len() - 8) };
+        let mut tags = mbi.tags();
+        assert!(tags.next().unwrap().is_err());
+        assert!(tags.next().is_none());
+        assert!(mbi.bootloader_name().is_none());
+    }
+
+    #[test]
+    fn modules() {
+        let mut data = Vec::new();
+        data.extend(0x200000u32.to_le_bytes());
+        data.extend(0x201000u32.to_le_bytes());
+        data.extend(b"/boot/hello world\0");
+        let mbi = load(&[(3, &data)]).unwrap();
+        let module = mbi.modules().next().unwrap();
+        assert_eq!((module.mod_start, module.mod_end), (0x200000, 0x201000));
+        assert_eq!(module.cmdline().to_str(), Ok("/boot/hello world"));
+    }
+
+    #[test]
+    fn boot_info() {
+        let mut elf = Vec::new();
+        elf.extend(2u32.to_le_bytes());
+        elf.extend((mem::size_of::<ElfSection>() as u32).to_le_bytes());
+        elf.extend(0u32.to_le_bytes());
+        elf.extend([0; mem::size_of::<ElfSection>()]);
+        let mut text = [0u8; mem::size_of::<ElfSection>()];
+        text[16..24].copy_from_slice(&0x100000u64.to_le_bytes());
+        text[32..40].copy_from_slice(&0x5000u64.to_le_bytes());
+        elf.extend(text);
+
+        let mut memory_map = Vec::new();
+        memory_map.extend(24u32.to_le_bytes());
+        memory_map.extend(0u32.to_le_bytes());
+        for (base, length, type_) in [(0u64, 0x9fc00u64, 1u32), (0x100000, 0x100000, 1), (0x200000, 0x100000, 1), (0x300000, 0x1000, 3), (0xffff_ffff_ffff_f000, 0x2000, 1)] {
+            memory_map.extend(base.to_le_bytes());
+            memory_map.extend(length.to_le_bytes());
+            memory_map.extend(type_.to_le_bytes());
+            memory_map.extend(0u32.to_le_bytes());
+        }
+
+        let mut module = Vec::new();
+        module.extend(0x400000u32.to_le_bytes());
+        module.extend(0x401000u32.to_le_bytes());
+        module.extend(b"/boot/hello world\0");
+
+        let mbi = load(&[(1, b"welcome=false\0"), (2, b"GRUB 2.12\0"), (3, &module), (6, &memory_map), (9, &elf)]).unwrap();
+        let info = mbi.boot_info();
+        assert_eq!(info.cmdline, "welcome=false");
+        assert_eq!(info.loader_name, "GRUB 2.12");
+        assert_eq!(info.kernel_sections.image(), 0x100000..0x105000);
+
+        // Adjacent usable regions are merged
+        let regions: Vec<_> = info.memory_regions().map(|r| (r.start, r.end, r.kind)).collect();
+        assert_eq!(regions, [
+            (0, 0x9fc00, MemoryKind::Usable),
+            (0x100000, 0x300000, MemoryKind::Usable),
+            (0x300000, 0x301000, MemoryKind::AcpiReclaimable),
+        ]);
+
+        assert!(info.module("hello").is_some());
+        assert!(info.module("/boot/hello").is_some());
+        assert!(info.module("world").is_none());
+        assert!(info.framebuffer.is_none());
+        assert!(info.rsdp.is_none());
+    }
+
+    #[test]
+    fn framebuffer_rgb() {
+        let mut data = Vec::new();
+        data.extend(0xfd000000u64.to_le_bytes());
+        data.extend((1024u32 * 4).to_le_bytes());
+        data.extend(1024u32.to_le_bytes());
+        data.extend(768u32.to_le_bytes());
+        data.extend([32, 1, 0, 0]);
+        data.extend([16, 8, 8, 8, 0, 8]);
+        let mbi = load(&[(8, &data)]).unwrap();
+        let fb = mbi.framebuffer_info().unwrap();
+        assert_eq!(fb.framebuffer_width, 1024);
+        assert!(matches!(fb.color_info(), ColorInfo::Rgb { red_field_position: 16, blue_mask_size: 8, .. }));
+    }
+
+    #[test]
+    fn efi_memory_map_stride() {
+        // OVMF uses 48 byte descriptors
+        let mut data = Vec::new();
+        data.extend(48u32.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        for (type_, start) in [(7u32, 0x100000u64), (3, 0x800000)] {
+            let mut descriptor = [0u8; 48];
+            descriptor[0..4].copy_from_slice(&type_.to_le_bytes());
+            descriptor[8..16].copy_from_slice(&start.to_le_bytes());
+            descriptor[24..32].copy_from_slice(&16u64.to_le_bytes());
+            data.extend(descriptor);
+        }
+        let mbi = load(&[(17, &data), (18, &[])]).unwrap();
+        let starts: Vec<u64> = mbi.efi_memory_map().unwrap().descriptors().map(|d| d.physical_start).collect();
+        assert_eq!(starts, [0x100000, 0x800000]);
+        assert!(!mbi.efi_boot_services_terminated());
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let mbi = load(&[(0x1234, &[1, 2, 3])]).unwrap();
+        assert!(matches!(mbi.tags().next(), Some(Ok(Tag::Unknown(0x1234, [1, 2, 3])))));
+    }
+
+    proptest! {
+        #[test]
+        fn unknown_tags_round_trip(tags in prop::collection::vec((0x100u32..0x1000, prop::collection::vec(any::<u8>(), 0..64)), 0..8)) {
+            let tags: Vec<(u32, &[u8])> = tags.iter().map(|(type_, data)| (*type_, data.as_slice())).collect();
+            let mbi = load(&tags).unwrap();
+            let parsed: Vec<(u32, &[u8])> = mbi.tags().map(|tag| match tag {
+                Ok(Tag::Unknown(type_, data)) => (type_, data),
+                other => panic!("{:?}", other),
+            }).collect();
+            prop_assert_eq!(parsed, tags);
+        }
+
+        #[test]
+        fn garbage_terminates(mut bytes in prop::collection::vec(any::<u8>(), 8..512)) {
+            let total_size = bytes.len() as u32;
+            bytes[0..4].copy_from_slice(&total_size.to_le_bytes());
+            // Skip validation in load
+            let mbi: &'static BootInformation = unsafe { &*ptr::from_raw_parts(leak(&bytes) as *const (), bytes.len() - 8) };
+            // Every tag is at least 8 bytes, so there can not be more than this
+            let items: Vec<_> = mbi.tags().take(bytes.len() / 8 + 1).collect();
+            prop_assert!(items.len() <= bytes.len() / 8);
+            // Only the last one can be an error
+            prop_assert!(items.iter().rev().skip(1).all(Result::is_ok));
+        }
     }
 }
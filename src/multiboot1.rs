@@ -0,0 +1,322 @@
+//! Structures for the multiboot 1 information
+//! https://www.gnu.org/software/grub/manual/multiboot/multiboot.html#Boot-information-format
+
+// The rest of the kernel works with multiboot2 information,
+// so the multiboot 1 information is translated into multiboot2 tags.
+
+use core::ffi::CStr;
+use core::mem;
+
+use crate::multiboot::{self, BootInformation, ElfSection, MemoryMapEntry, TagWriter};
+
+/// Set in EAX by a multiboot 1 bootloader
+pub const BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+/// Colors of the largest indexed palette that is translated, real ones have at most 256
+const MAX_PALETTE: u16 = 256;
+
+/// Which fields of [Multiboot1Info] are valid
+pub mod flags {
+    pub const MEMORY: u32 = 1 << 0;
+    pub const BOOT_DEVICE: u32 = 1 << 1;
+    pub const CMDLINE: u32 = 1 << 2;
+    pub const MODULES: u32 = 1 << 3;
+    pub const ELF_SECTIONS: u32 = 1 << 5;
+    pub const MEMORY_MAP: u32 = 1 << 6;
+    pub const BOOT_LOADER_NAME: u32 = 1 << 9;
+    pub const FRAMEBUFFER: u32 = 1 << 12;
+}
+
+/// All addresses in here are physical and below 4GiB
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct Multiboot1Info {
+    pub flags: u32,
+    pub mem_lower: u32,
+    pub mem_upper: u32,
+    pub boot_device: u32,
+    pub cmdline: u32,
+    pub mods_count: u32,
+    pub mods_addr: u32,
+    /// num, size, addr and shndx of the ELF section headers
+    pub syms: [u32; 4],
+    pub mmap_length: u32,
+    pub mmap_addr: u32,
+    pub drives_length: u32,
+    pub drives_addr: u32,
+    pub config_table: u32,
+    pub boot_loader_name: u32,
+    pub apm_table: u32,
+    pub vbe_control_info: u32,
+    pub vbe_mode_info: u32,
+    pub vbe_mode: u16,
+    pub vbe_interface_seg: u16,
+    pub vbe_interface_off: u16,
+    pub vbe_interface_len: u16,
+    pub framebuffer_addr: u64,
+    pub framebuffer_pitch: u32,
+    pub framebuffer_width: u32,
+    pub framebuffer_height: u32,
+    pub framebuffer_bpp: u8,
+    pub framebuffer_type: u8,
+    pub color_info: [u8; 6],
+}
+
+/// A module as described in multiboot 1
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Module {
+    mod_start: u32,
+    mod_end: u32,
+    string: u32,
+    _reserved: u32,
+}
+
+/// An entry of the memory map, `size` does not include itself
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MmapEntry {
+    size: u32,
+    base_addr: u64,
+    length: u64,
+    type_: u32,
+}
+
+impl Multiboot1Info {
+    /// Copy the structure out of memory, it does not have to be aligned
+    pub fn load(addr: usize) -> Self {
+        unsafe { (addr as *const Self).read_unaligned() }
+    }
+
+    fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn string(addr: u32) -> &'static CStr {
+        unsafe { CStr::from_ptr(addr as usize as *const core::ffi::c_char) }
+    }
+
+    pub fn cmdline(&self) -> Option<&'static CStr> {
+        self.has(flags::CMDLINE).then(|| Self::string(self.cmdline))
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static CStr> {
+        self.has(flags::BOOT_LOADER_NAME).then(|| Self::string(self.boot_loader_name))
+    }
+
+    fn modules(&self) -> impl Iterator<Item = Module> {
+        let count = if self.has(flags::MODULES) { self.mods_count as usize } else { 0 };
+        let addr = self.mods_addr as usize as *const Module;
+        (0..count).map(move |i| unsafe { addr.add(i).read_unaligned() })
+    }
+
+    fn mmap(&self) -> impl Iterator<Item = MmapEntry> {
+        match self.has(flags::MEMORY_MAP) {
+            true => mmap_entries(self.mmap_addr as usize, (self.mmap_addr as usize).saturating_add(self.mmap_length as usize)),
+            false => mmap_entries(0, 0),
+        }
+    }
+}
+
+/// The memory map entries in `start..end`, stopping at the first one that does not fit
+fn mmap_entries(start: usize, end: usize) -> impl Iterator<Item = MmapEntry> {
+    let mut addr = start;
+    core::iter::from_fn(move || {
+        if addr.checked_add(mem::size_of::<MmapEntry>()).is_none_or(|entry_end| entry_end > end) {
+            return None;
+        }
+        let entry = unsafe { (addr as *const MmapEntry).read_unaligned() };
+        addr = addr.saturating_add(entry.size as usize + 4);
+        Some(entry)
+    })
+}
+
+/// Translate the multiboot 1 information into multiboot2 tags
+pub fn translate_into(info: &Multiboot1Info, w: &mut TagWriter) {
+    if let Some(cmdline) = info.cmdline() {
+        // Multiboot 1 loaders (GRUB legacy, QEMU) put the kernel path in front of the arguments
+        let cmdline = cmdline.to_bytes();
+        let args = match cmdline.iter().position(|&c| c == b' ') {
+            Some(space) => &cmdline[space+1..],
+            None => &[],
+        };
+        w.tag(1, |w| {
+            w.write(args);
+            w.write(&[0]);
+        });
+    }
+    if let Some(name) = info.boot_loader_name() {
+        w.tag(2, |w| w.write(name.to_bytes_with_nul()));
+    }
+    for module in info.modules() {
+        w.tag(3, |w| {
+            w.write(&{module.mod_start}.to_le_bytes());
+            w.write(&{module.mod_end}.to_le_bytes());
+            match module.string {
+                0 => w.write(&[0]),
+                string => w.write(Multiboot1Info::string(string).to_bytes_with_nul()),
+            }
+        });
+    }
+    if info.has(flags::MEMORY) {
+        w.tag(4, |w| {
+            w.write(&{info.mem_lower}.to_le_bytes());
+            w.write(&{info.mem_upper}.to_le_bytes());
+        });
+    }
+    if info.has(flags::BOOT_DEVICE) {
+        // biosdev, partition and sub_partition are packed into bytes in multiboot 1
+        // an unused partition is 0xff in multiboot 1 and 0xffffffff in multiboot2
+        let [biosdev, partition, sub_partition, _] = info.boot_device.to_be_bytes();
+        let widen = |part: u8| if part == 0xff { u32::MAX } else { part as u32 };
+        w.tag(5, |w| {
+            w.write(&(biosdev as u32).to_le_bytes());
+            w.write(&widen(partition).to_le_bytes());
+            w.write(&widen(sub_partition).to_le_bytes());
+        });
+    }
+    if info.has(flags::MEMORY_MAP) {
+        w.tag(6, |w| {
+            w.write(&(mem::size_of::<MemoryMapEntry>() as u32).to_le_bytes());
+            w.write(&0u32.to_le_bytes());
+            for entry in info.mmap() {
+                w.write(&{entry.base_addr}.to_le_bytes());
+                w.write(&{entry.length}.to_le_bytes());
+                w.write(&{entry.type_}.to_le_bytes());
+                w.write(&0u32.to_le_bytes());
+            }
+        });
+    }
+    let num_colors = u16::from_le_bytes(info.color_info[4..6].try_into().unwrap());
+    if info.has(flags::FRAMEBUFFER) && info.framebuffer_type == 0 && num_colors > MAX_PALETTE {
+        wprintln!("Dropping the framebuffer, its palette of {} colors does not fit the translated information", num_colors);
+    } else if info.has(flags::FRAMEBUFFER) {
+        w.tag(8, |w| {
+            w.write(&{info.framebuffer_addr}.to_le_bytes());
+            w.write(&{info.framebuffer_pitch}.to_le_bytes());
+            w.write(&{info.framebuffer_width}.to_le_bytes());
+            w.write(&{info.framebuffer_height}.to_le_bytes());
+            w.write(&[info.framebuffer_bpp, info.framebuffer_type, 0, 0]);
+            match info.framebuffer_type {
+                // The palette is a pointer in multiboot 1 but inline in multiboot2
+                0 => {
+                    let address = u32::from_le_bytes(info.color_info[0..4].try_into().unwrap());
+                    w.write(&num_colors.to_le_bytes());
+                    let palette = unsafe { core::slice::from_raw_parts(address as usize as *const u8, num_colors as usize * 3) };
+                    w.write(palette);
+                },
+                1 => w.write(&info.color_info),
+                _ => {},
+            }
+        });
+    }
+    if info.has(flags::ELF_SECTIONS) {
+        let [num, size, addr, shndx] = info.syms;
+        // The headers are at a 32 bit address, a table that would not fit there is dropped
+        if let (true, Some(len)) = (size as usize == mem::size_of::<ElfSection>(), num.checked_mul(size)) {
+            w.tag(9, |w| {
+                w.write(&num.to_le_bytes());
+                w.write(&size.to_le_bytes());
+                w.write(&shndx.to_le_bytes());
+                let headers = unsafe { core::slice::from_raw_parts(addr as usize as *const u8, len as usize) };
+                w.write(headers);
+            });
+        }
+    }
+}
+
+/// Translate the multiboot 1 information at `addr` to multiboot2.
+/// Returns a pointer for [BootInformation::load].
+pub fn translate(addr: usize) -> *const BootInformation {
+    let info = Multiboot1Info::load(addr);
+    multiboot::translate(|w| translate_into(&info, w))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use core::ptr;
+    use crate::multiboot::Tag;
+
+    fn info(flags: u32) -> Multiboot1Info {
+        let mut info: Multiboot1Info = unsafe { mem::zeroed() };
+        info.flags = flags;
+        info
+    }
+
+    fn translate_leak(info: &Multiboot1Info) -> &'static BootInformation {
+        let words: &'static mut [u64] = Vec::leak(vec![0u64; 512]);
+        let buffer = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 512 * 8) };
+        let mut writer = TagWriter::new(buffer);
+        translate_into(info, &mut writer);
+        writer.finish();
+        BootInformation::load(ptr::from_raw_parts(words.as_ptr() as *const (), 0))
+            .expect("Translated information should be valid")
+    }
+
+    #[test]
+    fn empty() {
+        let mbi = translate_leak(&info(0));
+        assert_eq!(mbi.total_size, 16);
+        assert_eq!(mbi.tags().count(), 0);
+    }
+
+    // Strings and tables are 32 bit pointers, which host allocations do not fit in
+
+    #[test]
+    fn memory() {
+        let mut info = info(flags::MEMORY);
+        info.mem_lower = 639;
+        info.mem_upper = 130048;
+
+        let mbi = translate_leak(&info);
+        let meminfo = mbi.tags().find_map(|t| match t {
+            Ok(Tag::BasicMemInfo(m)) => Some(m),
+            _ => None,
+        }).unwrap();
+        assert_eq!((meminfo.mem_lower, meminfo.mem_upper), (639, 130048));
+    }
+
+    #[test]
+    fn boot_device() {
+        let mut info = info(flags::BOOT_DEVICE);
+        info.boot_device = 0x8001ffff;
+
+        let mbi = translate_leak(&info);
+        let device = mbi.tags().find_map(|t| match t {
+            Ok(Tag::BIOSBootDevice(d)) => Some(d),
+            _ => None,
+        }).unwrap();
+        assert_eq!((device.biosdev, device.partition, device.sub_partition), (0x80, 1, u32::MAX));
+    }
+
+    #[test]
+    fn mmap_entry_past_end() {
+        let mut bytes = Vec::new();
+        for (size, base, length) in [(20u32, 0u64, 0x9fc00u64), (u32::MAX, 0x100000, 0x100000), (20, 0x200000, 0x1000)] {
+            bytes.extend(size.to_le_bytes());
+            bytes.extend(base.to_le_bytes());
+            bytes.extend(length.to_le_bytes());
+            bytes.extend(1u32.to_le_bytes());
+        }
+        let start = Vec::leak(bytes).as_ptr() as usize;
+        let end = start + 3 * mem::size_of::<MmapEntry>();
+        // The second entry's size steps over the third and past the end
+        let bases: Vec<u64> = mmap_entries(start, end).map(|e| e.base_addr).collect();
+        assert_eq!(bases, [0, 0x100000]);
+        assert_eq!(mmap_entries(usize::MAX - 8, usize::MAX).count(), 0);
+        assert_eq!(mmap_entries(end, end).count(), 0);
+        // Entries that do not fit whole are not read
+        assert_eq!(mmap_entries(start, end - 1).count(), 2);
+    }
+
+    #[test]
+    fn palette_too_large() {
+        let mut info = info(flags::FRAMEBUFFER);
+        info.framebuffer_type = 0;
+        // The palette pointer is never read
+        info.color_info = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+        let mbi = translate_leak(&info);
+        assert!(mbi.tags().all(|t| !matches!(t, Ok(Tag::FrameBufferInfo(_)))));
+    }
+}
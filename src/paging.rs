@@ -0,0 +1,133 @@
+//! Helpers for working with the active page tables
+//! https://wiki.osdev.org/Paging
+
+// The page tables set up in boot.asm identity map the first 16MiB,
+// so every table can be accessed through its physical address.
+
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::VirtAddr;
+
+/// Everything below this physical address is identity mapped by boot.asm
+pub const IDENTITY_MAPPED_END: u64 = 16 * 1024 * 1024;
+
+/// Allow pages to be marked as not executable
+pub fn init() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// Get a mapper for the active page tables.
+/// Frames for new tables have to come from the identity mapped region.
+/// # Safety
+/// Only one mapper may exist at a time.
+pub unsafe fn mapper() -> OffsetPageTable<'static> {
+    let (pml4t, _) = Cr3::read();
+    OffsetPageTable::new(&mut *(pml4t.start_address().as_u64() as *mut PageTable), VirtAddr::new(0))
+}
+
+/// Walk the page tables for `addr`, calling `f` on every entry on the way down.
+/// Returns the size of the page mapping `addr`, or None if it is not mapped.
+fn walk(addr: VirtAddr, mut f: impl FnMut(&mut PageTableEntry)) -> Option<u64> {
+    let (pml4t, _) = Cr3::read();
+    let mut table = unsafe { &mut *(pml4t.start_address().as_u64() as *mut PageTable) };
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        f(entry);
+        match level {
+            3 => return Some(0x1000),
+            2 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => return Some(0x200000),
+            1 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => return Some(0x40000000),
+            _ => table = unsafe { &mut *(entry.addr().as_u64() as *mut PageTable) },
+        }
+    }
+    unreachable!()
+}
+
+/// Iterate over the start of every page that overlaps `start..start+len`,
+/// calling `f` which returns the size of that page.
+fn for_each_page(start: VirtAddr, len: u64, mut f: impl FnMut(VirtAddr) -> Option<u64>) -> bool {
+    let Some(end) = start.as_u64().checked_add(len) else { return false };
+    let mut addr = start.align_down(0x1000u64);
+    while addr.as_u64() < end {
+        let Some(size) = f(addr) else { return false };
+        // Past the end of the address space, which covers the rest of the range
+        let Some(next) = addr.align_down(size).as_u64().checked_add(size) else { return true };
+        if next >= end {
+            return true;
+        }
+        // The range runs into the non-canonical hole (which try_new sign extends into when bit 47 is set)
+        match VirtAddr::try_new(next) {
+            Ok(canonical) if canonical.as_u64() == next => addr = canonical,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Allow ring 3 to access the (already mapped) range `start..start+len`.
+/// If part of the range is mapped with a huge page, the whole huge page becomes accessible.
+#[allow(dead_code)]
+pub fn set_user_accessible(start: VirtAddr, len: u64) -> Result<(), &'static str> {
+    let mapped = for_each_page(start, len, |addr| {
+        walk(addr, |entry| entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE))
+    });
+    x86_64::instructions::tlb::flush_all();
+    if mapped { Ok(()) } else { Err("Range is not mapped") }
+}
+
+/// Check if the kernel can access `start..start+len` without a page fault
+pub fn is_mapped(start: VirtAddr, len: u64, write: bool) -> bool {
+    let mut writable = true;
+    let mapped = for_each_page(start, len, |addr| {
+        walk(addr, |entry| writable &= entry.flags().contains(PageTableFlags::WRITABLE))
+    });
+    mapped && (writable || !write)
+}
+
+/// Check if ring 3 is allowed to access `start..start+len`
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut accessible = true;
+    let mapped = for_each_page(start, len, |addr| {
+        walk(addr, |entry| accessible &= entry.flags().contains(required))
+    });
+    mapped && accessible
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_overflow() {
+        let top = VirtAddr::new(0xffff_ffff_ffff_f000);
+        assert!(!for_each_page(top, 0x2000, |_| Some(0x1000)));
+        assert!(!for_each_page(VirtAddr::new(0x1000), u64::MAX, |_| Some(0x1000)));
+        let mut pages = 0;
+        assert!(for_each_page(top, 0xfff, |_| { pages += 1; Some(0x1000) }));
+        assert_eq!(pages, 1);
+        assert!(for_each_page(VirtAddr::new(0x7fff_ffff_f000), 0x1000, |_| Some(0x1000)));
+        assert!(!for_each_page(VirtAddr::new(0x7fff_ffff_f000), 0x2000, |_| Some(0x1000)));
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use super::*;
+
+    #[test_case]
+    fn identity_mapped() {
+        assert!(is_mapped(VirtAddr::new(identity_mapped as usize as u64), 1, false));
+        assert!(is_mapped(VirtAddr::new(IDENTITY_MAPPED_END - 1), 1, true));
+        assert!(!is_mapped(VirtAddr::new(IDENTITY_MAPPED_END - 1), 2, false));
+    }
+}
@@ -1,20 +1,40 @@
 use core::panic::PanicInfo;
 use core::fmt::Write;
-use crate::vga;
+use crate::{crash, emergency, rsod, serial, vga};
+use crate::backtrace::Backtrace;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-	let cover = match crate::conf::CONFIG.get() {
-		Some(conf) => conf.panic_cover,
-		None => true,
+	let here = crash::Registers::here();
+	let registers = crash::take_exception().unwrap_or(here);
+	x86_64::instructions::interrupts::disable();
+	match emergency::enter_panic() {
+		0 => {},
+		// Panicked while panicking, the normal output paths may be what failed
+		1 => {
+			let _ = write!(emergency::Console, "\nPANIC while panicking: {}\n{}\n", info.location().unwrap(), info.message());
+			crate::hlt_loop!();
+		},
+		// Even that failed
+		_ => crate::hlt_loop!(),
+	}
+	let backtrace = Backtrace::here();
+	let (cover, reboot) = match crate::conf::CONFIG.get() {
+		Some(conf) => (conf.panic_cover, conf.panic_reboot),
+		None => (true, None),
 	};
+	write!(serial::RawWriter, "\x1b[33;41mPANIC\x1b[0m {}\n{}\nBacktrace:\n{}", info.location().unwrap(), info.message(), backtrace).unwrap();
+	crash::emit(&crash::CrashReport { info, registers: &registers, backtrace });
+	let mut screen = None;
 	if cover {
-		vga::fill(vga::Color::Red);
+		screen = Some(rsod::show(info, &registers, &backtrace));
+	} else {
+		let mut printer = vga::ColoredPrinter::new(0,vga::BUFFER_HEIGHT-1,vga::Color::White, vga::Color::Red);
+		write!(printer, "{}\n{}\nBacktrace:\n{}", info.location().unwrap(), info.message(), backtrace).unwrap();
 	}
-	let mut printer = vga::ColoredPrinter::new(0,vga::BUFFER_HEIGHT-1,vga::Color::White, vga::Color::Red);
-	write!(printer, "{}\n{}", info.location().unwrap(), info.message()).unwrap();
-	if cover {
-		vga::print_at(vga::BUFFER_WIDTH / 2 - 2, 12, "PANIC".as_bytes(), vga::Color::White, vga::Color::Red);
+	crate::gdbstub::panic();
+	if let Some(seconds) = reboot {
+		rsod::reboot_after(screen.as_mut(), seconds);
 	}
 	crate::hlt_loop!();
 }
@@ -0,0 +1,174 @@
+//! Per-CPU data areas accessed through the GS segment
+//! https://wiki.osdev.org/SWAPGS
+
+// Every per-CPU static is placed in the .percpu section by the linker.
+// That section is only a template, each CPU gets its own copy of it
+// and points GS.Base at that copy.
+// The first thing in every area is a [Header] with a pointer to the area itself,
+// so the area can be found with a single `mov reg, gs:[0]`.
+//
+// While in the kernel GS.Base holds the per-CPU area and KernelGsBase holds the
+// user value. Code entering from ring 3 has to `swapgs` before touching per-CPU data
+// and again before returning.
+
+use core::arch::asm;
+use core::cell::{Cell, UnsafeCell};
+use core::mem::offset_of;
+use core::ptr::addr_of;
+use x86_64::instructions::segmentation::GS;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+    /// Area reserved by the linker for the bootstrap processor
+    static mut __percpu_bsp: u8;
+}
+
+/// Declare one or more per-CPU statics.
+/// Types that have to be modified should use interior mutability (like `Cell`).
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $type_:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$type_> = {
+                let value: $type_ = $init;
+                // SAFETY: placed in .percpu by the attribute above
+                unsafe { $crate::percpu::PerCpu::new(value) }
+            };
+        )*
+    };
+}
+
+/// A per-CPU static, declare these using [percpu!]
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    /// The template value, this is never accessed directly after [init]
+    value: UnsafeCell<T>,
+}
+
+// Every CPU only ever accesses its own copy
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Used by [percpu!]
+    /// # Safety
+    /// The static has to be placed in the .percpu section, [PerCpu::with] finds the copy by its offset in there.
+    #[doc(hidden)]
+    pub const unsafe fn new(value: T) -> Self {
+        Self { value: UnsafeCell::new(value) }
+    }
+
+    /// Access this CPU's copy of the value.
+    /// Interrupts are disabled for the duration of `f`
+    /// so an interrupt handler never sees the value mid-update.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let offset = self.value.get() as usize - addr_of!(__percpu_start) as usize;
+            f(unsafe { &*(area().add(offset) as *const T) })
+        })
+    }
+}
+
+/// Always placed at the start of a per-CPU area.
+/// Fields that are used from assembly live here, so their offset is known.
+#[repr(C)]
+pub struct Header {
+    /// The address of the area itself
+    area: usize,
+    cpu_id: u32,
+    /// Stack to switch to on `syscall`
+    pub kernel_stack: Cell<usize>,
+    /// Stack pointer of user code during a `syscall`
+    pub user_stack: Cell<usize>,
+    /// Kernel stack pointer to restore when leaving user mode for good
+    pub user_return: Cell<usize>,
+}
+
+/// Offsets into the [Header] for use in assembly
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(Header, kernel_stack);
+pub const USER_STACK_OFFSET: usize = offset_of!(Header, user_stack);
+pub const USER_RETURN_OFFSET: usize = offset_of!(Header, user_return);
+
+#[link_section = ".percpu.header"]
+// SAFETY: placed in .percpu, at the start of it
+pub static HEADER: PerCpu<Header> = unsafe { PerCpu::new(Header {
+    area: 0,
+    cpu_id: 0,
+    kernel_stack: Cell::new(0),
+    user_stack: Cell::new(0),
+    user_return: Cell::new(0),
+}) };
+
+/// The per-CPU area of the current CPU
+#[inline(always)]
+fn area() -> *mut u8 {
+    let area: *mut u8;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) area, options(nostack, readonly, preserves_flags)) };
+    area
+}
+
+/// The size of a per-CPU area in bytes
+pub fn area_size() -> usize {
+    addr_of!(__percpu_end) as usize - addr_of!(__percpu_start) as usize
+}
+
+/// Copy the template into `area`, and make it the per-CPU area of the calling CPU.
+/// # Safety
+/// `area` has to be at least [area_size] bytes, page aligned and never be used for anything else.
+pub unsafe fn init_cpu(area: *mut u8, cpu_id: u32) {
+    core::ptr::copy_nonoverlapping(addr_of!(__percpu_start), area, area_size());
+    let header = area as *mut Header;
+    (*header).area = area as usize;
+    (*header).cpu_id = cpu_id;
+    GsBase::write(VirtAddr::from_ptr(area));
+    KernelGsBase::write(VirtAddr::new(0));
+}
+
+/// Set up the per-CPU area of the bootstrap processor.
+/// This has to happen before interrupts are enabled.
+pub fn init() {
+    unsafe { init_cpu(core::ptr::addr_of_mut!(__percpu_bsp), 0) };
+}
+
+/// Returned by [enter_interrupt], swaps GS back when dropped
+pub struct GsGuard {
+    from_user: bool,
+}
+
+/// Has to be called at the very start of every interrupt handler that can
+/// interrupt user code, as GS.Base still holds the user value in that case.
+pub fn enter_interrupt(stack_frame: &InterruptStackFrame) -> GsGuard {
+    let from_user = stack_frame.code_segment & 0b11 == 3;
+    if from_user {
+        unsafe { GS::swap() };
+    }
+    GsGuard { from_user }
+}
+
+impl Drop for GsGuard {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// If the calling CPU has a per-CPU area yet
+pub fn is_initialized() -> bool {
+    GsBase::read().as_u64() != 0
+}
+
+/// The ID of the CPU we are running on
+pub fn cpu_id() -> u32 {
+    HEADER.with(|h| h.cpu_id)
+}
+
+/// The address of the per-CPU area of the CPU we are running on
+pub fn area_addr() -> VirtAddr {
+    VirtAddr::from_ptr(area())
+}
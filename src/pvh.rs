@@ -0,0 +1,223 @@
+//! Structures for the Xen PVH boot protocol, used by `qemu -kernel`
+//! https://xenbits.xen.org/docs/unstable/misc/pvh.html
+//! https://xenbits.xen.org/gitweb/?p=xen.git;a=blob;f=xen/include/public/arch-x86/hvm/start_info.h
+
+// Like multiboot 1, the start info is translated into multiboot2 tags.
+
+use core::ffi::CStr;
+use core::mem;
+
+use crate::multiboot::{self, BootInformation, MemoryMapEntry, TagWriter};
+
+/// The `magic` field of [StartInfo], passed to runix as bootloader magic
+pub const START_INFO_MAGIC: u32 = 0x336ec578;
+
+/// All addresses in here are physical
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    /// Only valid from version 1
+    pub memmap_paddr: u64,
+    /// Only valid from version 1
+    pub memmap_entries: u32,
+    _reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    _reserved: u64,
+}
+
+/// Uses the E820 types, like the multiboot memory map
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MemmapEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    _reserved: u32,
+}
+
+impl StartInfo {
+    pub fn load(addr: usize) -> Self {
+        unsafe { *(addr as *const Self) }
+    }
+
+    fn string(addr: u64) -> Option<&'static CStr> {
+        (addr != 0).then(|| unsafe { CStr::from_ptr(addr as usize as *const core::ffi::c_char) })
+    }
+
+    fn modules(&self) -> &'static [ModlistEntry] {
+        if self.nr_modules == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.modlist_paddr as usize as *const ModlistEntry, self.nr_modules as usize) }
+    }
+
+    fn memmap(&self) -> &'static [MemmapEntry] {
+        if self.version < 1 || self.memmap_entries == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.memmap_paddr as usize as *const MemmapEntry, self.memmap_entries as usize) }
+    }
+
+    /// The RSDP is 20 bytes in revision 0 and `length` bytes after that
+    fn rsdp(&self) -> Option<&'static [u8]> {
+        if self.rsdp_paddr == 0 {
+            return None;
+        }
+        let rsdp = self.rsdp_paddr as usize as *const u8;
+        let revision = unsafe { *rsdp.add(15) };
+        let len = match revision {
+            0 => 20,
+            _ => unsafe { (rsdp.add(20) as *const u32).read_unaligned() as usize },
+        };
+        Some(unsafe { core::slice::from_raw_parts(rsdp, len) })
+    }
+}
+
+/// Translate the start info into multiboot2 tags
+pub fn translate_into(info: &StartInfo, w: &mut TagWriter) {
+    if let Some(cmdline) = StartInfo::string(info.cmdline_paddr) {
+        w.tag(1, |w| w.write(cmdline.to_bytes_with_nul()));
+    }
+    w.tag(2, |w| w.write(b"PVH\0"));
+    for module in info.modules() {
+        // Multiboot2 modules are limited to 4GiB
+        let Some(end) = module.paddr.checked_add(module.size) else { continue };
+        let (Ok(start), Ok(end)) = (u32::try_from(module.paddr), u32::try_from(end)) else {
+            continue;
+        };
+        w.tag(3, |w| {
+            w.write(&start.to_le_bytes());
+            w.write(&end.to_le_bytes());
+            w.write(StartInfo::string(module.cmdline_paddr).map_or(&[0], |s| s.to_bytes_with_nul()));
+        });
+    }
+    if !info.memmap().is_empty() {
+        w.tag(6, |w| {
+            w.write(&(mem::size_of::<MemoryMapEntry>() as u32).to_le_bytes());
+            w.write(&0u32.to_le_bytes());
+            for entry in info.memmap() {
+                w.write(&entry.addr.to_le_bytes());
+                w.write(&entry.size.to_le_bytes());
+                w.write(&entry.type_.to_le_bytes());
+                w.write(&0u32.to_le_bytes());
+            }
+        });
+    }
+    if let Some(rsdp) = info.rsdp() {
+        let type_ = if rsdp[15] == 0 { 14 } else { 15 };
+        w.tag(type_, |w| w.write(rsdp));
+    }
+}
+
+/// Translate the start info at `addr` to multiboot2.
+/// Returns a pointer for [BootInformation::load].
+pub fn translate(addr: usize) -> *const BootInformation {
+    let info = StartInfo::load(addr);
+    multiboot::translate(|w| translate_into(&info, w))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use core::ptr;
+    use crate::multiboot::Tag;
+
+    fn info() -> StartInfo {
+        let mut info: StartInfo = unsafe { mem::zeroed() };
+        info.magic = START_INFO_MAGIC;
+        info.version = 1;
+        info
+    }
+
+    fn translate_leak(info: &StartInfo) -> &'static BootInformation {
+        let words: &'static mut [u64] = Vec::leak(vec![0u64; 512]);
+        let buffer = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 512 * 8) };
+        let mut writer = TagWriter::new(buffer);
+        translate_into(info, &mut writer);
+        writer.finish();
+        BootInformation::load(ptr::from_raw_parts(words.as_ptr() as *const (), 0))
+            .expect("Translated information should be valid")
+    }
+
+    #[test]
+    fn cmdline_and_name() {
+        let mut info = info();
+        info.cmdline_paddr = b"welcome=false\0".as_ptr() as u64;
+
+        let mbi = translate_leak(&info);
+        assert_eq!(mbi.boot_command_line().unwrap().to_bytes(), b"welcome=false");
+        assert_eq!(mbi.bootloader_name().unwrap().to_bytes(), b"PVH");
+        assert!(mbi.memory_map().is_none());
+    }
+
+    #[test]
+    fn memory_map() {
+        let memmap = [
+            MemmapEntry { addr: 0, size: 0x9fc00, type_: 1, _reserved: 0 },
+            MemmapEntry { addr: 0x100000, size: 0x7ee0000, type_: 1, _reserved: 0 },
+            MemmapEntry { addr: 0xfeffc000, size: 0x4000, type_: 2, _reserved: 0 },
+        ];
+        let mut info = info();
+        info.memmap_paddr = memmap.as_ptr() as u64;
+        info.memmap_entries = memmap.len() as u32;
+
+        let memory_map = translate_leak(&info).memory_map().unwrap();
+        assert_eq!(memory_map.entries.len(), 3);
+        assert_eq!(memory_map.entries[1].base_addr, 0x100000);
+        assert_eq!(memory_map.entries[1].length, 0x7ee0000);
+        assert_eq!(memory_map.entries[2].type_, 2);
+
+        // Version 0 has no memory map
+        info.version = 0;
+        assert!(translate_leak(&info).memory_map().is_none());
+    }
+
+    #[test]
+    fn modules() {
+        let modules = [
+            ModlistEntry { paddr: 0x200000, size: 0x1234, cmdline_paddr: b"hello world\0".as_ptr() as u64, _reserved: 0 },
+            ModlistEntry { paddr: 0x300000, size: 0x10, cmdline_paddr: 0, _reserved: 0 },
+            // Does not fit in a multiboot2 module
+            ModlistEntry { paddr: 0x1_0000_0000, size: 0x10, cmdline_paddr: 0, _reserved: 0 },
+        ];
+        let mut info = info();
+        info.modlist_paddr = modules.as_ptr() as u64;
+        info.nr_modules = modules.len() as u32;
+
+        let modules: Vec<_> = translate_leak(&info).modules().collect();
+        assert_eq!(modules.len(), 2);
+        assert_eq!((modules[0].mod_start, modules[0].mod_end), (0x200000, 0x201234));
+        assert_eq!(modules[0].cmdline().to_bytes(), b"hello world");
+        assert_eq!(modules[1].cmdline().to_bytes(), b"");
+    }
+
+    #[test]
+    fn rsdp() {
+        let mut rsdp = [0u8; 36];
+        rsdp[..8].copy_from_slice(b"RSD PTR ");
+        let mut info = info();
+        info.rsdp_paddr = rsdp.as_ptr() as u64;
+
+        let mbi = translate_leak(&info);
+        assert!(matches!(mbi.tags().last(), Some(Ok(Tag::ACPIOldRSDP(r))) if r.len() == 20));
+
+        rsdp[15] = 2;
+        rsdp[20..24].copy_from_slice(&36u32.to_le_bytes());
+        let mbi = translate_leak(&info);
+        assert_eq!(mbi.rsdp().unwrap(), &rsdp[..]);
+    }
+}
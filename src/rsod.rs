@@ -0,0 +1,269 @@
+//! The red screen of death, drawn by the panic handler unless the `no panic_cover` option is given
+
+// The screen is laid out in fixed rows, every part is cut off to fit:
+//  0     header
+//  1     location
+//  2-5   message, wrapped
+//  7-14  registers, three per row
+//  15-19 backtrace
+//  20-23 the last log records
+//  24    footer
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+use core::panic::PanicInfo;
+
+use x86_64::instructions::port::Port;
+
+use crate::backtrace::{Backtrace, Caller};
+use crate::crash::Registers;
+use crate::logger;
+use crate::vga::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+const HEADER: usize = 0;
+const LOCATION: usize = 1;
+const MESSAGE: Range<usize> = 2..6;
+const REGISTERS: Range<usize> = 7..15;
+const BACKTRACE: Range<usize> = 15..20;
+const LOG: Range<usize> = 20..24;
+const FOOTER: usize = BUFFER_HEIGHT - 1;
+const REGISTER_WIDTH: usize = 26;
+
+/// Characters of the screen, before they are drawn
+pub struct Screen {
+    text: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Self { text: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT] }
+    }
+
+    /// A writer for `rows`, wrapping long lines if `wrap` is set or cutting them off otherwise
+    fn area(&mut self, rows: Range<usize>, wrap: bool) -> Area<'_> {
+        Area { screen: self, row: rows.start, col: 0, rows, wrap, full: false }
+    }
+
+    /// Lay out everything
+    pub fn layout(&mut self, location: &dyn fmt::Display, message: &dyn fmt::Display, registers: &Registers, backtrace: &Backtrace) {
+        let header = b"RUNIX KERNEL PANIC";
+        let start = (BUFFER_WIDTH - header.len()) / 2;
+        self.text[HEADER][start..start + header.len()].copy_from_slice(header);
+
+        write!(self.area(LOCATION..LOCATION + 1, false), "at {}", location).unwrap();
+        write!(self.area(MESSAGE, true), "{}", message).unwrap();
+
+        let columns = BUFFER_WIDTH / REGISTER_WIDTH;
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let row = REGISTERS.start + i / columns;
+            if row >= REGISTERS.end {
+                break;
+            }
+            let mut area = self.area(row..row + 1, false);
+            area.col = i % columns * REGISTER_WIDTH;
+            write!(area, "{:>6} {:#018x}", name, value).unwrap();
+        }
+
+        write!(self.area(BACKTRACE.start..BACKTRACE.start + 1, false), "Backtrace:").unwrap();
+        for (row, frame) in (BACKTRACE.start + 1..BACKTRACE.end).zip(backtrace.frames()) {
+            write!(self.area(row..row + 1, false), "  {:#018x} {}", frame.return_address, Caller(frame.return_address)).unwrap();
+        }
+
+        write!(self.area(LOG.start..LOG.start + 1, false), "Log:").unwrap();
+        let mut row = LOG.start + 1;
+        logger::tail(LOG.len() - 1, |entry| {
+            write!(self.area(row..row + 1, false), "  {}", entry).unwrap();
+            row += 1;
+        });
+
+        self.footer(format_args!("System halted"));
+    }
+
+    /// Replace the footer, centered
+    pub fn footer(&mut self, text: fmt::Arguments) {
+        let mut line = Screen::new();
+        write!(line.area(0..1, false), "{}", text).unwrap();
+        let len = line.text[0].iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        let start = (BUFFER_WIDTH - len) / 2;
+        self.text[FOOTER] = [b' '; BUFFER_WIDTH];
+        self.text[FOOTER][start..start + len].copy_from_slice(&line.text[0][..len]);
+    }
+
+    /// Put the screen on the display
+    pub fn draw(&self) {
+        for (y, line) in self.text.iter().enumerate() {
+            let (fg, bg) = match y {
+                HEADER | FOOTER => (Color::Red, Color::White),
+                _ => (Color::White, Color::Red),
+            };
+            vga::print_at(0, y, line, fg, bg);
+        }
+    }
+}
+
+/// Writes into some rows of the screen
+struct Area<'a> {
+    screen: &'a mut Screen,
+    rows: Range<usize>,
+    row: usize,
+    col: usize,
+    wrap: bool,
+    /// Set once something did not fit, it is then marked with `...`
+    full: bool,
+}
+
+impl Area<'_> {
+    fn newline(&mut self) {
+        self.row += 1;
+        self.col = 0;
+        if self.row >= self.rows.end {
+            self.truncated();
+        }
+    }
+
+    fn truncated(&mut self) {
+        if !self.full {
+            self.full = true;
+            let last = &mut self.screen.text[self.rows.end - 1];
+            last[BUFFER_WIDTH - 3..].copy_from_slice(b"...");
+        }
+    }
+}
+
+impl fmt::Write for Area<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full {
+                break;
+            }
+            if c == '\n' {
+                if self.wrap {
+                    self.newline();
+                } else {
+                    self.truncated();
+                }
+                continue;
+            }
+            if self.col == BUFFER_WIDTH {
+                if self.wrap {
+                    self.newline();
+                    if self.full {
+                        break;
+                    }
+                } else {
+                    self.truncated();
+                    break;
+                }
+            }
+            // The VGA font is code page 437, which only matches ASCII
+            let byte = if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' };
+            self.screen.text[self.row][self.col] = byte;
+            self.col += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Draw the red screen of death
+pub fn show(info: &PanicInfo, registers: &Registers, backtrace: &Backtrace) -> Screen {
+    let mut screen = Screen::new();
+    let location: &dyn fmt::Display = match info.location() {
+        Some(location) => location,
+        None => &"unknown location",
+    };
+    screen.layout(location, &info.message(), registers, backtrace);
+    screen.draw();
+    screen
+}
+
+/// Count down on the footer of the screen if it is shown, then reboot
+pub fn reboot_after(mut screen: Option<&mut Screen>, seconds: u32) -> ! {
+    for left in (1..=seconds).rev() {
+        if let Some(screen) = &mut screen {
+            screen.footer(format_args!("Rebooting in {} seconds", left));
+            screen.draw();
+        }
+        wait_second();
+    }
+    reboot();
+}
+
+/// Wait until the seconds of the real time clock change
+fn wait_second() {
+    let start = rtc_seconds();
+    while rtc_seconds() == start {
+        core::hint::spin_loop();
+    }
+}
+
+/// https://wiki.osdev.org/CMOS#Getting_Current_Date_and_Time_from_RTC
+fn rtc_seconds() -> u8 {
+    let mut index = Port::<u8>::new(0x70);
+    let mut data = Port::<u8>::new(0x71);
+    unsafe {
+        // Wait while the RTC is updating
+        loop {
+            index.write(0x0a);
+            if data.read() & 0x80 == 0 {
+                break;
+            }
+        }
+        index.write(0x00);
+        data.read()
+    }
+}
+
+/// Reset through the keyboard controller, or by triple faulting if that does not work
+pub fn reboot() -> ! {
+    unsafe {
+        Port::<u8>::new(0x64).write(0xfe);
+        let idt = x86_64::structures::DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::new(0) };
+        x86_64::instructions::tables::lidt(&idt);
+        x86_64::instructions::interrupts::int3();
+    }
+    crate::hlt_loop!();
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn row(screen: &Screen, row: usize) -> &str {
+        core::str::from_utf8(&screen.text[row]).unwrap().trim_end()
+    }
+
+    #[test]
+    fn wrap() {
+        let mut screen = Screen::new();
+        let message = "x".repeat(BUFFER_WIDTH + 10);
+        write!(screen.area(2..5, true), "{}\nnext", message).unwrap();
+        assert_eq!(row(&screen, 2), "x".repeat(BUFFER_WIDTH));
+        assert_eq!(row(&screen, 3), "x".repeat(10));
+        assert_eq!(row(&screen, 4), "next");
+        assert_eq!(row(&screen, 5), "");
+    }
+
+    #[test]
+    fn truncate() {
+        let mut screen = Screen::new();
+        write!(screen.area(0..2, true), "{}", "y".repeat(3 * BUFFER_WIDTH)).unwrap();
+        assert_eq!(row(&screen, 1), format!("{}...", "y".repeat(BUFFER_WIDTH - 3)));
+        assert_eq!(row(&screen, 2), "");
+
+        let mut screen = Screen::new();
+        write!(screen.area(5..6, false), "one\ntwo é").unwrap();
+        assert_eq!(row(&screen, 5), format!("one{}...", " ".repeat(BUFFER_WIDTH - 6)));
+
+        let mut screen = Screen::new();
+        write!(screen.area(5..6, false), "é\x1b").unwrap();
+        assert_eq!(row(&screen, 5), "??");
+    }
+
+    #[test]
+    fn footer() {
+        let mut screen = Screen::new();
+        screen.footer(format_args!("{}", "ab"));
+        assert_eq!(row(&screen, FOOTER).trim_start(), "ab");
+        assert_eq!(row(&screen, FOOTER).len(), BUFFER_WIDTH / 2 + 1);
+    }
+}
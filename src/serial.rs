@@ -0,0 +1,340 @@
+//! Driver for the 16550 UART serial ports COM1 to COM4
+//! https://wiki.osdev.org/Serial_Ports
+//! https://en.wikibooks.org/wiki/Serial_Programming/8250_UART_Programming
+
+// The ports start out polled. Once interrupts are set up they can be switched to IRQ mode,
+// then received bytes are buffered by the interrupt handler and sent bytes are queued.
+// When the transmit queue is full writing falls back to polling.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// The standard I/O port bases of COM1 to COM4
+pub const BASES: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+/// The clock of the UART divided by 16, the baud rate divisor is relative to this
+const MAX_BAUD: u32 = 115200;
+const BUFFER_SIZE: usize = 1024;
+
+// Register offsets from the base
+const DATA: u16 = 0;
+/// Interrupt enable, the divisor low byte when DLAB is set
+const IER: u16 = 1;
+/// FIFO control when written, interrupt identification when read
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+
+// Bits of the registers
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+/// Connects the interrupt line to the PIC
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// If output of the print macros is mirrored to COM1, see the `serial` boot option
+static MIRROR: AtomicBool = AtomicBool::new(true);
+
+pub static PORTS: [Mutex<Option<SerialPort>>; 4] = [
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+];
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    Mark = 0b101,
+    Space = 0b111,
+}
+
+/// Baud rate and frame format
+#[derive(Debug, Clone, Copy)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl Default for LineConfig {
+    /// 115200 8N1
+    fn default() -> Self {
+        Self { baud: MAX_BAUD, data_bits: 8, parity: Parity::None, stop_bits: 1 }
+    }
+}
+
+#[derive(Debug)]
+pub enum SerialError {
+    /// The loopback test failed, there is no UART at this port
+    NotPresent(u16),
+    BadConfig,
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::NotPresent(base) => write!(f, "no UART at {:#x}", base),
+            SerialError::BadConfig => write!(f, "invalid line configuration"),
+        }
+    }
+}
+
+/// A byte queue for the interrupt driven mode
+struct RingBuffer {
+    data: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self { data: [0; BUFFER_SIZE], start: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == BUFFER_SIZE {
+            return false;
+        }
+        self.data[(self.start + self.len) % BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+pub struct SerialPort {
+    base: u16,
+    interrupts: bool,
+    rx: RingBuffer,
+    tx: RingBuffer,
+}
+
+impl SerialPort {
+    /// Configure the UART at `base`, it starts out polled
+    pub fn init(base: u16, config: LineConfig) -> Result<Self, SerialError> {
+        if config.baud == 0 || MAX_BAUD % config.baud != 0
+            || !(5..=8).contains(&config.data_bits) || !(1..=2).contains(&config.stop_bits) {
+            return Err(SerialError::BadConfig);
+        }
+        let port = Self { base, interrupts: false, rx: RingBuffer::new(), tx: RingBuffer::new() };
+        let divisor = (MAX_BAUD / config.baud) as u16;
+        let line = (config.data_bits - 5) | (config.stop_bits - 1) << 2 | (config.parity as u8) << 3;
+        unsafe {
+            port.write(IER, 0);
+            port.write(LCR, LCR_DLAB);
+            port.write(DATA, divisor as u8);
+            port.write(IER, (divisor >> 8) as u8);
+            port.write(LCR, line);
+            // Enable and clear the FIFOs, interrupt at 14 bytes
+            port.write(FCR, 0xc7);
+
+            // Send a byte to ourselves to check if the UART is there
+            port.write(MCR, MCR_RTS | MCR_OUT2 | MCR_LOOPBACK);
+            port.write(DATA, 0xae);
+            if port.read(DATA) != 0xae {
+                return Err(SerialError::NotPresent(base));
+            }
+            port.write(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+        }
+        Ok(port)
+    }
+
+    unsafe fn read(&self, register: u16) -> u8 {
+        Port::new(self.base + register).read()
+    }
+
+    unsafe fn write(&self, register: u16, value: u8) {
+        Port::new(self.base + register).write(value)
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.read(LSR) }
+    }
+
+    /// Raise interrupts when data arrives or the transmitter is empty.
+    /// The IRQ has to be unmasked and handled, see [crate::serial::irq_handler].
+    pub fn enable_interrupts(&mut self) {
+        self.interrupts = true;
+        unsafe { self.write(IER, IER_RX_AVAILABLE | IER_TX_EMPTY) };
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        if self.interrupts && (self.tx.len > 0 || self.line_status() & LSR_TX_EMPTY == 0) {
+            if self.tx.push(byte) {
+                return;
+            }
+            // The queue is full, poll until there is room
+            while self.tx.len == BUFFER_SIZE {
+                self.transmit();
+            }
+            self.tx.push(byte);
+            return;
+        }
+        while self.line_status() & LSR_TX_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.write(DATA, byte) };
+    }
+
+    /// Wait until the transmit queue is empty, for when interrupts are disabled
+    pub fn flush(&mut self) {
+        while self.tx.len > 0 {
+            self.transmit();
+        }
+    }
+
+    /// A received byte, if there is one
+    pub fn receive(&mut self) -> Option<u8> {
+        self.rx.pop().or_else(|| {
+            (self.line_status() & LSR_DATA_READY != 0).then(|| unsafe { self.read(DATA) })
+        })
+    }
+
+    /// Move bytes between the UART and the queues
+    fn transmit(&mut self) {
+        while self.line_status() & LSR_DATA_READY != 0 {
+            let byte = unsafe { self.read(DATA) };
+            self.rx.push(byte);
+        }
+        // The FIFO holds 16 bytes
+        if self.line_status() & LSR_TX_EMPTY != 0 {
+            for _ in 0..16 {
+                let Some(byte) = self.tx.pop() else { break };
+                unsafe { self.write(DATA, byte) };
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Probe and set up all serial ports at 115200 8N1
+pub fn init() {
+    for (i, base) in BASES.iter().enumerate() {
+        *PORTS[i].lock() = SerialPort::init(*base, LineConfig::default()).ok();
+    }
+}
+
+/// Switch the present ports to interrupt driven mode
+pub fn enable_interrupts() {
+    for port in &PORTS {
+        if let Some(port) = port.lock().as_mut() {
+            port.enable_interrupts();
+        }
+    }
+}
+
+/// Handles both IRQ4 (COM1 and COM3) and IRQ3 (COM2 and COM4)
+pub fn irq_handler() {
+    for port in &PORTS {
+        if let Some(port) = port.lock().as_mut() {
+            if port.interrupts {
+                port.transmit();
+            }
+        }
+    }
+}
+
+/// Enable or disable mirroring the print macros to COM1
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+/// Helper for the print macros
+pub fn print_args(args: fmt::Arguments) {
+    use fmt::Write;
+    if !MIRROR.load(Ordering::Relaxed) {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(port) = crate::emergency::lock(&PORTS[0]).as_mut() {
+            port.write_fmt(args).unwrap();
+        }
+    });
+}
+
+/// Like [crate::vga::print_err], in yellow on red with ANSI escape codes
+pub fn print_err(err: &'static str) {
+    print_args(format_args!("\x1b[33;41m{}\x1b[0m ", err));
+}
+
+/// Polled output to COM1 without taking the lock, for the panic handler
+pub struct RawWriter;
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !MIRROR.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut data = Port::<u8>::new(BASES[0] + DATA);
+        let mut lsr = Port::<u8>::new(BASES[0] + LSR);
+        for byte in s.bytes() {
+            for byte in [b'\r', byte].into_iter().skip(if byte == b'\n' { 0 } else { 1 }) {
+                unsafe {
+                    while lsr.read() & LSR_TX_EMPTY == 0 {
+                        core::hint::spin_loop();
+                    }
+                    data.write(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer() {
+        let mut buffer = RingBuffer::new();
+        assert_eq!(buffer.pop(), None);
+        for i in 0..BUFFER_SIZE {
+            assert!(buffer.push(i as u8));
+        }
+        assert!(!buffer.push(0));
+        assert_eq!(buffer.pop(), Some(0));
+        assert!(buffer.push(0xff));
+        for i in 1..BUFFER_SIZE {
+            assert_eq!(buffer.pop(), Some(i as u8));
+        }
+        // Wrapped around the end
+        assert_eq!(buffer.pop(), Some(0xff));
+        assert_eq!(buffer.pop(), None);
+    }
+}
@@ -0,0 +1,306 @@
+//! Kernel symbol lookup through the `.symtab` and `.strtab` sections GRUB loaded
+//! See man elf(5)
+
+use core::fmt;
+
+use spin::Once;
+
+use crate::bootinfo;
+
+/// SHT_SYMTAB
+const SYMTAB: u32 = 2;
+/// Size of an Elf64_Sym
+const SYMBOL_SIZE: usize = 24;
+/// Symbol types, the low nibble of st_info
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+static SYMBOLS: Once<Option<SymbolTable>> = Once::new();
+
+pub struct SymbolTable {
+    /// Elf64_Sym entries
+    symbols: &'static [u8],
+    strtab: &'static [u8],
+}
+
+struct Symbol {
+    name: u32,
+    type_: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+impl SymbolTable {
+    pub fn new(symbols: &'static [u8], strtab: &'static [u8]) -> Self {
+        Self { symbols, strtab }
+    }
+
+    /// Find the symbol tables in the kernel sections
+    fn from_boot_info() -> Option<Self> {
+        let sections = bootinfo::try_get()?.kernel_sections;
+        let symtab = sections.iter().find(|s| s.type_ == SYMTAB && s.addr != 0)?;
+        let strtab = sections.get(symtab.link as usize).filter(|s| s.addr != 0)?;
+        Some(unsafe { Self::new(
+            core::slice::from_raw_parts(symtab.addr as *const u8, symtab.size as usize),
+            core::slice::from_raw_parts(strtab.addr as *const u8, strtab.size as usize),
+        )})
+    }
+
+    fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.symbols.chunks_exact(SYMBOL_SIZE).map(|s| Symbol {
+            name: u32::from_le_bytes(s[0..4].try_into().unwrap()),
+            type_: s[4] & 0xf,
+            shndx: u16::from_le_bytes(s[6..8].try_into().unwrap()),
+            value: u64::from_le_bytes(s[8..16].try_into().unwrap()),
+            size: u64::from_le_bytes(s[16..24].try_into().unwrap()),
+        })
+    }
+
+    fn name(&self, offset: u32) -> Option<&'static str> {
+        let bytes = self.strtab.get(offset as usize..)?;
+        let end = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..end]).ok().filter(|n| !n.is_empty())
+    }
+
+    /// The symbol containing `addr` and the offset into it.
+    /// Symbols without a size match if they are the closest one below `addr`.
+    pub fn symbolize(&self, addr: u64) -> Option<(&'static str, u64)> {
+        self.symbols()
+            .filter(|s| matches!(s.type_, STT_NOTYPE | STT_OBJECT | STT_FUNC) && s.shndx != 0 && s.name != 0)
+            .filter(|s| s.value <= addr && (s.size == 0 || addr < s.value + s.size))
+            // Prefer the closest symbol, and sized symbols over unsized ones
+            .max_by_key(|s| (s.value, s.size != 0))
+            .and_then(|s| Some((self.name(s.name)?, addr - s.value)))
+    }
+
+    /// The address of the symbol called `name`, mangled or demangled.
+    /// If no symbol has the whole name, the last path component is enough.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        let named = || self.symbols()
+            .filter(|s| matches!(s.type_, STT_NOTYPE | STT_OBJECT | STT_FUNC) && s.shndx != 0)
+            .filter_map(|s| Some((self.name(s.name)?, s.value)));
+        named()
+            .find(|&(symbol, _)| symbol == name || {
+                let mut compare = Compare { rest: name, equal: true };
+                fmt::write(&mut compare, format_args!("{}", Demangle(symbol))).is_ok() && compare.equal && compare.rest.is_empty()
+            })
+            .or_else(|| named().find(|&(symbol, _)| legacy_components(symbol).and_then(Iterator::last) == Some(name)))
+            .map(|(_, value)| value)
+    }
+}
+
+/// Checks if the text written to it is `rest`
+struct Compare<'a> {
+    rest: &'a str,
+    equal: bool,
+}
+
+impl fmt::Write for Compare<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.rest.strip_prefix(s) {
+            Some(rest) if self.equal => self.rest = rest,
+            _ => self.equal = false,
+        }
+        Ok(())
+    }
+}
+
+/// The kernel symbol table, if the bootloader loaded it
+pub fn table() -> Option<&'static SymbolTable> {
+    if bootinfo::try_get().is_none() {
+        return None;
+    }
+    SYMBOLS.call_once(SymbolTable::from_boot_info).as_ref()
+}
+
+/// The kernel symbol containing `addr`, and the offset into it
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    table()?.symbolize(addr)
+}
+
+/// The address of a kernel symbol, see [SymbolTable::lookup]
+pub fn lookup(name: &str) -> Option<u64> {
+    table()?.lookup(name)
+}
+
+/// Displays an address as `function+0x1c`, or as hex if there is no symbol
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match symbolize(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", Demangle(name), offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Displays a symbol name without the Rust (legacy) mangling,
+/// `_ZN5runix10interrupts18page_fault_handler17h0123456789abcdefE` becomes `runix::interrupts::page_fault_handler`.
+/// Other names are displayed as is.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match legacy_components(self.0) {
+            Some(components) => {
+                for (i, component) in components.enumerate() {
+                    if i > 0 {
+                        f.write_str("::")?;
+                    }
+                    write_unescaped(f, component)?;
+                }
+                Ok(())
+            },
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+/// The path components of a legacy mangled name, without the hash.
+/// None if `name` is not mangled like that.
+fn legacy_components(name: &str) -> Option<impl Iterator<Item = &str>> {
+    let mangled = name.strip_prefix("_ZN").or_else(|| name.strip_prefix("__ZN"))?;
+    // Check the whole name before returning anything
+    let mut rest = mangled;
+    let mut count = 0;
+    while !rest.starts_with('E') {
+        let (_, after) = next_component(rest)?;
+        rest = after;
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let mut rest = mangled;
+    Some((0..count).filter_map(move |_| {
+        let (component, after) = next_component(rest).unwrap();
+        rest = after;
+        let is_hash = component.len() == 17 && component.starts_with('h')
+            && component[1..].bytes().all(|b| b.is_ascii_hexdigit());
+        (!is_hash).then_some(component)
+    }))
+}
+
+/// Split `<length><component>` off of `s`
+fn next_component(s: &str) -> Option<(&str, &str)> {
+    let digits = s.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let len: usize = s[..digits].parse().ok()?;
+    let rest = &s[digits..];
+    if len == 0 || rest.len() < len || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Replace the `$..$` escapes and `..` of a component
+fn write_unescaped(f: &mut fmt::Formatter<'_>, component: &str) -> fmt::Result {
+    // A leading underscore protects a `$`
+    let mut rest = match component.starts_with("_$") {
+        true => &component[1..],
+        false => component,
+    };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some((escape, after)) = rest.strip_prefix('$').and_then(|r| r.split_once('$')) {
+            let c = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape.strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            match c {
+                Some(c) => {
+                    write!(f, "{}", c)?;
+                    rest = after;
+                },
+                None => {
+                    f.write_str("$")?;
+                    rest = &rest[1..];
+                },
+            }
+        } else {
+            let c = rest.chars().next().unwrap();
+            write!(f, "{}", c)?;
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn symbol(name: u32, type_: u8, value: u64, size: u64) -> [u8; SYMBOL_SIZE] {
+        let mut s = [0; SYMBOL_SIZE];
+        s[0..4].copy_from_slice(&name.to_le_bytes());
+        s[4] = type_;
+        s[6..8].copy_from_slice(&1u16.to_le_bytes());
+        s[8..16].copy_from_slice(&value.to_le_bytes());
+        s[16..24].copy_from_slice(&size.to_le_bytes());
+        s
+    }
+
+    fn table() -> SymbolTable {
+        let strtab: &'static [u8] = b"\0start\0runix\0data\0_ZN5runix6kdebug6kdebug17h0123456789abcdefE\0";
+        let mut symbols = Vec::new();
+        symbols.extend([0; SYMBOL_SIZE]);
+        symbols.extend(symbol(1, STT_NOTYPE, 0x100000, 0));
+        symbols.extend(symbol(7, STT_FUNC, 0x101000, 0x200));
+        symbols.extend(symbol(13, STT_OBJECT, 0x102000, 0x10));
+        symbols.extend(symbol(18, STT_FUNC, 0x103000, 0x100));
+        // Section symbol
+        symbols.extend(symbol(0, 3, 0x101100, 0));
+        SymbolTable::new(Vec::leak(symbols), strtab)
+    }
+
+    #[test]
+    fn symbolize() {
+        let table = table();
+        assert_eq!(table.symbolize(0x101000), Some(("runix", 0)));
+        assert_eq!(table.symbolize(0x10101c), Some(("runix", 0x1c)));
+        assert_eq!(table.symbolize(0x102008), Some(("data", 8)));
+        // Past the end of runix, the closest unsized symbol is start
+        assert_eq!(table.symbolize(0x101200), Some(("start", 0x1200)));
+        assert_eq!(table.symbolize(0xfffff), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let table = table();
+        assert_eq!(table.lookup("runix"), Some(0x101000));
+        assert_eq!(table.lookup("data"), Some(0x102000));
+        assert_eq!(table.lookup("runix::kdebug::kdebug"), Some(0x103000));
+        assert_eq!(table.lookup("_ZN5runix6kdebug6kdebug17h0123456789abcdefE"), Some(0x103000));
+        assert_eq!(table.lookup("kdebug"), Some(0x103000));
+        assert_eq!(table.lookup("runix::kdebug"), None);
+        assert_eq!(table.lookup("missing"), None);
+    }
+
+    #[test]
+    fn demangle() {
+        let demangle = |name| format!("{}", Demangle(name));
+        assert_eq!(demangle("_ZN5runix10interrupts18page_fault_handler17h0123456789abcdefE"), "runix::interrupts::page_fault_handler");
+        assert_eq!(demangle("_ZN4core3fmt9Formatter9write_str17h0123456789abcdefE"), "core::fmt::Formatter::write_str");
+        assert_eq!(demangle("_ZN56_$LT$runix..vga..Printer$u20$as$u20$core..fmt..Write$GT$9write_str17h0123456789abcdefE"),
+            "<runix::vga::Printer as core::fmt::Write>::write_str");
+        assert_eq!(demangle("_ZN5runix6percpu15PerCpu$LT$T$GT$4with28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE"),
+            "runix::percpu::PerCpu<T>::with::{{closure}}");
+        assert_eq!(demangle("runix"), "runix");
+        assert_eq!(demangle("_ZN5runix"), "_ZN5runix");
+        assert_eq!(demangle("_ZN99runixE"), "_ZN99runixE");
+    }
+}
@@ -0,0 +1,148 @@
+//! System calls from ring 3 using syscall/sysret
+//! https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+
+// The calling convention follows Linux:
+// rax holds the number, the arguments are in rdi, rsi, rdx, r10, r8 and r9
+// and the result is returned in rax.
+
+use core::arch::global_asm;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::{fixup, gdt, percpu, usermode};
+
+/// Returned for unknown syscalls and invalid arguments
+pub const ERROR: u64 = u64::MAX;
+/// `write` copies the buffer in chunks of this size
+const WRITE_CHUNK: usize = 256;
+
+/// Syscall numbers, for use by applications
+#[allow(dead_code)]
+pub mod number {
+    /// exit(code)
+    pub const EXIT: u64 = 0;
+    /// write(buffer, len)
+    pub const WRITE: u64 = 1;
+}
+
+/// The registers pushed by `syscall_entry`
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The syscall number
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+type Handler = fn(&mut SyscallFrame) -> u64;
+
+/// Indexed by syscall number
+static SYSCALLS: [Handler; 2] = [
+    sys_exit,
+    sys_write,
+];
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// The CPU does not switch stacks on syscall, so we do that here using the per-CPU header.
+// Interrupts are masked by SFMASK until sysretq restores RFLAGS.
+global_asm!("
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    push qword ptr gs:[{user_stack}]
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    call {dispatch}
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    add rsp, 8
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+",
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+);
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    match SYSCALLS.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => {
+            wprintln!("Unknown syscall {:#x} at {:#x}", frame.rax, frame.rip);
+            ERROR
+        }
+    }
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> u64 {
+    usermode::exit(frame.rdi as i64);
+}
+
+fn sys_write(frame: &mut SyscallFrame) -> u64 {
+    let (buffer, len) = (frame.rdi, frame.rsi);
+    let mut chunk = [0; WRITE_CHUNK];
+    // Bytes of a character split between chunks, moved to the start of the next one
+    let mut carried = 0;
+    let mut offset = 0;
+    while offset < len {
+        let n = (WRITE_CHUNK - carried).min((len - offset) as usize);
+        let Some(src) = buffer.checked_add(offset) else { return ERROR };
+        if fixup::copy_from_user(&mut chunk[carried..carried + n], src).is_err() {
+            return ERROR;
+        }
+        let filled = carried + n;
+        let valid = match core::str::from_utf8(&chunk[..filled]) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return ERROR,
+        };
+        print!("{}", core::str::from_utf8(&chunk[..valid]).unwrap());
+        chunk.copy_within(valid..filled, 0);
+        carried = filled - valid;
+        offset += n as u64;
+    }
+    match carried {
+        0 => len,
+        _ => ERROR,
+    }
+}
+
+/// Enable the syscall instruction, the GDT and per-CPU area have to be initialized
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(selectors.user_code, selectors.user_data, selectors.kernel_code, selectors.kernel_data)
+        .expect("GDT is not laid out for syscall/sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    percpu::HEADER.with(|h| h.kernel_stack.set(gdt::kernel_stack().as_u64() as usize));
+}
@@ -0,0 +1,126 @@
+//! Tests that run inside the kernel: `cargo test` builds a test kernel and `qemu-test.sh` boots it in QEMU.
+//! https://os.phil-opp.com/testing/
+
+// `#[test_case]` items run one after another once the kernel is initialized, results are reported on COM1.
+// When all passed or the first one failed, the kernel exits QEMU through the isa-debug-exit device.
+//
+// A panic can not be recovered from, so tests that are expected to panic (faults panic too) are
+// wrapped in [ShouldPanic]. When one panics as expected, the panic handler starts the tests over
+// on a fresh boot stack, skipping those that already ran.
+//
+// The unit tests of the pure parts of the kernel still run on the host, see the Makefile.
+
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::serial;
+
+/// The isa-debug-exit device, see qemu-test.sh
+const EXIT_PORT: u16 = 0xf4;
+
+/// Index of the next test to run
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+/// Set while a [ShouldPanic] test runs
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// QEMU exits with `(code << 1) | 1`, so neither can be confused with QEMU's own exit codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(code: ExitCode) -> ! {
+    unsafe { Port::<u32>::new(EXIT_PORT).write(code as u32) };
+    // Not running in QEMU
+    crate::hlt_loop!();
+}
+
+macro_rules! report {
+    ($($arg:tt)*) => {
+        write!(serial::RawWriter, $($arg)*).unwrap()
+    };
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        report!("{} ... ", core::any::type_name::<T>());
+        self();
+        report!("ok\n");
+    }
+}
+
+/// A test that passes if it panics or causes a fault, named as function pointers have no useful type name
+/// ```ignore
+/// #[test_case]
+/// static STACK_OVERFLOW: ShouldPanic = ShouldPanic("debug::stack_overflow", debug::stack_overflow);
+/// ```
+pub struct ShouldPanic(pub &'static str, pub fn());
+
+impl Testable for ShouldPanic {
+    fn run(&self) {
+        report!("{} (should panic) ... ", self.0);
+        EXPECT_PANIC.store(true, Ordering::SeqCst);
+        (self.1)();
+        EXPECT_PANIC.store(false, Ordering::SeqCst);
+        report!("FAILED\ndid not panic\n");
+        exit_qemu(ExitCode::Failed);
+    }
+}
+
+/// Called by the generated `test_main`, again after every expected panic
+pub fn runner(tests: &[&dyn Testable]) {
+    let start = NEXT.load(Ordering::SeqCst);
+    if start == 0 {
+        report!("running {} tests\n", tests.len());
+    }
+    for (i, test) in tests.iter().enumerate().skip(start) {
+        NEXT.store(i + 1, Ordering::SeqCst);
+        test.run();
+    }
+    report!("test result: ok. {} passed\n", tests.len());
+    exit_qemu(ExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+        report!("ok\n");
+        unsafe { restart() };
+    }
+    report!("FAILED\n{}\n{}\n", info.location().unwrap(), info.message());
+    exit_qemu(ExitCode::Failed);
+}
+
+/// Abandon the panicking test, whatever stack it is on, and continue with the next one on the boot stack
+unsafe fn restart() -> ! {
+    extern "C" {
+        static stack_top: u8;
+    }
+    asm!(
+        "mov rsp, {stack}",
+        // The end of the frame pointer chain for backtraces
+        "xor ebp, ebp",
+        "call {resume}",
+        stack = in(reg) core::ptr::addr_of!(stack_top),
+        resume = sym resume,
+        options(noreturn),
+    );
+}
+
+extern "C" fn resume() -> ! {
+    // The test may have faulted in an interrupt handler, which disabled interrupts
+    x86_64::instructions::interrupts::enable();
+    crate::test_main();
+    exit_qemu(ExitCode::Success);
+}
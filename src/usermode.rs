@@ -0,0 +1,102 @@
+//! Running code in ring 3
+
+// Entering user mode saves the callee-saved registers on the current kernel stack
+// and remembers that stack in the per-CPU header.
+// Leaving (through the exit syscall or because the application faulted)
+// restores that stack, so [enter] simply returns the exit code.
+// While user code runs, interrupts and syscalls use the stack from gdt::set_kernel_stack.
+
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+use crate::{gdt, percpu};
+
+extern "C" {
+    fn user_enter(entry: u64, stack: u64, cs: u64, ss: u64) -> i64;
+    fn user_return(code: i64) -> !;
+}
+
+global_asm!("
+.global user_enter
+user_enter:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov gs:[{user_return}], rsp
+
+    // iretq frame
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+
+    // Don't leak kernel values to user code
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8, r8
+    xor r9, r9
+    xor r10, r10
+    xor r11, r11
+    xor r12, r12
+    xor r13, r13
+    xor r14, r14
+    xor r15, r15
+
+    swapgs
+    iretq
+
+.global user_return
+user_return:
+    mov rax, rdi
+    mov rsp, gs:[{user_return}]
+    mov qword ptr gs:[{user_return}], 0
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+",
+    user_return = const percpu::USER_RETURN_OFFSET,
+);
+
+/// Run code in ring 3 at `entry` with the stack pointer set to `stack`.
+/// Returns once the code exits, with its exit code.
+/// The code and stack have to be accessible from ring 3, see [crate::paging::set_user_accessible].
+pub fn enter(entry: VirtAddr, stack: VirtAddr) -> i64 {
+    assert!(!is_active(), "Already running user code");
+    let selectors = gdt::selectors();
+    unsafe {
+        user_enter(
+            entry.as_u64(),
+            stack.as_u64(),
+            selectors.user_code.0 as u64,
+            selectors.user_data.0 as u64,
+        )
+    }
+}
+
+/// If user code has been entered and not yet exited
+pub fn is_active() -> bool {
+    percpu::HEADER.with(|h| h.user_return.get() != 0)
+}
+
+/// Stop running user code, making [enter] return `code`.
+/// Has to be called from the kernel stack used for ring 3
+/// (from a syscall or an exception in user code).
+pub fn exit(code: i64) -> ! {
+    assert!(is_active(), "Not running user code");
+    unsafe { user_return(code) }
+}
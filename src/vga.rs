@@ -12,22 +12,23 @@ macro_rules! println {
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
 
+// The println variants for errors and warnings write to the kernel log, see logger.rs
 #[macro_export]
 macro_rules! eprintln {
     () => (print!("\n"));
-    ($($arg:tt)*) => (eprint!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => (::log::error!($($arg)*));
 }
 
 #[macro_export]
 macro_rules! wprintln {
     () => (print!("\n"));
-    ($($arg:tt)*) => (wprint!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => (::log::warn!($($arg)*));
 }
 
 #[macro_export]
 macro_rules! exprintln {
     () => (print!("\n"));
-    ($($arg:tt)*) => (exprint!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => (::log::error!(target: "exception", $($arg)*));
 }
 
 #[macro_export]
@@ -63,17 +64,21 @@ macro_rules! exprint {
 
 pub fn print_err(err: &'static str) {
     use fmt::Write;
+    crate::serial::print_err(err);
     x86_64::instructions::interrupts::without_interrupts(|| {
-        PRINTER.lock().print_chars(err, Color::Yellow, Color::Red);
-        PRINTER.lock().write_char(' ').unwrap();
+        let mut printer = crate::emergency::lock(&PRINTER);
+        printer.print_chars(err, Color::Yellow, Color::Red);
+        printer.write_char(' ').unwrap();
     });
 }
 
-// Helper function for the `print` macro to prevent deadlocks
+// Helper function for the `print` macro to prevent deadlocks.
+// Exceptions can still interrupt the printer, their output steals the lock then.
 pub fn print_args(args: fmt::Arguments) {
     use fmt::Write;
+    crate::serial::print_args(args);
     x86_64::instructions::interrupts::without_interrupts(|| {
-        PRINTER.lock().write_fmt(args).unwrap();
+        crate::emergency::lock(&PRINTER).write_fmt(args).unwrap();
     });
 }
 
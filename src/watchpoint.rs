@@ -0,0 +1,210 @@
+//! Hardware watchpoints in the debug registers, set with `kdebug> watch`
+//! https://wiki.osdev.org/CPU_Registers_x86-64#Debug_Registers
+
+// DR0-DR3 hold the watched addresses and DR7 what kind of access to watch in each of them.
+// The CPU can not watch only reads, `r` watches reads and writes.
+// Data watchpoints trap after the access, so RIP is already at the instruction after the one that accessed it.
+// Execute watchpoints fault before the instruction runs, RFLAGS.RF is set to get past it when resuming.
+// Only one CPU runs, so its debug registers are all there is to set.
+
+use core::arch::asm;
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber,
+    Dr0, Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags, Dr7Value,
+};
+use x86_64::registers::rflags::RFlags;
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::trap::TrapFrame;
+use crate::symbols::Symbolized;
+
+/// There are 4 debug address registers
+pub const SLOTS: usize = 4;
+/// DR6 with no debug conditions, the reserved bits read as 1
+const DR6_CLEAR: u64 = 0xffff0ff0;
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; SLOTS]> = Mutex::new([None; SLOTS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadWrite,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn condition(self) -> BreakpointCondition {
+        match self {
+            Access::ReadWrite => BreakpointCondition::DataReadsWrites,
+            Access::Write => BreakpointCondition::DataWrites,
+            Access::Execute => BreakpointCondition::InstructionExecution,
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Access::ReadWrite => "rw",
+            Access::Write => "w",
+            Access::Execute => "x",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// All debug address registers are in use
+    Full,
+    /// Only 1, 2, 4 and 8 bytes can be watched
+    Length,
+    /// The address has to be aligned to the length
+    Misaligned,
+    /// Execute watchpoints have a length of 1
+    ExecuteLength,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchError::Full => write!(f, "All {} watchpoints are in use", SLOTS),
+            WatchError::Length => write!(f, "The length has to be 1, 2, 4 or 8"),
+            WatchError::Misaligned => write!(f, "The address has to be aligned to the length"),
+            WatchError::ExecuteLength => write!(f, "Execute watchpoints have a length of 1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub access: Access,
+    pub len: u64,
+}
+
+impl Watchpoint {
+    pub fn new(addr: u64, access: Access, len: u64) -> Result<Self, WatchError> {
+        if access == Access::Execute && len != 1 {
+            return Err(WatchError::ExecuteLength);
+        }
+        if BreakpointSize::new(len as usize).is_none() {
+            return Err(WatchError::Length);
+        }
+        if addr % len != 0 {
+            return Err(WatchError::Misaligned);
+        }
+        Ok(Self { addr, access, len })
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} at {:#x} ({})", self.access, self.len, self.addr, Symbolized(self.addr))
+    }
+}
+
+/// Watch in a free slot, returns the slot
+pub fn set(watchpoint: Watchpoint) -> Result<usize, WatchError> {
+    let mut slots = WATCHPOINTS.lock();
+    let slot = slots.iter().position(Option::is_none).ok_or(WatchError::Full)?;
+    slots[slot] = Some(watchpoint);
+    load(&slots);
+    Ok(slot)
+}
+
+/// Remove the watchpoint in `slot`, returns it if there was one
+pub fn clear(slot: usize) -> Option<Watchpoint> {
+    let mut slots = WATCHPOINTS.lock();
+    let watchpoint = slots.get_mut(slot)?.take();
+    load(&slots);
+    watchpoint
+}
+
+pub fn clear_all() {
+    let mut slots = WATCHPOINTS.lock();
+    *slots = [None; SLOTS];
+    load(&slots);
+}
+
+pub fn list() -> [Option<Watchpoint>; SLOTS] {
+    *WATCHPOINTS.lock()
+}
+
+/// The DR7 value enabling the watchpoints in `slots`
+fn dr7(slots: &[Option<Watchpoint>; SLOTS]) -> Dr7Value {
+    let mut dr7 = Dr7Value::from(Dr7Flags::LOCAL_EXACT_BREAKPOINT_ENABLE);
+    for (slot, watchpoint) in slots.iter().enumerate() {
+        let Some(watchpoint) = watchpoint else { continue };
+        let n = DebugAddressRegisterNumber::new(slot as u8).unwrap();
+        dr7.insert_flags(Dr7Flags::local_breakpoint_enable(n));
+        dr7.set_condition(n, watchpoint.access.condition());
+        dr7.set_size(n, BreakpointSize::new(watchpoint.len as usize).unwrap());
+    }
+    dr7
+}
+
+/// Write `slots` to the debug registers
+fn load(slots: &[Option<Watchpoint>; SLOTS]) {
+    for (slot, watchpoint) in slots.iter().enumerate() {
+        let addr = watchpoint.map_or(0, |w| w.addr);
+        match slot {
+            0 => Dr0::write(addr),
+            1 => Dr1::write(addr),
+            2 => Dr2::write(addr),
+            _ => Dr3::write(addr),
+        }
+    }
+    Dr7::write(dr7(slots));
+}
+
+/// Called on a debug exception to report the watchpoints that fired.
+/// Returns true if they were the only reason for the exception, so the interrupted code can just continue.
+pub fn hit(frame: &mut TrapFrame) -> bool {
+    let status = Dr6::read();
+    let mut fired = false;
+    for (slot, watchpoint) in list().iter().enumerate() {
+        let Some(watchpoint) = watchpoint else { continue };
+        if !status.contains(Dr6Flags::trap(DebugAddressRegisterNumber::new(slot as u8).unwrap())) {
+            continue;
+        }
+        fired = true;
+        match watchpoint.access {
+            Access::Execute => {
+                wprintln!("Watchpoint {} ({}) reached in {}", slot, watchpoint, Symbolized(frame.rip));
+                frame.rflags |= RFlags::RESUME_FLAG.bits();
+            },
+            _ => wprintln!("Watchpoint {} ({}) accessed, RIP after the access {:#x} in {}", slot, watchpoint, frame.rip, Symbolized(frame.rip)),
+        }
+        print!("Backtrace:\n{}", Backtrace::trapped(frame));
+    }
+    // The CPU never clears DR6
+    unsafe { asm!("mov dr6, {}", in(reg) DR6_CLEAR, options(nomem, nostack, preserves_flags)) };
+    fired && !status.intersects(Dr6Flags::STEP | Dr6Flags::SWITCH | Dr6Flags::ACCESS_DETECTED)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert!(Watchpoint::new(0x1000, Access::Write, 8).is_ok());
+        assert_eq!(Watchpoint::new(0x1004, Access::Write, 8), Err(WatchError::Misaligned));
+        assert_eq!(Watchpoint::new(0x1000, Access::ReadWrite, 3), Err(WatchError::Length));
+        assert_eq!(Watchpoint::new(0x1000, Access::Execute, 4), Err(WatchError::ExecuteLength));
+        assert!(Watchpoint::new(0x1003, Access::Execute, 1).is_ok());
+    }
+
+    #[test]
+    fn dr7_value() {
+        let mut slots = [None; SLOTS];
+        assert_eq!(dr7(&slots).bits(), 1 << 8);
+        slots[1] = Some(Watchpoint::new(0x1000, Access::Write, 8).unwrap());
+        slots[3] = Some(Watchpoint::new(0x2000, Access::ReadWrite, 4).unwrap());
+        // L1 and L3, R/W1 = 01 LEN1 = 10, R/W3 = 11 LEN3 = 11
+        assert_eq!(dr7(&slots).bits(), 1 << 8 | 1 << 2 | 1 << 6 | 0b1001 << 20 | 0b1111 << 28);
+    }
+}